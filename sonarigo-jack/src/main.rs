@@ -3,12 +3,23 @@ use std::env;
 use std::io::Write;
 use std::convert::TryFrom;
 use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
 
 extern crate jack;
 extern crate wmidi;
+extern crate ctrlc;
 
 use soundfonts::engine::EngineTrait;
 use soundfonts::sfz::engine;
+use soundfonts::interpolation;
+use soundfonts::loading;
+use soundfonts::resampling;
+use soundfonts::streaming;
+use soundfonts::voices;
+use soundfonts::watching;
 
 fn main() {
     let (client, _status) = match jack::Client::new("Sonarigo", jack::ClientOptions::NO_START_SERVER) {
@@ -20,19 +31,109 @@ fn main() {
     };
 
     let samplerate = client.sample_rate();
-    let max_block_length = client.buffer_size();
-    println!("Samplerate: {}; maximum buffer size: {}", samplerate, max_block_length);
+    println!("Samplerate: {}; maximum buffer size: {}", samplerate, client.buffer_size());
 
     let args: Vec<String> = env::args().collect();
     let filename = &args[1];
+    let stream_samples = args.iter().skip(2).any(|arg| arg == "--stream");
+    let packed_samples = args.iter().skip(2).any(|arg| arg == "--packed");
+    let watch = args.iter().skip(2).any(|arg| arg == "--watch");
+    let resample_quality = args.iter().skip(2).find_map(|arg| match arg.as_str() {
+        "--resample=fast" => Some(resampling::ResampleQuality::Fast),
+        "--resample" | "--resample=good" => Some(resampling::ResampleQuality::Good),
+        "--resample=best" => Some(resampling::ResampleQuality::Best),
+        _ => None,
+    });
+    let interpolation = args.iter().skip(2).find_map(|arg| match arg.as_str() {
+        "--interpolation=linear" => Some(interpolation::Interpolation::Linear),
+        "--interpolation=cubic" => Some(interpolation::Interpolation::Cubic),
+        "--interpolation=sinc" => Some(interpolation::Interpolation::Sinc),
+        _ => None,
+    }).unwrap_or_default();
+    let memory_budget = args.iter().skip(2)
+        .find_map(|arg| arg.strip_prefix("--memory-budget="))
+        .and_then(|mb| mb.parse::<usize>().ok())
+        .map(|mb| loading::MemoryBudget { bytes: mb << 20, stream_if_exceeded: true });
+    let max_voices = args.iter().skip(2)
+        .find_map(|arg| arg.strip_prefix("--max-voices="))
+        .and_then(|mv| mv.parse::<usize>().ok());
+    let voice_stealing = args.iter().skip(2)
+        .find_map(|arg| arg.strip_prefix("--voice-stealing="))
+        .and_then(voices::VoiceStealing::from_name)
+        .unwrap_or_default();
+
+    let cancel = loading::CancelToken::new();
+    let loading_done = Arc::new(AtomicBool::new(false));
+    {
+        let cancel = cancel.clone();
+        let loading_done = loading_done.clone();
+        let handler = ctrlc::set_handler(move || {
+            if loading_done.load(Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+            cancel.cancel();
+        });
+        if let Err(e) = handler {
+            println!("Could not install Ctrl-C handler: {:?}", e);
+        }
+    }
+
+    let load_options = move |progress: Option<loading::ProgressCallback>, cancel: loading::CancelToken| {
+        loading::LoadOptions {
+            streaming: match stream_samples {
+                true => Some(streaming::StreamingConfig::default()),
+                false => None,
+            },
+            resampling: resample_quality,
+            interpolation,
+            packed_samples,
+            memory_budget,
+            max_voices,
+            voice_stealing,
+            progress,
+            cancel,
+        }
+    };
 
-    let mut engine = match engine::Engine::new(filename.to_string(), samplerate as f64, max_block_length as usize) {
+    println!("Loading {}, press Ctrl-C to abort", filename);
+    let progress: loading::ProgressCallback = Box::new(|p: loading::LoadProgress| {
+        print!("\rLoaded {}/{} files, {}/{} MB",
+               p.files_done, p.files_total, p.bytes_done >> 20, p.bytes_total >> 20);
+        io::stdout().flush().ok();
+    });
+    let engine = engine::Engine::with_options(filename.to_string(),
+                                              samplerate as f64,
+                                              load_options(Some(progress), cancel));
+    loading_done.store(true, Ordering::Relaxed);
+    println!();
+    let mut engine = match engine {
         Err(e) => {
             println!("Could not launch SFZ engine: {:?}", e);
             return
         }
         Ok(e) => e
     };
+    let memory = engine.memory_usage();
+    println!("Memory: {} MB samples, {} MB stream buffers",
+             memory.sample_bytes >> 20, memory.stream_buffer_bytes >> 20);
+
+    let (reloaded_tx, reloaded_rx) = mpsc::sync_channel(1);
+    let (retired_tx, retired_rx) = mpsc::sync_channel(1);
+    if watch {
+        println!("Watching {} files for changes", engine.watched_files().len());
+        let watcher = watching::FileWatcher::new(engine.watched_files());
+        let filename = filename.to_string();
+        let samplerate = samplerate as f64;
+        thread::spawn(move || {
+            reload_on_change(watcher, reloaded_tx, retired_rx, || {
+                engine::Engine::with_options(filename.clone(),
+                                             samplerate,
+                                             load_options(None, loading::CancelToken::new()))
+            })
+        });
+    }
+    let mut next_engine: Option<engine::Engine> = None;
+    let mut retired_engine: Option<engine::Engine> = None;
 
     let midi_in = match client.register_port("MIDI input", jack::MidiIn::default()) {
         Err(e) => {
@@ -59,6 +160,36 @@ fn main() {
     };
 
     let callback = move |_: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
+        // A reloaded engine takes over right away while the old one fades out.
+        match next_engine.take() {
+            Some(next) if engine.fadeout_finished() && retired_engine.is_none() => {
+                retired_engine = Some(std::mem::replace(&mut engine, next));
+            }
+            Some(next) => next_engine = Some(next),
+            None => if let Ok(reloaded) = reloaded_rx.try_recv() {
+                engine.fadeout();
+                next_engine = Some(reloaded);
+            }
+        }
+        // The old engine is dropped by the reload thread, it is kept until the channel takes it.
+        if let Some(retired) = retired_engine.take() {
+            retired_engine = match retired_tx.try_send(retired) {
+                Ok(()) => None,
+                Err(mpsc::TrySendError::Full(retired)) | Err(mpsc::TrySendError::Disconnected(retired)) => Some(retired),
+            };
+        }
+
+        let left = out_left.as_mut_slice(ps);
+        let right = out_right.as_mut_slice(ps);
+        for (l, r) in Iterator::zip(left.iter_mut(), right.iter_mut()) {
+            *l = 0.0;
+            *r = 0.0;
+        }
+        if next_engine.is_some() {
+            engine.process(left, right);
+        }
+        let active_engine = next_engine.as_mut().unwrap_or(&mut engine);
+
         for e in midi_in.iter(ps) {
             let midi_msg = match wmidi::MidiMessage::try_from(e.bytes) {
                 Ok(m) => m,
@@ -68,13 +199,11 @@ fn main() {
                 }
             };
             println!("{:?}", midi_msg);
-            engine.midi_event(&midi_msg);
+            active_engine.queue_midi_event(e.time as usize, &midi_msg);
             io::stdout().flush();
         }
 
-        let left = out_left.as_mut_slice(ps);
-        let right = out_right.as_mut_slice(ps);
-        engine.process(left, right);
+        active_engine.process(left, right);
 
         jack::Control::Continue
     };
@@ -93,3 +222,30 @@ fn main() {
 
     active_client.deactivate().unwrap();
 }
+
+// Polls the files of the instrument and hands a reloaded engine to the process callback whenever
+// they change. Old engines come back to be dropped here rather than in the realtime thread.
+fn reload_on_change<F>(mut watcher: watching::FileWatcher,
+                       reloaded: mpsc::SyncSender<engine::Engine>,
+                       retired: mpsc::Receiver<engine::Engine>,
+                       load: F)
+where F: Fn() -> Result<engine::Engine, engine::EngineError> {
+    loop {
+        thread::sleep(watching::POLL_INTERVAL);
+        while retired.try_recv().is_ok() {}
+        if !watcher.poll() {
+            continue;
+        }
+        println!("Instrument changed, reloading");
+        match load() {
+            Ok(engine) => {
+                watcher = watching::FileWatcher::new(engine.watched_files());
+                if reloaded.send(engine).is_err() {
+                    return;
+                }
+                println!("Reloaded instrument");
+            }
+            Err(e) => println!("Could not reload SFZ engine, keeping the old one: {:?}", e),
+        }
+    }
+}
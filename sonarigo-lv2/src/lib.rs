@@ -10,6 +10,8 @@ use lv2::lv2_atom as atom;
 
 use soundfonts::engine::EngineTrait;
 use soundfonts::sfz::engine;
+use soundfonts::loading;
+use soundfonts::watching;
 
 mod lv2_stuff;
 
@@ -27,6 +29,7 @@ struct Ports {
     out_left: OutputPort<Audio>,
     out_right: OutputPort<Audio>,
     gain: InputPort<Control>,
+    hot_reload: InputPort<Control>,
 }
 
 #[derive(FeatureCollection)]
@@ -54,14 +57,19 @@ struct URIDs {
 
 #[uri("http://johannes-mueller.org/oss/lv2/sonarigo#lv2")]
 struct SonarigoLV2 {
-    engine: engine::Engine,
-    new_engine: Option<engine::Engine>,
+    engine: Box<engine::Engine>,
+    new_engine: Option<Box<engine::Engine>>,
+    retired: Option<Retired>,
     urids: URIDs,
 
     sfzfile_path: Option<std::string::String>,
+    load_cancel: loading::CancelToken,
+    load_generation: u64,
+
+    watch: Option<Watch>,
+    poll_countdown: usize,
 
     samplerate: f64,
-    max_block_length: usize,
 
     state_notification_needed: bool,
 
@@ -76,17 +84,21 @@ impl Plugin for SonarigoLV2 {
 
     fn new(plugin_info: &PluginInfo, features: &mut Features<'static>) -> Option<Self> {
         let samplerate = plugin_info.sample_rate();
-        let max_block_length = 8192; /*FIXME*/
-        let engine = engine::Engine::dummy(samplerate, max_block_length);
+        let engine = Box::new(engine::Engine::dummy(samplerate));
         Some(Self {
             engine,
             new_engine: None,
+            retired: None,
             urids: features.map.populate_collection()?,
 
             sfzfile_path: None,
+            load_cancel: loading::CancelToken::new(),
+            load_generation: 0,
+
+            watch: None,
+            poll_countdown: 0,
 
             samplerate,
-            max_block_length,
 
             state_notification_needed: false,
 
@@ -95,16 +107,19 @@ impl Plugin for SonarigoLV2 {
     }
 
     fn run(&mut self, ports: &mut Ports, features: &mut Self::AudioFeatures) {
-        let mut offset: usize = 0;
-
         for (l, r) in Iterator::zip(ports.out_left.iter_mut(), ports.out_right.iter_mut()) {
             *l = 0.0;
             *r = 0.0;
         }
 
+        if let Some(retired) = self.retired.take() {
+            retire(&features.schedule, &mut self.retired, retired);
+        }
+
         let active_engine = if let Some(new_engine) = &mut self.new_engine {
             if self.engine.fadeout_finished() {
-                self.engine = self.new_engine.take().unwrap();
+                let engine = std::mem::replace(&mut self.engine, self.new_engine.take().unwrap());
+                retire(&features.schedule, &mut self.retired, Retired { engine: Some(engine), watch: None });
                 &mut self.engine
             } else {
                 self.engine.process(&mut ports.out_left, &mut ports.out_right);
@@ -120,29 +135,26 @@ impl Plugin for SonarigoLV2 {
             .unwrap();
 
         for (timestamp, message) in control_sequence {
-            match timestamp.as_frames() {
-                Some(ts) if ts > 0  => {
-                    let frame = ts as usize;
-                    active_engine.process(&mut ports.out_left[offset..frame],
-                                          &mut ports.out_right[offset..frame]);
-                    offset = frame;
-                }
-                _ => {}
-            };
-
             if let Some(msg) = message.read(self.urids.midi.wmidi, ()) {
-                active_engine.midi_event(&msg);
+                let frame = timestamp.as_frames().map_or(0, |ts| ts.max(0) as usize);
+                active_engine.queue_midi_event(frame, &msg);
             };
 
             if let Some((header, mut object_reader)) = message.read(self.urids.atom.object, ()) {
                 println!("received message");
                 if header.otype == self.urids.patch.set {
                     if let Some(path) = parse_sfzfile_path(&self.urids, &mut object_reader) {
-                        if let Err(e) = features.schedule.schedule_work(EngineParameters {
+                        self.load_cancel.renew();
+                        self.load_generation += 1;
+                        if let Some(watch) = self.watch.take() {
+                            retire(&features.schedule, &mut self.retired, Retired { engine: None, watch: Some(watch) });
+                        }
+                        if let Err(e) = features.schedule.schedule_work(WorkRequest::Load(EngineParameters {
                             sfzfile: path.to_string(),
                             host_samplerate: self.samplerate,
-                            max_block_length: self.max_block_length
-                        }) {
+                            cancel: self.load_cancel.clone(),
+                            generation: self.load_generation
+                        })) {
                             println!("can't schedule work {}", e);
                         } else {
                             println!("work scheduled");
@@ -157,9 +169,22 @@ impl Plugin for SonarigoLV2 {
         }
 
         let nsamples = ports.out_left.len();
-        if offset < nsamples {
-            active_engine.process(&mut ports.out_left[offset..nsamples],
-                                  &mut ports.out_right[offset..nsamples]);
+        active_engine.process(&mut ports.out_left, &mut ports.out_right);
+
+        if *ports.hot_reload > 0.5 && self.new_engine.is_none() {
+            self.poll_countdown = self.poll_countdown.saturating_sub(nsamples);
+            if self.poll_countdown == 0 {
+                if let Some(watch) = self.watch.take() {
+                    self.poll_countdown = (watching::POLL_INTERVAL.as_secs_f64() * self.samplerate) as usize;
+                    use lv2_worker::ScheduleError::*;
+                    if let Err(Unknown(request) | NoSpace(request) | NoCallback(request)) =
+                        features.schedule.schedule_work(WorkRequest::Poll(watch)) {
+                        if let WorkRequest::Poll(watch) = request {
+                            self.watch = Some(watch);
+                        }
+                    }
+                }
+            }
         }
 
         let gain_target = match *ports.gain {
@@ -213,6 +238,17 @@ impl Plugin for SonarigoLV2 {
     }
 }
 
+// If the worker can't be scheduled, `retired` is kept in `pending` to try again in the next cycle.
+fn retire(schedule: &lv2_worker::Schedule<SonarigoLV2>, pending: &mut Option<Retired>, retired: Retired) {
+    use lv2_worker::ScheduleError::*;
+    if let Err(Unknown(request) | NoSpace(request) | NoCallback(request)) =
+        schedule.schedule_work(WorkRequest::Drop(retired)) {
+        if let WorkRequest::Drop(retired) = request {
+            *pending = Some(retired);
+        }
+    }
+}
+
 fn parse_sfzfile_path<'a>(urids: &URIDs, object_reader:
                           &mut atom::object::ObjectReader<'a>) -> Option<&'a str> {
     if let Some((property_header, atom)) = object_reader.next() {
@@ -240,34 +276,125 @@ fn parse_sfzfile_path<'a>(urids: &URIDs, object_reader:
 struct EngineParameters {
     sfzfile: std::string::String,
     host_samplerate: f64,
-    max_block_length: usize
+    cancel: loading::CancelToken,
+    generation: u64
+}
+
+impl EngineParameters {
+    // Returns `None` if loading was cancelled.
+    fn load(&self) -> Result<Option<engine::Engine>, lv2_worker::WorkerError> {
+        let options = loading::LoadOptions {
+            progress: Some(Box::new(|p: loading::LoadProgress| {
+                println!("loaded {}/{} files", p.files_done, p.files_total);
+            })),
+            cancel: self.cancel.clone(),
+            ..Default::default()
+        };
+        match soundfonts::sfz::engine::Engine::with_options(self.sfzfile.clone(),
+                                                            self.host_samplerate,
+                                                            options) {
+            Ok(engine) => Ok(Some(engine)),
+            Err(engine::EngineError::Cancelled) => {
+                println!("loading cancelled");
+                Ok(None)
+            }
+            Err(e) => {
+                println!("failed {:?}", e);
+                Err(lv2_worker::WorkerError::Unknown)
+            }
+        }
+    }
+}
+
+// The files of the current instrument, passed to the worker to be polled for changes and back.
+struct Watch {
+    watcher: watching::FileWatcher,
+    parameters: EngineParameters
+}
+
+// Engines and watches the audio thread is done with, passed to the worker to be deallocated there.
+struct Retired {
+    engine: Option<Box<engine::Engine>>,
+    watch: Option<Watch>
+}
+
+enum WorkRequest {
+    Load(EngineParameters),
+    Poll(Watch),
+    Drop(Retired)
+}
+
+enum WorkResponse {
+    Loaded(Box<engine::Engine>, Watch),
+    Unchanged(Watch)
 }
 
 impl lv2_worker::Worker for SonarigoLV2 {
-    type WorkData = EngineParameters;
+    type WorkData = WorkRequest;
 
-    type ResponseData = soundfonts::sfz::engine::Engine;
+    type ResponseData = WorkResponse;
 
     fn work(response_handler: &lv2_worker::ResponseHandler<Self>, data: Self::WorkData)
             -> Result<(), lv2_worker::WorkerError> {
-        println!("work {}", data.sfzfile);
-        let engine = soundfonts::sfz::engine::Engine::new(data.sfzfile,
-                                                          data.host_samplerate,
-                                                          data.max_block_length)
-            .map_err(|e| {
-                println!("failed {:?}", e);
-                lv2_worker::WorkerError::Unknown
-            })?;
+        let response = match data {
+            WorkRequest::Load(parameters) => {
+                println!("work {}", parameters.sfzfile);
+                match parameters.load()? {
+                    Some(engine) => {
+                        let watcher = watching::FileWatcher::new(engine.watched_files());
+                        WorkResponse::Loaded(Box::new(engine), Watch { watcher, parameters })
+                    }
+                    None => return Ok(()),
+                }
+            }
+            WorkRequest::Poll(mut watch) => {
+                if !watch.watcher.poll() {
+                    WorkResponse::Unchanged(watch)
+                } else {
+                    println!("instrument changed, reloading {}", watch.parameters.sfzfile);
+                    match watch.parameters.load() {
+                        Ok(Some(engine)) => {
+                            watch.watcher = watching::FileWatcher::new(engine.watched_files());
+                            WorkResponse::Loaded(Box::new(engine), watch)
+                        }
+                        Ok(None) => return Ok(()),
+                        Err(_) => WorkResponse::Unchanged(watch),
+                    }
+                }
+            }
+            WorkRequest::Drop(retired) => {
+                drop(retired);
+                return Ok(());
+            }
+        };
 
-        response_handler.respond(engine).map_err(|_| lv2_worker::WorkerError::Unknown)
+        response_handler.respond(response).map_err(|_| lv2_worker::WorkerError::Unknown)
     }
 
-    fn work_response(&mut self, data: Self::ResponseData, _f: &mut Self::AudioFeatures)
+    fn work_response(&mut self, data: Self::ResponseData, features: &mut Self::AudioFeatures)
                      -> Result<(), lv2_worker::WorkerError> {
         println!("work_response");
-        self.engine.fadeout();
-        self.new_engine = Some(data);
-        self.state_notification_needed = true;
+        // Responses to work scheduled before another instrument was selected are outdated.
+        match data {
+            WorkResponse::Loaded(engine, watch) if watch.parameters.generation == self.load_generation => {
+                self.engine.fadeout();
+                let pending = self.new_engine.replace(engine);
+                let watch = self.watch.replace(watch);
+                if pending.is_some() || watch.is_some() {
+                    retire(&features.schedule, &mut self.retired, Retired { engine: pending, watch });
+                }
+                self.state_notification_needed = true;
+            }
+            WorkResponse::Unchanged(watch) if watch.parameters.generation == self.load_generation => {
+                self.watch = Some(watch);
+            }
+            WorkResponse::Loaded(engine, watch) => {
+                retire(&features.schedule, &mut self.retired, Retired { engine: Some(engine), watch: Some(watch) });
+            }
+            WorkResponse::Unchanged(watch) => {
+                retire(&features.schedule, &mut self.retired, Retired { engine: None, watch: Some(watch) });
+            }
+        }
 
         Ok(())
     }
@@ -0,0 +1,59 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// The global allocator of the tests. It counts the allocations of each thread, so that tests can
+// check that the realtime code paths neither allocate nor free memory.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count() {
+    let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count();
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// The number of allocations, reallocations and deallocations `f` makes on the current thread.
+pub(crate) fn count_allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(|a| a.get());
+    f();
+    ALLOCATIONS.with(|a| a.get()) - before
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_allocations_of_current_thread() {
+        let mut data = Vec::with_capacity(4);
+        assert_eq!(count_allocations(|| data.extend_from_slice(&[1, 2, 3, 4])), 0);
+        assert_eq!(count_allocations(|| data.push(5)), 1);
+        assert_eq!(count_allocations(|| drop(data)), 1);
+    }
+}
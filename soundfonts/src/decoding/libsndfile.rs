@@ -0,0 +1,165 @@
+use std::io::SeekFrom;
+use std::os::raw::{c_int, c_void};
+use std::sync::Mutex;
+
+use sndfile_sys::{sf_count_t, SF_INFO, SF_VIRTUAL_IO, SNDFILE};
+
+use crate::source::SampleReader;
+
+// libsndfile's open functions are not thread safe.
+static OPEN_LOCK: Mutex<()> = Mutex::new(());
+
+// Decodes a sample file from any `SampleReader` using libsndfile's virtual IO.
+pub(crate) struct Decoder {
+    sndfile: *mut SNDFILE,
+    _vio: Box<SF_VIRTUAL_IO>,
+    _reader: Box<Box<dyn SampleReader>>,
+
+    channels: usize,
+    samplerate: f64,
+    frames: usize,
+    format: c_int,
+    seekable: bool,
+}
+
+// The libsndfile handle and the reader are only ever accessed through `&mut self`.
+unsafe impl Send for Decoder {}
+
+impl Decoder {
+    pub(crate) fn new(reader: Box<dyn SampleReader>) -> Option<Decoder> {
+        let mut vio = Box::new(SF_VIRTUAL_IO {
+            get_filelen: vio_get_filelen,
+            seek: vio_seek,
+            read: vio_read,
+            write: vio_write,
+            tell: vio_tell,
+        });
+        let mut reader = Box::new(reader);
+        let mut info = SF_INFO { frames: 0, samplerate: 0, channels: 0, format: 0, sections: 0, seekable: 0 };
+
+        let sndfile = {
+            let _lock = OPEN_LOCK.lock();
+            unsafe {
+                sndfile_sys::sf_open_virtual(&mut *vio,
+                                             sndfile_sys::SFM_READ,
+                                             &mut info,
+                                             &mut *reader as *mut Box<dyn SampleReader> as *mut c_void)
+            }
+        };
+        if sndfile.is_null() {
+            return None;
+        }
+
+        let decoder = Decoder {
+            sndfile,
+            _vio: vio,
+            _reader: reader,
+            channels: info.channels.max(0) as usize,
+            samplerate: info.samplerate as f64,
+            frames: info.frames.max(0) as usize,
+            format: info.format,
+            seekable: info.seekable != 0,
+        };
+        match decoder.channels > 0 && decoder.samplerate > 0.0 {
+            true => Some(decoder),
+            false => None,
+        }
+    }
+
+    pub(crate) fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub(crate) fn samplerate(&self) -> f64 {
+        self.samplerate
+    }
+
+    pub(crate) fn frames(&self) -> usize {
+        self.frames
+    }
+
+    // The bits per sample of integer PCM files.
+    pub(crate) fn pcm_bits(&self) -> Option<u32> {
+        match self.format & sndfile_sys::SF_FORMAT_SUBMASK {
+            sndfile_sys::SF_FORMAT_PCM_S8 | sndfile_sys::SF_FORMAT_PCM_U8 => Some(8),
+            sndfile_sys::SF_FORMAT_PCM_16 => Some(16),
+            sndfile_sys::SF_FORMAT_PCM_24 => Some(24),
+            sndfile_sys::SF_FORMAT_PCM_32 => Some(32),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn seekable(&self) -> bool {
+        self.seekable
+    }
+
+    pub(crate) fn seek(&mut self, frame: usize) -> bool {
+        let position = unsafe { sndfile_sys::sf_seek(self.sndfile, frame as sf_count_t, sndfile_sys::SF_SEEK_SET) };
+        position == frame as sf_count_t
+    }
+
+    // Reads interleaved frames into `buf` and returns the number of frames read.
+    pub(crate) fn read(&mut self, buf: &mut [f32]) -> usize {
+        let frames = (buf.len() / self.channels) as sf_count_t;
+        let read = unsafe { sndfile_sys::sf_readf_float(self.sndfile, buf.as_mut_ptr(), frames) };
+        read.max(0) as usize
+    }
+
+    pub(crate) fn read_all(&mut self) -> Vec<f32> {
+        let mut data = vec![0.0; self.frames * self.channels];
+        let read = self.read(&mut data);
+        data.truncate(read * self.channels);
+        data
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        unsafe {
+            sndfile_sys::sf_close(self.sndfile);
+        }
+    }
+}
+
+fn reader<'a>(user_data: *mut c_void) -> &'a mut Box<dyn SampleReader> {
+    unsafe { &mut *(user_data as *mut Box<dyn SampleReader>) }
+}
+
+extern "C" fn vio_get_filelen(user_data: *mut c_void) -> sf_count_t {
+    let reader = reader(user_data);
+    let length = reader.stream_position()
+        .and_then(|position| reader.seek(SeekFrom::End(0)).map(|length| (position, length)))
+        .and_then(|(position, length)| reader.seek(SeekFrom::Start(position)).map(|_| length));
+    length.map_or(-1, |length| length as sf_count_t)
+}
+
+extern "C" fn vio_seek(offset: sf_count_t, whence: c_int, user_data: *mut c_void) -> sf_count_t {
+    let seek_from = match whence {
+        sndfile_sys::SF_SEEK_SET => SeekFrom::Start(offset as u64),
+        sndfile_sys::SF_SEEK_CUR => SeekFrom::Current(offset),
+        sndfile_sys::SF_SEEK_END => SeekFrom::End(offset),
+        _ => return -1,
+    };
+    reader(user_data).seek(seek_from).map_or(-1, |position| position as sf_count_t)
+}
+
+extern "C" fn vio_read(dst: *mut c_void, count: sf_count_t, user_data: *mut c_void) -> sf_count_t {
+    let buf = unsafe { std::slice::from_raw_parts_mut(dst as *mut u8, count as usize) };
+    let reader = reader(user_data);
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => read += n,
+        }
+    }
+    read as sf_count_t
+}
+
+extern "C" fn vio_write(_src: *const c_void, _count: sf_count_t, _user_data: *mut c_void) -> sf_count_t {
+    0
+}
+
+extern "C" fn vio_tell(user_data: *mut c_void) -> sf_count_t {
+    reader(user_data).stream_position().map_or(-1, |position| position as sf_count_t)
+}
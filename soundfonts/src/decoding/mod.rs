@@ -0,0 +1,23 @@
+// Sample file decoding, either by libsndfile or by pure Rust decoders for WAV, FLAC and Ogg
+// Vorbis. libsndfile is used if both features are enabled.
+
+#[cfg(feature = "libsndfile")]
+mod libsndfile;
+#[cfg(feature = "libsndfile")]
+pub(crate) use self::libsndfile::Decoder;
+
+#[cfg(all(feature = "pure-rust", not(feature = "libsndfile")))]
+mod native;
+#[cfg(all(feature = "pure-rust", not(feature = "libsndfile")))]
+pub(crate) use self::native::Decoder;
+
+#[cfg(not(any(feature = "libsndfile", feature = "pure-rust")))]
+compile_error!("Either the \"libsndfile\" or the \"pure-rust\" feature is needed to decode samples");
+
+use crate::streaming;
+
+impl streaming::FrameReader for Decoder {
+    fn read_frames(&mut self, buf: &mut [f32]) -> usize {
+        self.read(buf)
+    }
+}
@@ -0,0 +1,346 @@
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom};
+
+use lewton::inside_ogg::OggStreamReader;
+use lewton::samples::InterleavedSamples;
+
+use crate::source::SampleReader;
+
+// Frames decoded at once from WAV files, FLAC and Ogg Vorbis decode whole blocks and packets.
+const WAV_CHUNK_FRAMES: usize = 4096;
+
+// Ogg pages are at most 65307 bytes long, so the last one starts within this distance of the end.
+const OGG_TAIL_BYTES: u64 = 65536;
+
+// Decodes WAV, FLAC and Ogg Vorbis files from any `SampleReader` in pure Rust. The format is
+// told by the file's magic bytes.
+pub(crate) struct Decoder {
+    stream: Stream,
+    channels: usize,
+    samplerate: f64,
+    frames: usize,
+    bits: Option<u32>,
+    // Decoded interleaved samples not handed out yet, starting at `pending_pos`.
+    pending: Vec<f32>,
+    pending_pos: usize,
+    position: usize,
+}
+
+enum Stream {
+    Wav(hound::WavReader<Box<dyn SampleReader>>, hound::SampleFormat),
+    Flac(claxon::FlacReader<Box<dyn SampleReader>>, Vec<i32>),
+    Ogg(Box<OggStreamReader<Box<dyn SampleReader>>>),
+}
+
+impl Decoder {
+    pub(crate) fn new(mut reader: Box<dyn SampleReader>) -> Option<Decoder> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).ok()?;
+        reader.seek(SeekFrom::Start(0)).ok()?;
+
+        let (stream, channels, samplerate, frames, bits) = match &magic {
+            b"RIFF" => {
+                let wav = hound::WavReader::new(reader).ok()?;
+                let spec = wav.spec();
+                let bits = match spec.sample_format {
+                    hound::SampleFormat::Int => Some(spec.bits_per_sample as u32),
+                    hound::SampleFormat::Float => None,
+                };
+                let frames = wav.duration() as usize;
+                (Stream::Wav(wav, spec.sample_format), spec.channels as usize, spec.sample_rate, frames, bits)
+            }
+            b"fLaC" => {
+                let flac = claxon::FlacReader::new(reader).ok()?;
+                let info = flac.streaminfo();
+                let frames = info.samples.unwrap_or(0) as usize;
+                let bits = Some(info.bits_per_sample);
+                (Stream::Flac(flac, Vec::new()), info.channels as usize, info.sample_rate, frames, bits)
+            }
+            b"OggS" => {
+                let frames = ogg_frames(&mut reader)?;
+                let ogg = OggStreamReader::new(reader).ok()?;
+                let (channels, samplerate) = (ogg.ident_hdr.audio_channels as usize, ogg.ident_hdr.audio_sample_rate);
+                (Stream::Ogg(Box::new(ogg)), channels, samplerate, frames, None)
+            }
+            _ => return None,
+        };
+
+        match channels > 0 && samplerate > 0 {
+            true => Some(Decoder {
+                stream,
+                channels,
+                samplerate: samplerate as f64,
+                frames,
+                bits,
+                pending: Vec::new(),
+                pending_pos: 0,
+                position: 0,
+            }),
+            false => None,
+        }
+    }
+
+    pub(crate) fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub(crate) fn samplerate(&self) -> f64 {
+        self.samplerate
+    }
+
+    pub(crate) fn frames(&self) -> usize {
+        self.frames
+    }
+
+    // The bits per sample of integer PCM files.
+    pub(crate) fn pcm_bits(&self) -> Option<u32> {
+        self.bits
+    }
+
+    // Only WAV files seek directly, so FLAC and Ogg Vorbis files are not streamed.
+    pub(crate) fn seekable(&self) -> bool {
+        matches!(self.stream, Stream::Wav(..))
+    }
+
+    // FLAC and Ogg Vorbis files can only be decoded forward to `frame`.
+    pub(crate) fn seek(&mut self, frame: usize) -> bool {
+        if self.frames > 0 && frame > self.frames {
+            return false;
+        }
+        if let Stream::Wav(wav, _) = &mut self.stream {
+            if wav.seek(frame as u32).is_err() {
+                return false;
+            }
+            self.pending.clear();
+            self.pending_pos = 0;
+            self.position = frame;
+            return true;
+        }
+        if frame < self.position {
+            return false;
+        }
+        while self.position < frame {
+            if self.pending_pos == self.pending.len() && !self.decode_next() {
+                return false;
+            }
+            let skip = ((self.pending.len() - self.pending_pos) / self.channels).min(frame - self.position);
+            self.pending_pos += skip * self.channels;
+            self.position += skip;
+        }
+        true
+    }
+
+    // Reads interleaved frames into `buf` and returns the number of frames read.
+    pub(crate) fn read(&mut self, buf: &mut [f32]) -> usize {
+        let wanted = buf.len() / self.channels * self.channels;
+        let mut written = 0;
+        while written < wanted {
+            if self.pending_pos == self.pending.len() && !self.decode_next() {
+                break;
+            }
+            let n = (self.pending.len() - self.pending_pos).min(wanted - written);
+            buf[written..written + n].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + n]);
+            self.pending_pos += n;
+            written += n;
+        }
+        self.position += written / self.channels;
+        written / self.channels
+    }
+
+    pub(crate) fn read_all(&mut self) -> Vec<f32> {
+        // FLAC files may leave their length open, as may Ogg Vorbis files without a final granule position.
+        if self.frames == 0 {
+            let mut data = self.pending.split_off(self.pending_pos);
+            while self.decode_next() {
+                data.extend_from_slice(&self.pending);
+            }
+            self.position += data.len() / self.channels;
+            return data;
+        }
+        let mut data = vec![0.0; self.frames * self.channels];
+        let read = self.read(&mut data);
+        data.truncate(read * self.channels);
+        data
+    }
+
+    // Replaces `pending` by the next decoded chunk. Returns false at the end of the stream or
+    // on a decoding error.
+    fn decode_next(&mut self) -> bool {
+        self.pending.clear();
+        self.pending_pos = 0;
+        let pending = &mut self.pending;
+        while pending.is_empty() {
+            let more = match &mut self.stream {
+                Stream::Wav(wav, hound::SampleFormat::Int) => {
+                    let scale = 1.0 / (1u64 << (wav.spec().bits_per_sample - 1)) as f32;
+                    pending.extend(wav.samples::<i32>()
+                                   .take(WAV_CHUNK_FRAMES * self.channels)
+                                   .map_while(|s| s.ok())
+                                   .map(|s| s as f32 * scale));
+                    !pending.is_empty()
+                }
+                Stream::Wav(wav, hound::SampleFormat::Float) => {
+                    pending.extend(wav.samples::<f32>()
+                                   .take(WAV_CHUNK_FRAMES * self.channels)
+                                   .map_while(|s| s.ok()));
+                    !pending.is_empty()
+                }
+                Stream::Flac(flac, buffer) => {
+                    let scale = 1.0 / (1u64 << (flac.streaminfo().bits_per_sample - 1)) as f32;
+                    match flac.blocks().read_next_or_eof(std::mem::take(buffer)) {
+                        Ok(Some(block)) => {
+                            for i in 0..block.duration() {
+                                pending.extend((0..block.channels()).map(|ch| block.sample(ch, i) as f32 * scale));
+                            }
+                            *buffer = block.into_buffer();
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                Stream::Ogg(ogg) => match ogg.read_dec_packet_generic::<InterleavedSamples<f32>>() {
+                    Ok(Some(packet)) => {
+                        pending.extend(packet.samples);
+                        true
+                    }
+                    _ => false,
+                },
+            };
+            if !more {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// The length of an Ogg Vorbis file is the granule position of its last page.
+fn ogg_frames(reader: &mut Box<dyn SampleReader>) -> Option<usize> {
+    let len = reader.seek(SeekFrom::End(0)).ok()?;
+    let start = len.saturating_sub(OGG_TAIL_BYTES);
+    reader.seek(SeekFrom::Start(start)).ok()?;
+    let mut tail = Vec::new();
+    reader.by_ref().take(len - start).read_to_end(&mut tail).ok()?;
+    reader.seek(SeekFrom::Start(0)).ok()?;
+
+    // A granule position of -1 marks pages on which no packet ends.
+    let frames = (0..tail.len().saturating_sub(13))
+        .rev()
+        .filter(|i| &tail[*i..*i + 4] == b"OggS")
+        .map(|i| u64::from_le_bytes(tail[i + 6..i + 14].try_into().unwrap()))
+        .find(|granule| *granule != u64::MAX)
+        .unwrap_or(0);
+    Some(frames as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn wav(spec: hound::WavSpec, samples: &[i32]) -> Box<dyn SampleReader> {
+        let mut data = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut data, spec).unwrap();
+        samples.iter().for_each(|s| writer.write_sample(*s).unwrap());
+        writer.finalize().unwrap();
+        data.set_position(0);
+        Box::new(data)
+    }
+
+    fn int_spec(channels: u16, bits: u16) -> hound::WavSpec {
+        hound::WavSpec {
+            channels,
+            sample_rate: 44100,
+            bits_per_sample: bits,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+
+    #[test]
+    fn decode_wav_16_bit() {
+        let mut decoder = Decoder::new(wav(int_spec(2, 16), &[0, 16384, -32768, 8192])).unwrap();
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.samplerate(), 44100.0);
+        assert_eq!(decoder.frames(), 2);
+        assert_eq!(decoder.pcm_bits(), Some(16));
+        assert_eq!(decoder.read_all(), [0.0, 0.5, -1.0, 0.25]);
+    }
+
+    #[test]
+    fn decode_wav_24_bit() {
+        let mut decoder = Decoder::new(wav(int_spec(1, 24), &[4194304, -8388608])).unwrap();
+        assert_eq!(decoder.pcm_bits(), Some(24));
+        assert_eq!(decoder.read_all(), [0.5, -1.0]);
+    }
+
+    #[test]
+    fn read_and_seek_wav() {
+        let samples: Vec<i32> = (0..10000).map(|i| i % 1000).collect();
+        let mut decoder = Decoder::new(wav(int_spec(1, 16), &samples)).unwrap();
+        assert!(decoder.seekable());
+        let mut buf = vec![0.0; 5000];
+        assert_eq!(decoder.read(&mut buf), 5000);
+        assert_eq!(buf[4999], 999.0 / 32768.0);
+
+        assert!(decoder.seek(1500));
+        assert_eq!(decoder.read(&mut buf[..3]), 3);
+        assert_eq!(buf[..3], [500.0 / 32768.0, 501.0 / 32768.0, 502.0 / 32768.0]);
+
+        assert!(decoder.seek(9999));
+        assert_eq!(decoder.read(&mut buf), 1);
+        assert!(!decoder.seek(10001));
+    }
+
+    #[test]
+    fn unknown_format_is_rejected() {
+        assert!(Decoder::new(Box::new(Cursor::new(b"not a sample file".to_vec()))).is_none());
+        assert!(Decoder::new(Box::new(Cursor::new(b"RI".to_vec()))).is_none());
+    }
+
+    #[test]
+    fn decode_and_seek_flac() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/sine-440Hz-44100sr.flac");
+        let open = || Decoder::new(Box::new(std::io::BufReader::new(std::fs::File::open(&path).unwrap()))).unwrap();
+        let mut decoder = open();
+        assert_eq!(decoder.samplerate(), 44100.0);
+        assert!(!decoder.seekable());
+        let data = decoder.read_all();
+        assert_eq!(data.len(), decoder.frames() * decoder.channels());
+
+        let mut decoder = open();
+        let channels = decoder.channels();
+        let mut buf = vec![0.0; 10 * channels];
+        assert!(decoder.seek(10000));
+        assert_eq!(decoder.read(&mut buf), 10);
+        assert_eq!(buf, data[10000 * channels..10010 * channels]);
+        assert!(!decoder.seek(5000));
+    }
+
+    #[test]
+    fn decode_flac_without_length() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/sine-440Hz-44100sr.flac");
+        let mut file = std::fs::read(&path).unwrap();
+        let data = Decoder::new(Box::new(Cursor::new(file.clone()))).unwrap().read_all();
+        assert!(!data.is_empty());
+
+        // The total samples are the lower 36 bits of bytes 13 to 17 of the STREAMINFO block.
+        file[21] &= 0xf0;
+        file[22..26].copy_from_slice(&[0; 4]);
+        let mut decoder = Decoder::new(Box::new(Cursor::new(file))).unwrap();
+        assert_eq!(decoder.frames(), 0);
+        assert_eq!(decoder.read_all(), data);
+    }
+
+    #[test]
+    fn ogg_length_from_last_page() {
+        let mut file = Vec::new();
+        for granule in &[0u64, 1024, 4410, u64::MAX] {
+            file.extend_from_slice(b"OggS\0\0");
+            file.extend_from_slice(&granule.to_le_bytes());
+            file.extend_from_slice(&[0; 20]);
+        }
+        let mut reader: Box<dyn SampleReader> = Box::new(Cursor::new(file));
+        assert_eq!(ogg_frames(&mut reader), Some(4410));
+        assert_eq!(reader.stream_position().unwrap(), 0);
+    }
+}
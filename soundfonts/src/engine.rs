@@ -1,8 +1,92 @@
+use std::collections::VecDeque;
 
 use wmidi;
 
+// The most MIDI events queued for one `process` call. When the queue is full, the queued events
+// due up to the frame of a further event are applied right away together with it.
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
 pub trait EngineTrait {
     fn midi_event(&mut self, midi_msg: &wmidi::MidiMessage);
 
+    // Queues `midi_msg` to be applied `frame` frames into the next `process` call. Events beyond
+    // the end of the next buffer are kept for the following calls.
+    fn queue_midi_event(&mut self, frame: usize, midi_msg: &wmidi::MidiMessage);
+
     fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]);
 }
+
+// MIDI events in the order of their frame offsets in a ring buffer allocated up front.
+pub(crate) struct EventQueue {
+    events: VecDeque<(usize, wmidi::MidiMessage<'static>)>,
+}
+
+impl EventQueue {
+    pub(crate) fn new() -> EventQueue {
+        EventQueue { events: VecDeque::with_capacity(MAX_QUEUED_EVENTS) }
+    }
+
+    // Hands the event back if the queue is full. Events at the same frame keep their order.
+    pub(crate) fn push(&mut self, frame: usize, midi_msg: wmidi::MidiMessage<'static>) -> Result<(), wmidi::MidiMessage<'static>> {
+        if self.events.len() == MAX_QUEUED_EVENTS {
+            return Err(midi_msg);
+        }
+        let index = self.events.partition_point(|(f, _)| *f <= frame);
+        self.events.insert(index, (frame, midi_msg));
+        Ok(())
+    }
+
+    // Takes the next event due before frame `end`.
+    pub(crate) fn pop_before(&mut self, end: usize) -> Option<(usize, wmidi::MidiMessage<'static>)> {
+        match self.events.front() {
+            Some((frame, _)) if *frame < end => self.events.pop_front(),
+            _ => None,
+        }
+    }
+
+    // Moves the events left after a buffer of `frames` frames to the start of the next one.
+    pub(crate) fn advance(&mut self, frames: usize) {
+        for (frame, _) in &mut self.events {
+            *frame -= frames;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(key: wmidi::Note) -> wmidi::MidiMessage<'static> {
+        wmidi::MidiMessage::NoteOn(wmidi::Channel::Ch1, key, wmidi::Velocity::MAX)
+    }
+
+    #[test]
+    fn events_in_frame_order() {
+        let mut queue = EventQueue::new();
+        assert!(queue.push(10, note_on(wmidi::Note::C3)).is_ok());
+        assert!(queue.push(3, note_on(wmidi::Note::D3)).is_ok());
+        assert!(queue.push(10, note_on(wmidi::Note::E3)).is_ok());
+        assert!(queue.push(70, note_on(wmidi::Note::F3)).is_ok());
+
+        assert_eq!(queue.pop_before(64), Some((3, note_on(wmidi::Note::D3))));
+        assert_eq!(queue.pop_before(64), Some((10, note_on(wmidi::Note::C3))));
+        assert_eq!(queue.pop_before(64), Some((10, note_on(wmidi::Note::E3))));
+        assert_eq!(queue.pop_before(64), None);
+
+        queue.advance(64);
+        assert_eq!(queue.pop_before(6), None);
+        assert_eq!(queue.pop_before(7), Some((6, note_on(wmidi::Note::F3))));
+    }
+
+    #[test]
+    fn full_queue_rejects_events() {
+        let mut queue = EventQueue::new();
+        for frame in 0..MAX_QUEUED_EVENTS {
+            assert!(queue.push(frame, note_on(wmidi::Note::C3)).is_ok());
+        }
+        assert_eq!(queue.push(0, note_on(wmidi::Note::D3)), Err(note_on(wmidi::Note::D3)));
+        assert_eq!(queue.pop_before(1), Some((0, note_on(wmidi::Note::C3))));
+        assert!(queue.push(0, note_on(wmidi::Note::D3)).is_ok());
+        assert_eq!(queue.pop_before(1), Some((0, note_on(wmidi::Note::D3))));
+    }
+}
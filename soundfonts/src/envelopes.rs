@@ -1,36 +1,144 @@
-use log::error;
-
 use crate::errors::*;
 
 use super::utils;
 
+// The envelope parameters that velocity and controllers modulate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Stage {
+    Delay,
+    Start,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+}
+
+impl Stage {
+    // The stage of an `ampeg_<stage>_onccN` opcode.
+    pub(crate) fn from_name(name: &str) -> Option<Stage> {
+        match name {
+            "delay" => Some(Stage::Delay),
+            "start" => Some(Stage::Start),
+            "attack" => Some(Stage::Attack),
+            "hold" => Some(Stage::Hold),
+            "decay" => Some(Stage::Decay),
+            "sustain" => Some(Stage::Sustain),
+            "release" => Some(Stage::Release),
+            _ => None,
+        }
+    }
+
+    fn oncc_key(&self) -> &'static str {
+        match self {
+            Stage::Delay => "ampeg_delay_onccN",
+            Stage::Start => "ampeg_start_onccN",
+            Stage::Attack => "ampeg_attack_onccN",
+            Stage::Hold => "ampeg_hold_onccN",
+            Stage::Decay => "ampeg_decay_onccN",
+            Stage::Sustain => "ampeg_sustain_onccN",
+            Stage::Release => "ampeg_release_onccN",
+        }
+    }
+
+    // Start and sustain are levels given in percent, the others times in seconds.
+    fn is_level(&self) -> bool {
+        matches!(self, Stage::Start | Stage::Sustain)
+    }
+}
+
+// How a stage moves from its start to its end level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Shape {
+    // Decays and releases fall by the same factor every frame, attacks follow a curvature of -8.
+    Exponential,
+    // 0 is linear, negative curvatures change fast at first and slow down, positive ones start
+    // slow.
+    Curve(f32),
+}
+
+impl Shape {
+    pub(crate) const LINEAR: Shape = Shape::Curve(0.0);
+
+    // The part of the way from the start to the end level after `x` of the stage.
+    fn progress(&self, x: f32) -> f32 {
+        let curvature = match *self {
+            Shape::Exponential => -8.0,
+            Shape::Curve(curvature) => curvature,
+        };
+        if curvature.abs() < 1e-3 {
+            return x;
+        }
+        // (e^(c*x) - 1) / (e^c - 1), rearranged for positive curvatures so that it does not
+        // overflow.
+        match curvature > 0.0 {
+            true => (curvature * (x - 1.0)).exp() * (-curvature * x).exp_m1() / (-curvature).exp_m1(),
+            false => (curvature * x).exp_m1() / curvature.exp_m1(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Generator {
+    delay: f32,
+    start: f32,
     attack: f32,
     hold: f32,
     decay: f32,
     sustain: f32,
     release: f32,
+
+    attack_shape: Shape,
+    decay_shape: Shape,
+    release_shape: Shape,
+
+    vel2delay: f32,
+    vel2attack: f32,
+    vel2hold: f32,
+    vel2decay: f32,
+    vel2sustain: f32,
+    vel2release: f32,
+
+    // The `ampeg_*_onccN` modulations as controller number, stage and amount at value 127.
+    oncc: Vec<(u8, Stage, f32)>,
 }
 
 impl Default for Generator {
     fn default() -> Self {
         Generator {
+            delay: 0.0,
+            start: 0.0,
             attack: 0.0,
             hold: 0.0,
             decay: 0.0,
             sustain: 1.0,
             release: 0.0,
+
+            attack_shape: Shape::LINEAR,
+            decay_shape: Shape::Exponential,
+            release_shape: Shape::Exponential,
+
+            vel2delay: 0.0,
+            vel2attack: 0.0,
+            vel2hold: 0.0,
+            vel2decay: 0.0,
+            vel2sustain: 0.0,
+            vel2release: 0.0,
+
+            oncc: Vec::new(),
         }
     }
 }
 
-fn calc_needed_samples(length: f32, samplerate: f32, max_block_length: usize) -> usize {
-    let needed_samples = (length * samplerate).round() as usize;
-    ((needed_samples / max_block_length) + 2) * max_block_length
-}
-
 impl Generator {
+    pub(crate) fn set_delay(&mut self, v: f32) -> Result<(), RangeError> {
+        self.delay = range_check(v, 0.0, 100.0, "ampeg_delay")?;
+        Ok(())
+    }
+    pub(crate) fn set_start(&mut self, v: f32) -> Result<(), RangeError> {
+        self.start = range_check(v, 0.0, 100.0, "ampeg_start")? / 100.0;
+        Ok(())
+    }
     pub(crate) fn set_attack(&mut self, v: f32) -> Result<(), RangeError> {
         self.attack = range_check(v, 0.0, 100.0, "ampeg_attack")?;
         Ok(())
@@ -52,134 +160,227 @@ impl Generator {
         Ok(())
     }
 
-    fn ads_envelope(&self, samplerate: f32, max_block_length: usize) -> Vec<f32> {
-        let length = calc_needed_samples(
-            self.attack + self.hold + 2.0 * self.decay,
-            samplerate,
-            max_block_length,
-        );
-
-        let mut env = Vec::with_capacity(length);
-        env.resize(length, 0.0);
-
-        let decay_step = (-8.0 / (samplerate * self.decay)).exp();
-        let mut time = 0;
-        let mut last = 1.0 - self.sustain;
-
-        for e in env.iter_mut() {
-            *e = match time as f32 / samplerate {
-                t if t < self.attack => t / self.attack,
-                t if t < self.attack + self.hold => 1.0,
-                t if t < self.attack + self.hold + 2.0 * self.decay => {
-                    last *= decay_step;
-                    self.sustain + last
-                }
-                _ => self.sustain,
-            };
-            time += 1;
-        }
-        env
+    pub(crate) fn set_attack_shape(&mut self, shape: Shape) {
+        self.attack_shape = shape;
     }
-
-    fn sustain_envelope(&self, nsamples: usize) -> Vec<f32> {
-        let mut sustain = Vec::new();
-        sustain.resize(nsamples, self.sustain);
-        sustain
+    pub(crate) fn set_decay_shape(&mut self, shape: Shape) {
+        self.decay_shape = shape;
+    }
+    pub(crate) fn set_release_shape(&mut self, shape: Shape) {
+        self.release_shape = shape;
     }
 
-    fn release_envelope(&self, samplerate: f32, max_block_length: usize) -> Vec<f32> {
-        let length = calc_needed_samples(2.0 * self.release, samplerate, max_block_length);
-        let mut env = Vec::new();
-        env.resize(length, 0.0);
-
-        let release_step = (-8.0 / (samplerate * self.release)).exp();
-        let mut last = self.sustain;
+    pub(crate) fn set_vel2delay(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2delay = range_check(v, -100.0, 100.0, "ampeg_vel2delay")?;
+        Ok(())
+    }
+    pub(crate) fn set_vel2attack(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2attack = range_check(v, -100.0, 100.0, "ampeg_vel2attack")?;
+        Ok(())
+    }
+    pub(crate) fn set_vel2hold(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2hold = range_check(v, -100.0, 100.0, "ampeg_vel2hold")?;
+        Ok(())
+    }
+    pub(crate) fn set_vel2decay(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2decay = range_check(v, -100.0, 100.0, "ampeg_vel2decay")?;
+        Ok(())
+    }
+    pub(crate) fn set_vel2sustain(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2sustain = range_check(v, -100.0, 100.0, "ampeg_vel2sustain")? / 100.0;
+        Ok(())
+    }
+    pub(crate) fn set_vel2release(&mut self, v: f32) -> Result<(), RangeError> {
+        self.vel2release = range_check(v, -100.0, 100.0, "ampeg_vel2release")?;
+        Ok(())
+    }
 
-        for e in env.iter_mut() {
-            last *= release_step;
-            *e = last;
+    // A region redefining the modulation of a controller and stage replaces the group's.
+    pub(crate) fn set_oncc(&mut self, cc: u32, stage: Stage, v: f32) -> Result<(), RangeError> {
+        let cc = range_check(cc, 0, 127, "cc number")? as u8;
+        let mut amount = range_check(v, -100.0, 100.0, stage.oncc_key())?;
+        if stage.is_level() {
+            amount /= 100.0;
         }
+        match self.oncc.iter_mut().find(|(c, s, _)| *c == cc && *s == stage) {
+            Some(modulation) => modulation.2 = amount,
+            None => self.oncc.push((cc, stage, amount)),
+        }
+        Ok(())
+    }
 
-        env
+    // The sum of the controller modulations of `stage`.
+    fn cc_modulation(&self, stage: Stage, controllers: &[u8; 128]) -> f32 {
+        self.oncc.iter()
+            .filter(|(_, s, _)| *s == stage)
+            .map(|(cc, _, amount)| amount * controllers[*cc as usize] as f32 / 127.0)
+            .sum()
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+// The state of the envelope of one voice.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum State {
-    AttackDecay(usize),
+    // The frames left until the attack starts.
+    Delay(usize),
+    // The frames since the attack started and the remaining decay above the sustain level.
+    AttackDecay(usize, f32),
     Sustain,
-    Release(usize),
+    // The frames since note off and the release level.
+    Release(usize, f32),
     Inactive,
 }
 
 impl State {
     pub fn is_active(&self) -> bool {
-        match *self {
-            State::Inactive => false,
-            _ => true,
-        }
+        !matches!(self, State::Inactive)
     }
 
     pub fn is_releasing(&self) -> bool {
-        match *self {
-            State::Inactive | State::Release(_) => true,
-            _ => false,
-        }
+        matches!(self, State::Inactive | State::Release(..))
     }
 }
 
+// The shape of the envelope of a voice, computed frame by frame from its `State`.
+#[derive(Debug, Clone, Copy)]
 pub struct ADSREnvelope {
-    attack_decay_envelope: Vec<f32>,
-    sustain_envelope: Vec<f32>,
-    release_envelope: Vec<f32>,
+    samplerate: f32,
+    delay_frames: usize,
+    start: f32,
+    attack: f32,
+    hold: f32,
+    decay: f32,
+    sustain: f32,
 
-    max_block_length: usize,
+    attack_shape: Shape,
+    decay_shape: Shape,
+    release_shape: Shape,
+
+    decay_step: f32,
+    release_step: f32,
+    // An exponential release ends after twice the release time at the latest, the others after
+    // the release time.
+    release_frames: usize,
 }
 
 impl ADSREnvelope {
-    pub(crate) fn new(generator: &Generator, samplerate: f32, max_block_length: usize) -> Self {
+    pub(crate) fn new(generator: &Generator, samplerate: f32) -> Self {
+        Self::for_note(generator, samplerate, 0, &[0; 128])
+    }
+
+    // The envelope of a note played with `velocity` while the controllers are at `controllers`.
+    pub(crate) fn for_note(generator: &Generator, samplerate: f32, velocity: u8, controllers: &[u8; 128]) -> Self {
+        let vel = velocity as f32 / 127.0;
+        let time = |base: f32, vel2: f32, stage| {
+            (base + vel2 * vel + generator.cc_modulation(stage, controllers)).clamp(0.0, 100.0)
+        };
+        let level = |base: f32, vel2: f32, stage| {
+            (base + vel2 * vel + generator.cc_modulation(stage, controllers)).clamp(0.0, 1.0)
+        };
+
+        let delay = time(generator.delay, generator.vel2delay, Stage::Delay);
+        let decay = time(generator.decay, generator.vel2decay, Stage::Decay);
+        let release = time(generator.release, generator.vel2release, Stage::Release);
+
         ADSREnvelope {
-            attack_decay_envelope: generator.ads_envelope(samplerate, max_block_length),
-            sustain_envelope: generator.sustain_envelope(max_block_length),
-            release_envelope: generator.release_envelope(samplerate, max_block_length),
+            samplerate,
+            delay_frames: (delay * samplerate).round() as usize,
+            start: level(generator.start, 0.0, Stage::Start),
+            attack: time(generator.attack, generator.vel2attack, Stage::Attack),
+            hold: time(generator.hold, generator.vel2hold, Stage::Hold),
+            decay,
+            sustain: level(generator.sustain, generator.vel2sustain, Stage::Sustain),
 
-            max_block_length: max_block_length,
+            attack_shape: generator.attack_shape,
+            decay_shape: generator.decay_shape,
+            release_shape: generator.release_shape,
+
+            decay_step: (-8.0 / (samplerate * decay)).exp(),
+            release_step: (-8.0 / (samplerate * release)).exp(),
+            release_frames: match generator.release_shape {
+                Shape::Exponential => (2.0 * release * samplerate).round() as usize,
+                _ => (release * samplerate).round() as usize,
+            },
         }
     }
 
-    pub(crate) fn active_envelope(&self, state: State) -> (&Vec<f32>, usize) {
-        match state {
-            State::AttackDecay(pos) => (&self.attack_decay_envelope, pos),
-            State::Release(pos) => (&self.release_envelope, pos),
-            State::Sustain => (&self.sustain_envelope, 0),
-            State::Inactive => {
-                error!("Ordered envelope while inactive. This should not happen. Using sustain.");
-                (&self.sustain_envelope, 0)
-            }
+    pub(crate) fn start(&self) -> State {
+        match self.delay_frames {
+            0 => self.attack_state(),
+            frames => State::Delay(frames),
         }
     }
 
-    pub(crate) fn update_state(&self, state: &mut State, new_pos: usize) {
-        *state = match &state {
-            State::AttackDecay(_) => {
-                if new_pos < self.attack_decay_envelope.len() - self.max_block_length {
-                    State::AttackDecay(new_pos)
-                } else {
-                    State::Sustain
+    fn attack_state(&self) -> State {
+        State::AttackDecay(0, 1.0 - self.sustain)
+    }
+
+    pub(crate) fn release(&self) -> State {
+        State::Release(0, self.sustain)
+    }
+
+    // The envelope gain of the current frame, advancing `state` to the next one.
+    pub(crate) fn next(&self, state: &mut State) -> f32 {
+        match state {
+            State::Delay(remaining) => {
+                *remaining -= 1;
+                if *remaining == 0 {
+                    *state = self.attack_state();
+                }
+                0.0
+            }
+            State::AttackDecay(time, last) => {
+                let t = *time as f32 / self.samplerate;
+                *time += 1;
+                let decay_start = self.attack + self.hold;
+                match t {
+                    t if t < self.attack => {
+                        self.start + (1.0 - self.start) * self.attack_shape.progress(t / self.attack)
+                    }
+                    t if t < decay_start => 1.0,
+                    t if self.decay_shape == Shape::Exponential && t < decay_start + 2.0 * self.decay => {
+                        *last *= self.decay_step;
+                        self.sustain + *last
+                    }
+                    t if self.decay_shape != Shape::Exponential && t < decay_start + self.decay => {
+                        let progress = self.decay_shape.progress((t - decay_start) / self.decay);
+                        self.sustain + (1.0 - self.sustain) * (1.0 - progress)
+                    }
+                    _ => {
+                        *state = State::Sustain;
+                        self.sustain
+                    }
                 }
             }
-            State::Release(_) => {
-                if new_pos < self.release_envelope.len() - self.max_block_length
-                    && self.release_envelope[new_pos] > utils::dB_to_gain(-160.0)
-                {
-                    State::Release(new_pos)
-                } else {
-                    State::Inactive
+            State::Sustain => self.sustain,
+            State::Release(time, level) => {
+                let value = match self.release_shape {
+                    Shape::Exponential => {
+                        *level *= self.release_step;
+                        *level
+                    }
+                    shape => {
+                        let x = *time as f32 / self.release_frames as f32;
+                        *level * (1.0 - shape.progress(x))
+                    }
+                };
+                *time += 1;
+                match *time > self.release_frames || value <= utils::dB_to_gain(-160.0) {
+                    true => {
+                        *state = State::Inactive;
+                        0.0
+                    }
+                    false => value,
                 }
             }
-            s => **s,
+            State::Inactive => 0.0,
         }
     }
+
+    // The envelope gain `next` would return for `state`.
+    pub(crate) fn peek(&self, mut state: State) -> f32 {
+        self.next(&mut state)
+    }
 }
 
 #[cfg(test)]
@@ -187,13 +388,32 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn shape_curvature() {
+        for shape in &[Shape::Exponential, Shape::LINEAR, Shape::Curve(-3.0), Shape::Curve(1000.0), Shape::Curve(-1000.0)] {
+            assert_eq!(shape.progress(0.0), 0.0);
+            assert!((shape.progress(1.0) - 1.0).abs() < 1e-6);
+            assert!(shape.progress(0.5).is_finite());
+        }
+        assert_eq!(Shape::LINEAR.progress(0.25), 0.25);
+        assert!(Shape::Curve(-3.0).progress(0.25) > Shape::Curve(-1.0).progress(0.25));
+        assert!(Shape::Curve(-1.0).progress(0.25) > 0.25);
+        assert!(Shape::Curve(1.0).progress(0.25) < 0.25);
+        assert!(Shape::Curve(3.0).progress(0.25) < Shape::Curve(1.0).progress(0.25));
+        assert!((Shape::Curve(2.0).progress(0.3) + Shape::Curve(-2.0).progress(0.7) - 1.0).abs() < 1e-6);
+    }
+
+    fn render(envelope: &ADSREnvelope, mut state: State, frames: usize) -> Vec<f32> {
+        (0..frames).map(|_| envelope.next(&mut state)).collect()
+    }
+
     #[test]
     fn adsr_envelope_length() {
-        let eg = Generator::default();
+        let envelope = ADSREnvelope::new(&Generator::default(), 1.0);
 
-        assert_eq!(eg.ads_envelope(1.0, 8).as_slice(), [1.0; 16]);
-        assert_eq!(eg.sustain_envelope(8).as_slice(), [1.0; 8]);
-        assert_eq!(eg.release_envelope(1.0, 8).as_slice(), [0.0; 16]);
+        assert_eq!(render(&envelope, envelope.start(), 16).as_slice(), [1.0; 16]);
+        assert_eq!(render(&envelope, State::Sustain, 8).as_slice(), [1.0; 8]);
+        assert_eq!(render(&envelope, envelope.release(), 16).as_slice(), [0.0; 16]);
     }
 
     #[test]
@@ -204,11 +424,114 @@ mod tests {
         eg.set_decay(4.0).unwrap();
         eg.set_sustain(60.0).unwrap();
         eg.set_release(5.0).unwrap();
+        let envelope = ADSREnvelope::new(&eg, 1.0);
 
-        let ads: Vec<f32> = eg.ads_envelope(1.0, 12)[..12].iter().map(|v| (v*100.0).round()/100.0).collect();
+        let ads: Vec<f32> = render(&envelope, envelope.start(), 12).iter().map(|v| (v*100.0).round()/100.0).collect();
         assert_eq!(ads.as_slice(), [0.0, 0.5, 1.0, 1.0, 1.0, 0.65, 0.61, 0.6, 0.6, 0.6, 0.6, 0.6]);
 
-        let rel: Vec<f32> = eg.release_envelope(1.0, 8)[..8].iter().map(|v| (v*10000.0).round()/10000.0).collect();
+        let rel: Vec<f32> = render(&envelope, envelope.release(), 8).iter().map(|v| (v*10000.0).round()/10000.0).collect();
         assert_eq!(rel.as_slice(), [0.1211, 0.0245, 0.0049, 0.0010, 0.0002, 0.0, 0.0, 0.0]);
     }
+
+    #[test]
+    fn envelope_states() {
+        let mut eg = Generator::default();
+        eg.set_attack(2.0).unwrap();
+        eg.set_decay(1.0).unwrap();
+        eg.set_sustain(50.0).unwrap();
+        eg.set_release(4.0).unwrap();
+        let envelope = ADSREnvelope::new(&eg, 1.0);
+
+        let mut state = envelope.start();
+        assert_eq!(envelope.next(&mut state), 0.0);
+        assert_eq!(envelope.next(&mut state), 0.5);
+        envelope.next(&mut state);
+        envelope.next(&mut state);
+        assert!(matches!(state, State::AttackDecay(4, _)));
+        assert_eq!(envelope.peek(state), 0.5);
+        assert!(matches!(state, State::AttackDecay(4, _)));
+        envelope.next(&mut state);
+        assert_eq!(state, State::Sustain);
+        assert!(state.is_active() && !state.is_releasing());
+
+        let mut state = envelope.release();
+        for _ in 0..8 {
+            assert!(envelope.next(&mut state) > 0.0);
+        }
+        assert!(state.is_active() && state.is_releasing());
+        assert_eq!(envelope.next(&mut state), 0.0);
+        assert_eq!(state, State::Inactive);
+        assert!(!state.is_active());
+    }
+
+    #[test]
+    fn delay_and_start_level() {
+        let mut eg = Generator::default();
+        eg.set_delay(2.0).unwrap();
+        eg.set_start(50.0).unwrap();
+        eg.set_attack(4.0).unwrap();
+        let envelope = ADSREnvelope::new(&eg, 1.0);
+
+        assert_eq!(envelope.start(), State::Delay(2));
+        assert_eq!(render(&envelope, envelope.start(), 8).as_slice(), [0.0, 0.0, 0.5, 0.625, 0.75, 0.875, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn velocity_and_controller_modulation() {
+        let mut eg = Generator::default();
+        eg.set_attack(2.0).unwrap();
+        eg.set_vel2attack(2.0).unwrap();
+        eg.set_oncc(1, Stage::Attack, -4.0).unwrap();
+        eg.set_sustain(50.0).unwrap();
+        eg.set_vel2sustain(-25.0).unwrap();
+        eg.set_oncc(7, Stage::Sustain, 100.0).unwrap();
+        eg.set_oncc(7, Stage::Sustain, 25.0).unwrap();
+
+        let mut controllers = [0; 128];
+        let envelope = ADSREnvelope::for_note(&eg, 1.0, 127, &controllers);
+        assert_eq!(render(&envelope, envelope.start(), 6).as_slice(), [0.0, 0.25, 0.5, 0.75, 0.25, 0.25]);
+
+        controllers[1] = 127;
+        controllers[7] = 127;
+        let envelope = ADSREnvelope::for_note(&eg, 1.0, 0, &controllers);
+        assert_eq!(render(&envelope, envelope.start(), 3).as_slice(), [0.75, 0.75, 0.75]);
+    }
+
+    #[test]
+    fn modulation_ranges() {
+        let mut eg = Generator::default();
+        assert_eq!(format!("{}", eg.set_vel2attack(-105.0).unwrap_err()), "ampeg_vel2attack out of range: -100 <= -105 <= 100");
+        assert_eq!(format!("{}", eg.set_oncc(1, Stage::Decay, 120.0).unwrap_err()), "ampeg_decay_onccN out of range: -100 <= 120 <= 100");
+        assert_eq!(format!("{}", eg.set_oncc(128, Stage::Decay, 1.0).unwrap_err()), "cc number out of range: 0 <= 128 <= 127");
+        assert_eq!(Stage::from_name("sustain"), Some(Stage::Sustain));
+        assert_eq!(Stage::from_name("velocity"), None);
+    }
+
+    #[test]
+    fn envelope_shapes() {
+        let mut eg = Generator::default();
+        eg.set_sustain(50.0).unwrap();
+        eg.set_decay(4.0).unwrap();
+        eg.set_release(4.0).unwrap();
+        eg.set_decay_shape(Shape::LINEAR);
+        eg.set_release_shape(Shape::LINEAR);
+        let envelope = ADSREnvelope::new(&eg, 1.0);
+        assert_eq!(render(&envelope, envelope.start(), 6).as_slice(), [1.0, 0.875, 0.75, 0.625, 0.5, 0.5]);
+        assert_eq!(render(&envelope, envelope.release(), 6).as_slice(), [0.5, 0.375, 0.25, 0.125, 0.0, 0.0]);
+
+        let rounded = |values: Vec<f32>| values.iter().map(|v| (v*100.0).round()/100.0).collect::<Vec<f32>>();
+        let mut eg = Generator::default();
+        eg.set_attack(2.0).unwrap();
+        eg.set_attack_shape(Shape::Exponential);
+        let envelope = ADSREnvelope::new(&eg, 1.0);
+        assert_eq!(rounded(render(&envelope, envelope.start(), 3)).as_slice(), [0.0, 0.98, 1.0]);
+
+        eg.set_attack_shape(Shape::Curve(8.0));
+        let envelope = ADSREnvelope::new(&eg, 1.0);
+        assert_eq!(rounded(render(&envelope, envelope.start(), 3)).as_slice(), [0.0, 0.02, 1.0]);
+
+        eg.set_attack_shape(Shape::Curve(2.5));
+        let envelope = ADSREnvelope::new(&eg, 1.0);
+        assert_eq!(rounded(render(&envelope, envelope.start(), 3)).as_slice(), [0.0, 0.22, 1.0]);
+    }
 }
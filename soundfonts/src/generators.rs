@@ -0,0 +1,143 @@
+use std::f64::consts::PI;
+
+const TABLE_LENGTH: usize = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Noise,
+    Silence,
+}
+
+impl Waveform {
+    pub(crate) fn from_sample_name(name: &str) -> Option<Waveform> {
+        match name {
+            "*sine" => Some(Waveform::Sine),
+            "*saw" => Some(Waveform::Saw),
+            "*square" => Some(Waveform::Square),
+            "*triangle" | "*tri" => Some(Waveform::Triangle),
+            "*noise" => Some(Waveform::Noise),
+            "*silence" => Some(Waveform::Silence),
+            _ => None,
+        }
+    }
+
+    // Returns mono tables, their samplerate and how many bands they are. Periodic waveforms are
+    // rendered as single cycles that sound at `native_frequency` when played at the returned
+    // samplerate, one band per octave of playback speed. Band `b` keeps only the harmonics that
+    // stay below Nyquist when it is read at up to 2^b frames per output frame.
+    pub(crate) fn render(&self, native_frequency: f64, host_samplerate: f64) -> (Vec<f32>, f64, usize) {
+        let samplerate = native_frequency * TABLE_LENGTH as f64;
+
+        let amplitude: fn(usize) -> f64 = match self {
+            Waveform::Sine => return (additive(|k| if k == 1 { 1.0 } else { 0.0 }, 1), samplerate, 1),
+            Waveform::Saw => |k| {
+                let sign = if k % 2 == 1 { 1.0 } else { -1.0 };
+                sign * 2.0 / (PI * k as f64)
+            },
+            Waveform::Square => |k| {
+                if k % 2 == 1 { 4.0 / (PI * k as f64) } else { 0.0 }
+            },
+            Waveform::Triangle => |k| {
+                match k % 4 {
+                    1 => 8.0 / (PI * PI * (k * k) as f64),
+                    3 => -8.0 / (PI * PI * (k * k) as f64),
+                    _ => 0.0,
+                }
+            },
+            Waveform::Noise => {
+                let noise = (0..host_samplerate.round() as usize)
+                    .map(|_| 2.0 * rand::random::<f32>() - 1.0)
+                    .collect();
+                return (noise, host_samplerate, 1);
+            }
+            Waveform::Silence => return (vec![0.0; TABLE_LENGTH], samplerate, 1),
+        };
+
+        let tables = (0..BANDS)
+            .flat_map(|band| additive(amplitude, band_harmonics(band)))
+            .collect();
+        (tables, samplerate, BANDS)
+    }
+}
+
+// Enough bands that the last one keeps only the fundamental.
+const BANDS: usize = 11;
+
+fn band_harmonics(band: usize) -> usize {
+    ((TABLE_LENGTH >> (band + 1)).saturating_sub(1)).max(1)
+}
+
+fn additive<F: Fn(usize) -> f64>(amplitude: F, harmonics: usize) -> Vec<f32> {
+    (0..TABLE_LENGTH)
+        .map(|i| {
+            let phase = 2.0 * PI * i as f64 / TABLE_LENGTH as f64;
+            (1..=harmonics)
+                .map(|k| amplitude(k) * (k as f64 * phase).sin())
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sample::tests::assert_frequency_result_sample;
+
+    #[test]
+    fn waveform_names() {
+        assert_eq!(Waveform::from_sample_name("*sine"), Some(Waveform::Sine));
+        assert_eq!(Waveform::from_sample_name("*saw"), Some(Waveform::Saw));
+        assert_eq!(Waveform::from_sample_name("*square"), Some(Waveform::Square));
+        assert_eq!(Waveform::from_sample_name("*triangle"), Some(Waveform::Triangle));
+        assert_eq!(Waveform::from_sample_name("*noise"), Some(Waveform::Noise));
+        assert_eq!(Waveform::from_sample_name("*silence"), Some(Waveform::Silence));
+        assert_eq!(Waveform::from_sample_name("sine.wav"), None);
+    }
+
+    #[test]
+    fn render_sine_table() {
+        let (table, samplerate, bands) = Waveform::Sine.render(440.0, 48000.0);
+        assert_eq!(table.len(), TABLE_LENGTH);
+        assert_eq!(bands, 1);
+        assert_eq!(samplerate, 440.0 * TABLE_LENGTH as f64);
+        assert_eq!(table[0], 0.0);
+        assert!((table[TABLE_LENGTH / 4] - 1.0).abs() < 1e-6);
+        assert!((table[3 * TABLE_LENGTH / 4] + 1.0).abs() < 1e-6);
+
+        assert_frequency_result_sample(&table, samplerate, 440.0);
+    }
+
+    #[test]
+    fn render_band_limited() {
+        let (tables, _, bands) = Waveform::Square.render(100.0, 48000.0);
+        assert_eq!(bands, BANDS);
+        assert_eq!(tables.len(), BANDS * TABLE_LENGTH);
+        assert_eq!(band_harmonics(0), TABLE_LENGTH / 2 - 1);
+        assert_eq!(band_harmonics(BANDS - 1), 1);
+
+        let low = &tables[..TABLE_LENGTH];
+        let high = &tables[(BANDS - 1) * TABLE_LENGTH..];
+        let high_sine: Vec<f32> = (0..TABLE_LENGTH)
+            .map(|i| (4.0 / PI * (2.0 * PI * i as f64 / TABLE_LENGTH as f64).sin()) as f32)
+            .collect();
+        assert!(Iterator::zip(high.iter(), high_sine.iter()).all(|(a, b)| (a - b).abs() < 1e-6));
+        assert!(Iterator::zip(low.iter(), high.iter()).any(|(a, b)| (a - b).abs() > 0.01));
+    }
+
+    #[test]
+    fn render_noise_and_silence() {
+        let (noise, samplerate, bands) = Waveform::Noise.render(440.0, 100.0);
+        assert_eq!((samplerate, bands), (100.0, 1));
+        assert_eq!(noise.len(), 100);
+        assert!(noise.iter().all(|s| *s >= -1.0 && *s <= 1.0));
+        assert!(noise.iter().any(|s| *s != 0.0));
+
+        let (silence, _, _) = Waveform::Silence.render(440.0, 48000.0);
+        assert!(silence.iter().all(|s| *s == 0.0));
+    }
+}
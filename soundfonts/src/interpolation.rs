@@ -0,0 +1,260 @@
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+use crate::resampling;
+use crate::storage::SampleFormat;
+
+// Frames before and after the current position any of the interpolators reads.
+pub(crate) const FRAMES_BEFORE: usize = 7;
+pub(crate) const FRAMES_AFTER: usize = 8;
+
+const SINC_TAPS: usize = FRAMES_BEFORE + FRAMES_AFTER + 1;
+const SINC_KAISER_BETA: f64 = 8.0;
+const WINDOW_OVERSAMPLING: usize = 256;
+// Pitching up by more than an octave still aliases, the kernel would get too wide otherwise.
+const SINC_MIN_CUTOFF: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Interpolation {
+    Linear,
+    #[default]
+    Cubic,
+    Sinc,
+}
+
+impl Interpolation {
+    // Maps the `sample_quality` opcode: 1 is linear, 2 cubic, higher values sinc.
+    pub(crate) fn from_sample_quality(quality: u32) -> Interpolation {
+        match quality {
+            0 | 1 => Interpolation::Linear,
+            2 => Interpolation::Cubic,
+            _ => Interpolation::Sinc,
+        }
+    }
+
+    // Prepares what the interpolator needs outside of the realtime thread.
+    pub(crate) fn prepare(&self) {
+        if *self == Interpolation::Sinc {
+            sinc_window();
+        }
+    }
+}
+
+// Reads the value at `remainder` after the frame at `pos` of the interleaved `data` with
+// `stride` channels. Reads before the beginning wrap around into the padding at the end.
+pub(crate) trait Interpolator {
+    fn new(ratio: f64) -> Self;
+    fn interpolate<T: SampleFormat>(&self, data: &[T], pos: usize, stride: usize, remainder: f64) -> f32;
+}
+
+pub(crate) struct Linear;
+
+impl Interpolator for Linear {
+    fn new(_ratio: f64) -> Self {
+        Linear
+    }
+
+    fn interpolate<T: SampleFormat>(&self, data: &[T], pos: usize, stride: usize, remainder: f64) -> f32 {
+        let p1 = data[pos].to_f64();
+        let p2 = data[pos + stride].to_f64();
+        (p1 + (p2 - p1) * remainder) as f32
+    }
+}
+
+pub(crate) struct Cubic;
+
+impl Interpolator for Cubic {
+    fn new(_ratio: f64) -> Self {
+        Cubic
+    }
+
+    fn interpolate<T: SampleFormat>(&self, data: &[T], pos: usize, stride: usize, remainder: f64) -> f32 {
+        let len = data.len();
+
+        let p0 = data[((pos + len) - stride) % len].to_f64();
+        let p1 = data[pos].to_f64();
+        let p2 = data[pos + stride].to_f64();
+        let p3 = data[pos + 2 * stride].to_f64();
+
+        let a = remainder;
+        let b = 1.0 - a;
+        let c = a * b;
+
+        ((1.0 + 1.5 * c) * (p1 * b + p2 * a) - 0.5 * c * (p0 * b + p1 + p2 + p3 * a)) as f32
+    }
+}
+
+// Kaiser windowed sinc, its cutoff lowered when pitching up so that the result stays band limited.
+pub(crate) struct Sinc {
+    cutoff: f64,
+    window: &'static [f64],
+}
+
+impl Interpolator for Sinc {
+    fn new(ratio: f64) -> Self {
+        Sinc {
+            cutoff: ratio.recip().clamp(SINC_MIN_CUTOFF, 1.0),
+            window: sinc_window(),
+        }
+    }
+
+    fn interpolate<T: SampleFormat>(&self, data: &[T], pos: usize, stride: usize, remainder: f64) -> f32 {
+        let len = data.len();
+        let first = match pos >= FRAMES_BEFORE * stride {
+            true => pos - FRAMES_BEFORE * stride,
+            false => pos + len - FRAMES_BEFORE * stride,
+        };
+        let distance = -(FRAMES_BEFORE as f64) - remainder;
+
+        // sin(PI * cutoff * distance) is advanced tap by tap by rotation.
+        let (mut sin, mut cos) = (PI * self.cutoff * distance).sin_cos();
+        let (step_sin, step_cos) = (PI * self.cutoff).sin_cos();
+
+        let (mut sum, mut weights) = (0.0, 0.0);
+        for tap in 0..SINC_TAPS {
+            let d = distance + tap as f64;
+            let sinc = match d.abs() < 1e-9 {
+                true => self.cutoff,
+                false => sin / (PI * d),
+            };
+            let weight = sinc * self.window_value(d);
+            let index = first + tap * stride;
+            let index = if index >= len { index - len } else { index };
+            sum += weight * data[index].to_f64();
+            weights += weight;
+
+            let next_sin = sin * step_cos + cos * step_sin;
+            cos = cos * step_cos - sin * step_sin;
+            sin = next_sin;
+        }
+        (sum / weights) as f32
+    }
+}
+
+impl Sinc {
+    fn window_value(&self, d: f64) -> f64 {
+        let x = d.abs() * WINDOW_OVERSAMPLING as f64;
+        let index = x as usize;
+        if index + 1 >= self.window.len() {
+            return 0.0;
+        }
+        let remainder = x - index as f64;
+        self.window[index] + remainder * (self.window[index + 1] - self.window[index])
+    }
+}
+
+fn sinc_window() -> &'static [f64] {
+    static WINDOW: OnceLock<Vec<f64>> = OnceLock::new();
+    WINDOW.get_or_init(|| {
+        let half_width = FRAMES_AFTER as f64;
+        (0..=FRAMES_AFTER * WINDOW_OVERSAMPLING + 1)
+            .map(|i| resampling::kaiser(i as f64 / WINDOW_OVERSAMPLING as f64 / half_width, SINC_KAISER_BETA))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn padded_sine(frames: usize, freq: f64) -> Vec<f32> {
+        let mut data: Vec<f32> = (0..frames).map(|i| (2.0 * PI * freq * i as f64).sin() as f32).collect();
+        data.resize(frames + FRAMES_AFTER, 0.0);
+        data
+    }
+
+    fn max_error<I: Interpolator>(interpolator: &I, data: &[f32], freq: f64) -> f64 {
+        (200..800)
+            .flat_map(|pos| (0..4).map(move |i| (pos, i as f64 / 4.0)))
+            .map(|(pos, remainder)| {
+                let expected = (2.0 * PI * freq * (pos as f64 + remainder)).sin();
+                (interpolator.interpolate(data, pos, 1, remainder) as f64 - expected).abs()
+            })
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn from_sample_quality() {
+        assert_eq!(Interpolation::from_sample_quality(1), Interpolation::Linear);
+        assert_eq!(Interpolation::from_sample_quality(2), Interpolation::Cubic);
+        assert_eq!(Interpolation::from_sample_quality(3), Interpolation::Sinc);
+        assert_eq!(Interpolation::from_sample_quality(10), Interpolation::Sinc);
+    }
+
+    #[test]
+    fn test_linear_interpolation() {
+        let d = [0.0, 0.0,
+                 1.0, -1.0,
+                 3.0, -3.0,
+                 0.0, 0.0];
+
+        assert_eq!(Linear.interpolate(&d, 2, 2, 0.0), 1.0);
+        assert_eq!(Linear.interpolate(&d, 2, 2, 0.5), 2.0);
+        assert_eq!(Linear.interpolate(&d, 3, 2, 0.25), -1.5);
+        assert_eq!(Linear.interpolate(&d, 4, 2, 0.5), 1.5);
+    }
+
+    #[test]
+    fn test_cubic_interpolation() {
+        let d = [0.0, 0.0,
+                 1.0, -1.0,
+                 2.0, -2.0,
+                 3.0, -3.0,
+                 4.0, -4.0,
+                 0.0, 0.0];
+
+        assert_eq!(Cubic.interpolate(&d, 0, 2, 0.0), 0.0);
+        assert_eq!(Cubic.interpolate(&d, 2, 2, 0.0), 1.0);
+        assert_eq!(Cubic.interpolate(&d, 4, 2, 0.0), 2.0);
+        assert_eq!(Cubic.interpolate(&d, 6, 2, 0.0), 3.0);
+
+        assert_eq!(Cubic.interpolate(&d, 1, 2, 0.0), -0.0);
+        assert_eq!(Cubic.interpolate(&d, 3, 2, 0.0), -1.0);
+        assert_eq!(Cubic.interpolate(&d, 5, 2, 0.0), -2.0);
+        assert_eq!(Cubic.interpolate(&d, 7, 2, 0.0), -3.0);
+
+        assert_eq!(Cubic.interpolate(&d, 4, 2, 0.5), 2.5);
+        assert_eq!(Cubic.interpolate(&d, 5, 2, 0.5), -2.5);
+    }
+
+    #[test]
+    fn sinc_hits_sample_points() {
+        let data = padded_sine(100, 0.05);
+        let sinc = Sinc::new(1.0);
+        for pos in 0..100 {
+            assert!((sinc.interpolate(&data, pos, 1, 0.0) - data[pos]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn sinc_stereo_channels() {
+        let data: Vec<f32> = padded_sine(100, 0.05).iter().flat_map(|s| vec![*s, -*s]).collect();
+        let sinc = Sinc::new(1.0);
+        let left = sinc.interpolate(&data, 2 * 50, 2, 0.3);
+        let right = sinc.interpolate(&data, 2 * 50 + 1, 2, 0.3);
+        assert_eq!(left, -right);
+        assert!((left as f64 - (2.0 * PI * 0.05 * 50.3).sin()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn sinc_more_accurate_than_cubic_on_high_frequencies() {
+        let data = padded_sine(1000, 0.3);
+        let sinc_error = max_error(&Sinc::new(1.0), &data, 0.3);
+        let cubic_error = max_error(&Cubic, &data, 0.3);
+        let linear_error = max_error(&Linear, &data, 0.3);
+        assert!(sinc_error < 0.01, "sinc error {}", sinc_error);
+        assert!(sinc_error < cubic_error / 10.0, "sinc error {}, cubic error {}", sinc_error, cubic_error);
+        assert!(cubic_error < linear_error);
+    }
+
+    #[test]
+    fn sinc_suppresses_frequencies_above_nyquist_when_pitching_up() {
+        // At twice the speed, 0.35 cycles per frame of the sample would be above Nyquist.
+        let data = padded_sine(1000, 0.35);
+        let sinc = Sinc::new(2.0);
+        let peak = (200..800).map(|pos| sinc.interpolate(&data, pos, 1, 0.5).abs()).fold(0.0, f32::max);
+        assert!(peak < 0.05, "peak {}", peak);
+        let peak = (200..800).map(|pos| Cubic.interpolate(&data, pos, 1, 0.5).abs()).fold(0.0, f32::max);
+        assert!(peak > 0.5);
+    }
+}
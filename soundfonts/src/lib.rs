@@ -2,11 +2,36 @@ extern crate itertools;
 extern crate wmidi;
 extern crate log;
 extern crate rand;
-extern crate sndfile;
+extern crate rtrb;
+extern crate crossbeam_queue;
+extern crate rayon;
+#[cfg(feature = "libsndfile")]
+extern crate sndfile_sys;
+#[cfg(feature = "pure-rust")]
+extern crate hound;
+#[cfg(feature = "pure-rust")]
+extern crate claxon;
+#[cfg(feature = "pure-rust")]
+extern crate lewton;
+extern crate zip;
 
 pub mod sfz;
 pub mod engine;
 mod sample;
 mod envelopes;
+mod panning;
+mod generators;
+pub mod interpolation;
+mod decoding;
 mod errors;
+pub mod loading;
+pub mod resampling;
+pub mod source;
+mod storage;
+pub mod streaming;
 pub mod utils;
+pub mod voices;
+pub mod watching;
+
+#[cfg(test)]
+mod allocations;
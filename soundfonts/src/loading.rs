@@ -0,0 +1,179 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::interpolation;
+use crate::resampling;
+use crate::streaming;
+use crate::voices;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LoadProgress {
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+// Loads are numbered, cancelling a load cancels all loads with a lower or equal number that
+// share the token.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled_below: Arc<AtomicU64>,
+    generation: u64,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled_below.fetch_max(self.generation + 1, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled_below.load(Ordering::Relaxed) > self.generation
+    }
+
+    // Cancels the load using this token and makes it a token for the next load. Does not
+    // allocate, so a plugin can do it in its audio thread.
+    pub fn renew(&mut self) {
+        self.cancel();
+        self.generation += 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryBudget {
+    pub bytes: usize,
+    // Streams the files that allow it if the instrument would not fit into memory otherwise.
+    pub stream_if_exceeded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMemory {
+    pub path: PathBuf,
+    pub bytes: usize,
+    pub streamed: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryUsage {
+    // All sample data including generated waveforms, each shared sample counted once.
+    pub sample_bytes: usize,
+    pub stream_buffer_bytes: usize,
+    pub files: Vec<FileMemory>,
+}
+
+impl MemoryUsage {
+    pub fn total_bytes(&self) -> usize {
+        self.sample_bytes + self.stream_buffer_bytes
+    }
+}
+
+pub type ProgressCallback = Box<dyn Fn(LoadProgress) + Send + Sync>;
+
+#[derive(Default)]
+pub struct LoadOptions {
+    pub streaming: Option<streaming::StreamingConfig>,
+    // Resamples files that are not at the host sample rate while loading. Such files are always
+    // loaded into memory completely.
+    pub resampling: Option<resampling::ResampleQuality>,
+    // Used by all regions without a `sample_quality` opcode.
+    pub interpolation: interpolation::Interpolation,
+    // Keeps 16 and 24 bit samples at their bit depth in memory instead of converting them to float.
+    pub packed_samples: bool,
+    // Checks the memory the instrument needs against the budget before decoding any sample.
+    pub memory_budget: Option<MemoryBudget>,
+    // The most voices playing at once, `voices::DEFAULT_MAX_VOICES` if not given.
+    pub max_voices: Option<usize>,
+    pub voice_stealing: voices::VoiceStealing,
+    pub progress: Option<ProgressCallback>,
+    pub cancel: CancelToken,
+}
+
+// Counts finished files from the loader threads and reports them to the progress callback.
+pub(crate) struct ProgressTracker<'a> {
+    callback: Option<&'a ProgressCallback>,
+    files_total: usize,
+    bytes_total: u64,
+    files_done: AtomicUsize,
+    bytes_done: AtomicU64,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub(crate) fn new(callback: Option<&'a ProgressCallback>, files_total: usize, bytes_total: u64) -> Self {
+        ProgressTracker {
+            callback,
+            files_total,
+            bytes_total,
+            files_done: AtomicUsize::new(0),
+            bytes_done: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn file_done(&self, bytes: u64) {
+        let files_done = self.files_done.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes_done = self.bytes_done.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(callback) = self.callback {
+            callback(LoadProgress {
+                files_done,
+                files_total: self.files_total,
+                bytes_done,
+                bytes_total: self.bytes_total,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn cancel_token_is_shared() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        token.cancel();
+        assert!(clone.is_cancelled());
+    }
+
+    #[test]
+    fn renewed_cancel_token() {
+        let mut token = CancelToken::new();
+        let old = token.clone();
+        token.renew();
+        assert!(old.is_cancelled());
+        assert!(!token.is_cancelled());
+
+        let newer = token.clone();
+        token.renew();
+        assert!(newer.is_cancelled());
+        assert!(!token.is_cancelled());
+
+        newer.cancel();
+        assert!(!token.is_cancelled());
+        token.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn progress_tracker_reports_totals() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let callback: ProgressCallback = Box::new(move |p| sink.lock().unwrap().push(p));
+
+        let tracker = ProgressTracker::new(Some(&callback), 2, 300);
+        tracker.file_done(100);
+        tracker.file_done(200);
+
+        assert_eq!(*reports.lock().unwrap(), [
+            LoadProgress { files_done: 1, files_total: 2, bytes_done: 100, bytes_total: 300 },
+            LoadProgress { files_done: 2, files_total: 2, bytes_done: 300, bytes_total: 300 },
+        ]);
+    }
+}
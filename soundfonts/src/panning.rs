@@ -0,0 +1,87 @@
+use std::f32::consts::FRAC_PI_4;
+
+// How the two channels of a sample are mixed into the output, set up by a region's `pan`,
+// `width` and `position`. Mono samples play on both channels and are panned the same way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Panning {
+    left_to_left: f32,
+    right_to_left: f32,
+    left_to_right: f32,
+    right_to_right: f32,
+}
+
+impl Default for Panning {
+    fn default() -> Self {
+        Panning::new(0.0, 1.0, 0.0)
+    }
+}
+
+// Constant power pan law for `pan` from -1 (left) to 1 (right), normalized so that the center
+// leaves both channels at unity gain.
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan + 1.0) * FRAC_PI_4;
+    (angle.cos() / FRAC_PI_4.cos(), angle.sin() / FRAC_PI_4.sin())
+}
+
+impl Panning {
+    // All parameters range from -1 to 1. `width` narrows the stereo image down to mono at 0 and
+    // swaps the channels when negative, `position` then moves the image and `pan` moves it further.
+    // Both add up to a single balance, so the gains never exceed those of the pan law.
+    pub(crate) fn new(pan: f32, width: f32, position: f32) -> Panning {
+        let same = (1.0 + width) / 2.0;
+        let other = (1.0 - width) / 2.0;
+        let (left, right) = pan_gains((position + pan).clamp(-1.0, 1.0));
+
+        Panning {
+            left_to_left: left * same,
+            right_to_left: left * other,
+            left_to_right: right * other,
+            right_to_right: right * same,
+        }
+    }
+
+    pub(crate) fn apply(&self, left: f32, right: f32) -> (f32, f32) {
+        (self.left_to_left * left + self.right_to_left * right,
+         self.left_to_right * left + self.right_to_right * right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rounded((left, right): (f32, f32)) -> (f32, f32) {
+        ((left * 1000.0).round() / 1000.0, (right * 1000.0).round() / 1000.0)
+    }
+
+    #[test]
+    fn default_panning_is_transparent() {
+        assert_eq!(Panning::default().apply(0.3, -0.7), (0.3, -0.7));
+    }
+
+    #[test]
+    fn constant_power_pan() {
+        assert_eq!(rounded(Panning::new(-1.0, 1.0, 0.0).apply(1.0, 1.0)), (1.414, 0.0));
+        assert_eq!(rounded(Panning::new(1.0, 1.0, 0.0).apply(1.0, 1.0)), (0.0, 1.414));
+
+        let (left, right) = Panning::new(0.4, 1.0, 0.0).apply(1.0, 1.0);
+        assert!(((left * left + right * right) - 2.0).abs() < 1e-5);
+        assert!(left < right);
+    }
+
+    #[test]
+    fn width_and_position() {
+        assert_eq!(Panning::new(0.0, 0.0, 0.0).apply(1.0, 0.0), (0.5, 0.5));
+        assert_eq!(Panning::new(0.0, -1.0, 0.0).apply(1.0, 0.0), (0.0, 1.0));
+        assert_eq!(rounded(Panning::new(0.0, 0.0, -1.0).apply(1.0, 0.0)), (0.707, 0.0));
+        assert_eq!(rounded(Panning::new(0.0, 1.0, 1.0).apply(0.5, 0.5)), (0.0, 0.707));
+    }
+
+    #[test]
+    fn pan_and_position_add_up() {
+        assert_eq!(rounded(Panning::new(1.0, 1.0, 1.0).apply(1.0, 1.0)), (0.0, 1.414));
+        assert_eq!(rounded(Panning::new(-1.0, 1.0, -1.0).apply(1.0, 1.0)), (1.414, 0.0));
+        assert_eq!(rounded(Panning::new(1.0, 1.0, -1.0).apply(1.0, 1.0)), (1.0, 1.0));
+        assert_eq!(rounded(Panning::new(-0.5, 0.0, 0.5).apply(1.0, 0.0)), (0.5, 0.5));
+    }
+}
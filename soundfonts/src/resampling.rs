@@ -0,0 +1,188 @@
+use std::f64::consts::PI;
+
+// Kernel table entries per zero crossing, linearly interpolated in between.
+const TABLE_OVERSAMPLING: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResampleQuality {
+    Fast,
+    Good,
+    Best,
+}
+
+impl ResampleQuality {
+    // Zero crossings of the windowed sinc on each side, the Kaiser window's beta and the cutoff
+    // relative to the lower of the two Nyquist frequencies.
+    fn parameters(&self) -> (usize, f64, f64) {
+        match self {
+            ResampleQuality::Fast => (8, 6.0, 0.90),
+            ResampleQuality::Good => (16, 8.0, 0.94),
+            ResampleQuality::Best => (32, 10.0, 0.97),
+        }
+    }
+}
+
+struct Kernel {
+    table: Vec<f64>,
+}
+
+impl Kernel {
+    fn new(zero_crossings: usize, beta: f64) -> Kernel {
+        let length = zero_crossings * TABLE_OVERSAMPLING;
+        let table = (0..length + 2)
+            .map(|i| {
+                let x = i as f64 / TABLE_OVERSAMPLING as f64;
+                match i < length {
+                    true => sinc(x) * kaiser(x / zero_crossings as f64, beta),
+                    false => 0.0,
+                }
+            })
+            .collect();
+        Kernel { table }
+    }
+
+    // `x` is the distance from the kernel's center in zero crossings.
+    fn value(&self, x: f64) -> f64 {
+        let x = x.abs() * TABLE_OVERSAMPLING as f64;
+        let index = x as usize;
+        if index + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let remainder = x - index as f64;
+        self.table[index] + remainder * (self.table[index + 1] - self.table[index])
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    match x == 0.0 {
+        true => 1.0,
+        false => (PI * x).sin() / (PI * x),
+    }
+}
+
+pub(crate) fn kaiser(x: f64, beta: f64) -> f64 {
+    bessel_i0(beta * (1.0 - x * x).max(0.0).sqrt()) / bessel_i0(beta)
+}
+
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > 1e-12 * sum {
+        term *= (0.5 * x / k) * (0.5 * x / k);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+pub(crate) fn resampled_frames(frames: usize, from_samplerate: f64, to_samplerate: f64) -> usize {
+    (frames as f64 / (from_samplerate / to_samplerate)).round() as usize
+}
+
+// Converts interleaved `data` from `from_samplerate` to `to_samplerate` by band limited
+// interpolation with a Kaiser windowed sinc.
+pub(crate) fn resample(data: &[f32],
+                       channels: usize,
+                       from_samplerate: f64,
+                       to_samplerate: f64,
+                       quality: ResampleQuality) -> Vec<f32> {
+    if from_samplerate == to_samplerate {
+        return data.to_vec();
+    }
+    let (zero_crossings, beta, rolloff) = quality.parameters();
+    let kernel = Kernel::new(zero_crossings, beta);
+
+    let frames = data.len() / channels;
+    let step = from_samplerate / to_samplerate;
+    let cutoff = rolloff * step.recip().min(1.0);
+    let reach = (zero_crossings as f64 / cutoff).ceil() as isize;
+    let out_frames = resampled_frames(frames, from_samplerate, to_samplerate);
+
+    let mut out = vec![0.0; out_frames * channels];
+    let mut acc = vec![0.0; channels];
+    for (n, out_frame) in out.chunks_mut(channels).enumerate() {
+        let t = n as f64 * step;
+        let center = t.floor() as isize;
+        acc.iter_mut().for_each(|a| *a = 0.0);
+        let first = (center - reach + 1).max(0) as usize;
+        let last = ((center + reach) as usize).min(frames - 1);
+        for k in first..=last {
+            let weight = kernel.value((t - k as f64) * cutoff);
+            for (a, s) in Iterator::zip(acc.iter_mut(), data[k * channels..(k + 1) * channels].iter()) {
+                *a += weight * *s as f64;
+            }
+        }
+        for (o, a) in Iterator::zip(out_frame.iter_mut(), acc.iter()) {
+            *o = (a * cutoff) as f32;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frames: usize, samplerate: f64, freq: f64) -> Vec<f32> {
+        (0..frames).map(|i| (2.0 * PI * freq * i as f64 / samplerate).sin() as f32).collect()
+    }
+
+    fn rms(data: &[f32]) -> f64 {
+        (data.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / data.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn same_samplerate_is_identity() {
+        let data = sine(100, 48000.0, 1000.0);
+        assert_eq!(resample(&data, 1, 48000.0, 48000.0, ResampleQuality::Best), data);
+    }
+
+    #[test]
+    fn resampled_length() {
+        let data = vec![0.0; 2 * 48000];
+        assert_eq!(resample(&data, 2, 48000.0, 44100.0, ResampleQuality::Fast).len(), 2 * 44100);
+        assert_eq!(resample(&data, 2, 48000.0, 96000.0, ResampleQuality::Fast).len(), 2 * 96000);
+    }
+
+    #[test]
+    fn sine_is_preserved() {
+        for quality in &[ResampleQuality::Fast, ResampleQuality::Good, ResampleQuality::Best] {
+            let resampled = resample(&sine(9600, 96000.0, 1000.0), 1, 96000.0, 44100.0, *quality);
+            let expected = sine(4410, 44100.0, 1000.0);
+            let max_error = Iterator::zip(resampled[200..4200].iter(), expected[200..4200].iter())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            assert!(max_error < 1e-3, "{:?} max error {}", quality, max_error);
+        }
+    }
+
+    #[test]
+    fn upsampled_sine_is_preserved() {
+        let resampled = resample(&sine(4410, 44100.0, 5000.0), 1, 44100.0, 48000.0, ResampleQuality::Good);
+        let expected = sine(4800, 48000.0, 5000.0);
+        let max_error = Iterator::zip(resampled[200..4600].iter(), expected[200..4600].iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(max_error < 1e-3, "max error {}", max_error);
+    }
+
+    #[test]
+    fn frequencies_above_nyquist_are_removed() {
+        for quality in &[ResampleQuality::Fast, ResampleQuality::Good, ResampleQuality::Best] {
+            let resampled = resample(&sine(9600, 96000.0, 30000.0), 1, 96000.0, 44100.0, *quality);
+            assert!(rms(&resampled[200..4200]) < 0.01, "{:?} rms {}", quality, rms(&resampled[200..4200]));
+        }
+    }
+
+    #[test]
+    fn channels_are_independent() {
+        let left = sine(4800, 48000.0, 440.0);
+        let stereo: Vec<f32> = left.iter().flat_map(|s| vec![*s, 0.0]).collect();
+        let resampled = resample(&stereo, 2, 48000.0, 44100.0, ResampleQuality::Good);
+        let mono = resample(&left, 1, 48000.0, 44100.0, ResampleQuality::Good);
+
+        assert!(resampled.iter().skip(1).step_by(2).all(|s| *s == 0.0));
+        assert_eq!(resampled.iter().step_by(2).cloned().collect::<Vec<f32>>(), mono);
+    }
+}
@@ -1,69 +1,384 @@
+use std::sync::Arc;
+
 use wmidi;
 
 use super::envelopes;
+use super::panning;
+use super::interpolation::{self, Interpolation, Interpolator};
+use super::storage::{self, SampleFormat, Storage};
+use super::streaming;
+use super::voices::VoiceInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum LoopMode {
+    #[default]
+    NoLoop,
+    OneShot,
+    LoopContinuous,
+    LoopSustain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Direction {
+    #[default]
+    Forward,
+    Reverse,
+}
+
+// The interpolators read up to `FRAMES_AFTER` frames beyond the current position.
+pub(crate) const PADDING_FRAMES: usize = interpolation::FRAMES_AFTER;
+
+// The voices of a sample are allocated up front. If all of them are busy, a new note steals the
+// oldest one, which keeps one of the extra slots while it fades out.
+pub(crate) const MAX_VOICES: usize = 32;
+const VOICE_SLOTS: usize = 2 * MAX_VOICES;
+
+pub(crate) struct SampleData {
+    data: Storage,
+    frames: usize,
+    channels: usize,
+    samplerate: f64,
+
+    stream: Option<streaming::StreamSource>,
+    stream_from: usize,
+
+    // Frames of `data` per frame of the sample file, for data resampled at load time.
+    frame_scale: f64,
+
+    // Generated waveforms hold one table per octave of playback speed, see `tables`.
+    bands: usize,
+    // The first frame of the first band and the distance from one band to the next.
+    band_offset: usize,
+    band_stride: usize,
+}
+
+impl SampleData {
+    pub(crate) fn new(data: Vec<f32>, channels: usize, samplerate: f64) -> SampleData {
+        match channels {
+            1 | 2 => SampleData::from_storage(Storage::Float(data), channels, samplerate),
+            _ => SampleData::from_storage(Storage::Float(route_to_stereo(&data, channels)), 2, samplerate),
+        }
+    }
+
+    // Keeps the data of a file with `bits` per sample packed, unless it needs to be mixed down.
+    pub(crate) fn packed(data: Vec<f32>, channels: usize, samplerate: f64, bits: Option<u32>) -> SampleData {
+        match channels {
+            1 | 2 => SampleData::from_storage(Storage::pack(data, bits), channels, samplerate),
+            _ => SampleData::new(data, channels, samplerate),
+        }
+    }
+
+    fn from_storage(mut data: Storage, channels: usize, samplerate: f64) -> SampleData {
+        let frames = data.len() / channels;
+        data.resize((frames + PADDING_FRAMES) * channels);
+
+        SampleData {
+            data, frames, channels, samplerate,
+            stream: None, stream_from: usize::MAX,
+            frame_scale: 1.0,
+            bands: 1, band_offset: 0, band_stride: 0,
+        }
+    }
+
+    // Only the first frames are held in memory, the rest of the `frames` is read by the disk thread
+    // once a stream source is set.
+    pub(crate) fn streamed(head: Vec<f32>, channels: usize, samplerate: f64, frames: usize) -> SampleData {
+        let mut sample_data = SampleData::new(head, channels, samplerate);
+        sample_data.stream_from = sample_data.frames.saturating_sub(interpolation::FRAMES_AFTER);
+        sample_data.frames = frames;
+        sample_data
+    }
+
+    pub(crate) fn is_streamed(&self) -> bool {
+        self.stream_from != usize::MAX
+    }
+
+    // Marks the data as resampled from a file at `samplerate`, so that offsets and loop points
+    // given in frames of the file are scaled accordingly.
+    pub(crate) fn set_file_samplerate(&mut self, samplerate: f64) {
+        self.frame_scale = self.samplerate / samplerate;
+    }
+
+    // Mono single cycle `tables` of equal length, one for each of the `bands`. A voice plays the
+    // table `b` if it reads at most 2^b frames of data per output frame, the first one if it reads
+    // slower. Each table is framed by the frames of its other end that the interpolators read
+    // around the loop point.
+    pub(crate) fn tables(tables: Vec<f32>, bands: usize, samplerate: f64) -> SampleData {
+        let bands = bands.max(1);
+        let length = tables.len() / bands;
+        let guard = interpolation::FRAMES_BEFORE + interpolation::FRAMES_AFTER;
+        let mut data = Vec::with_capacity(bands * (length + guard));
+        let skip = length - interpolation::FRAMES_BEFORE % length.max(1);
+        for table in tables.chunks_exact(length.max(1)) {
+            data.extend(table.iter().cycle().skip(skip).take(length + guard));
+        }
+        let mut sample_data = SampleData::new(data, 1, samplerate);
+        sample_data.frames = length;
+        sample_data.bands = bands;
+        sample_data.band_offset = interpolation::FRAMES_BEFORE;
+        sample_data.band_stride = length + guard;
+        sample_data
+    }
+
+    fn band(&self, ratio: f64) -> usize {
+        match ratio {
+            r if r <= 1.0 => 0,
+            r => (r.log2().ceil() as usize).min(self.bands - 1),
+        }
+    }
+
+    pub(crate) fn set_stream_source(&mut self, source: streaming::StreamSource) {
+        self.stream = Some(source);
+    }
+
+    fn start_stream(&self) -> Option<streaming::Stream> {
+        self.stream.as_ref()
+            .and_then(|s| s.pool.start(s.file, self.channels, self.stream_from.saturating_sub(interpolation::FRAMES_BEFORE)))
+    }
+
+    fn release_stream(&self, stream: streaming::Stream) {
+        if let Some(s) = &self.stream {
+            s.pool.release(stream);
+        }
+    }
+
+    fn report_underrun(&self) {
+        if let Some(s) = &self.stream {
+            s.pool.report_underrun();
+        }
+    }
+
+    pub(crate) fn memory_bytes(&self) -> usize {
+        self.data.bytes()
+    }
+
+    pub(crate) fn samplerate(&self) -> f64 {
+        self.samplerate
+    }
+
+    // The frames of one band.
+    fn frames(&self) -> usize {
+        self.frames
+    }
+
+    fn band_start(&self, band: usize) -> usize {
+        self.band_offset + band * self.band_stride
+    }
+}
+
+// How much of `channel` of a file with `channels` goes to the left and right. Files in the
+// usual layouts (quad, 5.0, 5.1 and 7.1 in the WAV channel order) get a standard downmix with
+// the centre and surround channels at -3 dB and without the LFE. Otherwise even channels go
+// to the left, odd channels to the right.
+fn downmix_gains(channel: usize, channels: usize) -> (f32, f32) {
+    const HALF_POWER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+    match (channels, channel) {
+        (3, 2) | (5, 2) | (6, 2) | (8, 2) => (HALF_POWER, HALF_POWER),
+        (6, 3) | (8, 3) => (0.0, 0.0),
+        (4, 2) | (5, 3) | (6, 4) | (8, 4) | (8, 6) => (HALF_POWER, 0.0),
+        (4, 3) | (5, 4) | (6, 5) | (8, 5) | (8, 7) => (0.0, HALF_POWER),
+        (_, c) if c % 2 == 0 => (1.0, 0.0),
+        _ => (0.0, 1.0),
+    }
+}
+
+// Files with more than two channels are folded down to stereo, see `downmix_gains`. The gains
+// are normalized so that a signal on all channels keeps its level.
+pub(crate) fn route_to_stereo(data: &[f32], channels: usize) -> Vec<f32> {
+    let gains: Vec<(f32, f32)> = (0..channels).map(|c| downmix_gains(c, channels)).collect();
+    let (left_sum, right_sum) = gains.iter().fold((0.0, 0.0), |(l, r), (gl, gr)| (l + gl, r + gr));
+    let matrix: Vec<(f32, f32)> = gains.iter().map(|(gl, gr)| (gl / left_sum, gr / right_sum)).collect();
+
+    let mut stereo = Vec::with_capacity(data.len() / channels * 2);
+    for frame in data.chunks_exact(channels) {
+        let (left, right) = Iterator::zip(frame.iter(), matrix.iter())
+            .fold((0.0, 0.0), |(l, r), (s, (gl, gr))| (l + gl * s, r + gr * s));
+        stereo.push(left);
+        stereo.push(right);
+    }
+    stereo
+}
+
+// A linear fade to silence of a voice that was stolen or choked.
+#[derive(Clone, Copy, PartialEq)]
+struct Fadeout {
+    remaining: usize,
+    frames: usize,
+}
 
 struct Voice {
     position: f64,
+    // The first frame of the band of the sample data the voice plays.
+    band_start: usize,
     note: wmidi::Note,
+    // The velocity of the note on that started the voice.
+    velocity: u8,
     frequency: f64,
     gain: f32,
 
+    remaining_count: u32,
+
+    envelope: envelopes::ADSREnvelope,
     envelope_state: envelopes::State,
     last_envelope_gain: f32,
     release_start_gain: f32,
+
+    frames_played: usize,
+    fadeout: Option<Fadeout>,
+
+    stream: Option<streaming::Stream>,
 }
 
 impl Voice {
-    fn new(note: wmidi::Note, frequency: f64, gain: f32) -> Voice {
+    fn new(note: wmidi::Note,
+           frequency: f64,
+           gain: f32,
+           position: f64,
+           count: u32,
+           envelope: envelopes::ADSREnvelope,
+           stream: Option<streaming::Stream>) -> Voice {
         Voice {
             frequency: frequency,
             note: note,
+            velocity: 0,
             gain: gain,
-            position: 0.0,
+            position,
+            band_start: 0,
+
+            remaining_count: count,
 
-            envelope_state: envelopes::State::AttackDecay(0),
+            envelope,
+            envelope_state: envelope.start(),
             last_envelope_gain: 1.0,
             release_start_gain: 1.0,
+
+            frames_played: 0,
+            fadeout: None,
+
+            stream,
         }
     }
+
+    fn is_fading_out(&self) -> bool {
+        self.fadeout.is_some()
+    }
+
+    fn fade_out(&mut self, frames: usize) {
+        self.fadeout = Some(Fadeout { remaining: frames, frames });
+    }
 }
 
 pub struct Sample {
-    sample_data: Vec<f32>,
+    sample_data: Arc<SampleData>,
 
     voices: Vec<Voice>,
 
     real_sample_length: f64,
-    max_block_length: usize,
 
     native_frequency: f64,
 
+    loop_mode: LoopMode,
+    count: u32,
+    direction: Direction,
+    offset: f64,
+    loop_start: f64,
+    loop_end: f64,
+
+    interpolation: Interpolation,
+
     envelope: envelopes::ADSREnvelope,
+    declick_frames: usize,
+    velocity: u8,
+
+    panning: panning::Panning,
 }
 
 impl Sample {
-    pub fn new(
-        mut sample_data: Vec<f32>,
-        max_block_length: usize,
+    pub(crate) fn new(
+        sample_data: Arc<SampleData>,
         native_frequency: f64,
         envelope: envelopes::ADSREnvelope,
     ) -> Self {
-        let real_sample_length = sample_data.len();
-        let frames = real_sample_length / 2;
-
-        let reserve_frames = ((frames / max_block_length) + 2) * max_block_length;
-        sample_data.resize(reserve_frames * 2, 0.0);
+        let frames = sample_data.frames();
 
         Sample {
             sample_data: sample_data,
 
-            voices: Vec::new(),
+            voices: Vec::with_capacity(VOICE_SLOTS),
             real_sample_length: frames as f64,
-            max_block_length: max_block_length,
 
             native_frequency: native_frequency,
 
+            loop_mode: LoopMode::NoLoop,
+            count: 1,
+            direction: Direction::Forward,
+            offset: 0.0,
+            loop_start: 0.0,
+            loop_end: frames as f64,
+
+            interpolation: Interpolation::default(),
+
             envelope: envelope,
+            declick_frames: 0,
+            velocity: 127,
+
+            panning: panning::Panning::default(),
+        }
+    }
+
+    pub(crate) fn sample_data(&self) -> &Arc<SampleData> {
+        &self.sample_data
+    }
+
+    // The envelope of the voices started from now on, running voices keep theirs.
+    pub(crate) fn set_envelope(&mut self, envelope: envelopes::ADSREnvelope) {
+        self.envelope = envelope;
+    }
+
+    // The velocity of the note on starting the next voice.
+    pub(crate) fn set_velocity(&mut self, velocity: u8) {
+        self.velocity = velocity;
+    }
+
+    pub(crate) fn set_panning(&mut self, panning: panning::Panning) {
+        self.panning = panning;
+    }
+
+    pub fn set_loop_mode(&mut self, loop_mode: LoopMode) {
+        self.loop_mode = loop_mode;
+    }
+
+    pub fn set_count(&mut self, count: u32) {
+        self.count = count;
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        interpolation.prepare();
+        self.interpolation = interpolation;
+    }
+
+    pub fn set_declick_frames(&mut self, frames: usize) {
+        self.declick_frames = frames;
+    }
+
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+
+    pub fn set_offset(&mut self, offset: u32) {
+        self.offset = (offset as f64 * self.sample_data.frame_scale).round().min(self.real_sample_length);
+    }
+
+    pub fn set_loop_points(&mut self, start: u32, end: Option<u32>) {
+        let scale = self.sample_data.frame_scale;
+        self.loop_end = end.map_or(self.real_sample_length, |e| ((e as f64 + 1.0) * scale).round().min(self.real_sample_length));
+        self.loop_start = (start as f64 * scale).round().min(self.loop_end);
+    }
+
+    fn start_position(&self) -> f64 {
+        match self.direction {
+            Direction::Forward => self.offset,
+            Direction::Reverse => self.real_sample_length - 1.0 - self.offset,
         }
     }
 
@@ -72,73 +387,242 @@ impl Sample {
     }
 
     pub fn note_on(&mut self, note: wmidi::Note, frequency: f64, gain: f32) {
-        self.note_off(note);
-        self.voices.push(Voice::new(note, frequency, gain))
+        if self.active_voices().count() == MAX_VOICES {
+            let index = self.voices.iter().position(|v| !v.is_fading_out()).unwrap_or(0);
+            self.steal_voice(index);
+        }
+        if self.voices.len() == VOICE_SLOTS {
+            // Voices are stolen faster than they fade out, cut the one closest to silence.
+            let index = self.voices.iter()
+                .enumerate()
+                .filter_map(|(i, v)| v.fadeout.map(|f| (i, f.remaining)))
+                .min_by_key(|(_, remaining)| *remaining)
+                .map_or(0, |(i, _)| i);
+            if let Some(stream) = self.voices.remove(index).stream {
+                self.sample_data.release_stream(stream);
+            }
+        }
+        let position = self.start_position();
+        let stream = self.sample_data.start_stream();
+        let mut voice = Voice::new(note, frequency, gain, position, self.count, self.envelope, stream);
+        voice.band_start = self.sample_data.band_start(self.sample_data.band(frequency / self.native_frequency));
+        voice.velocity = self.velocity;
+        self.voices.push(voice)
     }
 
     pub fn note_off(&mut self, note: wmidi::Note) {
+        self.release_voices(note, u8::MAX);
+    }
+
+    // Releases the voices of `note` that were started with at most `max_velocity`.
+    pub fn release_voices(&mut self, note: wmidi::Note, max_velocity: u8) {
+        if self.loop_mode == LoopMode::OneShot {
+            return;
+        }
         for voice in &mut self.voices {
-            if voice.note == note && !voice.envelope_state.is_releasing() {
-                voice.envelope_state = envelopes::State::Release(0);
+            if voice.note == note && voice.velocity <= max_velocity && !voice.envelope_state.is_releasing() && !voice.is_fading_out() {
+                voice.envelope_state = voice.envelope.release();
                 voice.release_start_gain = voice.last_envelope_gain;
             }
         }
     }
 
     pub fn all_notes_off(&mut self) {
-        for voice in &mut self.voices {
-            voice.envelope_state = envelopes::State::Release(0);
+        for voice in self.voices.iter_mut().filter(|v| !v.is_fading_out()) {
+            voice.envelope_state = voice.envelope.release();
             voice.release_start_gain = voice.last_envelope_gain;
         }
     }
 
+    // Fades all voices out within `frames` instead of releasing them.
+    pub(crate) fn choke(&mut self, frames: usize) {
+        for voice in self.voices.iter_mut().filter(|v| !v.is_fading_out()) {
+            voice.fade_out(frames);
+        }
+    }
+
+    // The voices that count against voice limits, voices fading out do not.
+    pub(crate) fn active_voices(&self) -> impl Iterator<Item = (usize, VoiceInfo)> + '_ {
+        self.voices.iter()
+            .enumerate()
+            .filter(|(_, v)| !v.is_fading_out())
+            .map(|(i, v)| (i, VoiceInfo {
+                note: v.note,
+                frames_played: v.frames_played,
+                level: v.gain * v.last_envelope_gain * v.release_start_gain,
+            }))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn active_voice_count(&self) -> usize {
+        self.voices.iter().filter(|v| !v.is_fading_out()).count()
+    }
+
+    // Fades the voice out within the declick time.
+    pub(crate) fn steal_voice(&mut self, index: usize) {
+        self.voices[index].fade_out(self.declick_frames);
+    }
+
     pub fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+        match self.interpolation {
+            Interpolation::Linear => self.process_voices::<interpolation::Linear>(out_left, out_right),
+            Interpolation::Cubic => self.process_voices::<interpolation::Cubic>(out_left, out_right),
+            Interpolation::Sinc => self.process_voices::<interpolation::Sinc>(out_left, out_right),
+        }
+    }
+
+    fn process_voices<I: Interpolator>(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+        match self.sample_data.data {
+            Storage::Float(_) => self.process_stored::<I, f32>(out_left, out_right),
+            Storage::Int16(_) => self.process_stored::<I, i16>(out_left, out_right),
+            Storage::Int24(_) => self.process_stored::<I, storage::I24>(out_left, out_right),
+        }
+    }
+
+    fn process_stored<I: Interpolator, T: SampleFormat>(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+        let playback = Playback {
+            direction: self.direction,
+            loop_mode: self.loop_mode,
+            length: self.real_sample_length,
+            loop_start: self.loop_start,
+            loop_end: self.loop_end,
+        };
+
+        let sample_data = &self.sample_data;
+        let channels = sample_data.channels;
+        let head = T::slice(&sample_data.data);
+        let panning = self.panning;
+
         for voice in &mut self.voices {
-            let ratio = voice.frequency / self.native_frequency;
-            let needed_sample_length =
-                (voice.position + self.max_block_length as f64 * ratio).ceil() as usize + 5;
-            if needed_sample_length * 2 >= self.sample_data.len() {
-                self.sample_data.resize(needed_sample_length * 2, 0.0)
+            if matches!(voice.fadeout, Some(Fadeout { remaining: 0, .. })) {
+                continue;
             }
+            let ratio = voice.frequency / self.native_frequency;
+            let interpolator = I::new(ratio);
 
-            let (envelope, mut env_position) = self.envelope.active_envelope(voice.envelope_state);
+            let mut underrun = false;
             for (l, r) in Iterator::zip(out_left.iter_mut(), out_right.iter_mut()) {
+                if !playback.is_inside(voice.position) || !voice.envelope_state.is_active() {
+                    break;
+                }
+                let fadeout_gain = match &mut voice.fadeout {
+                    Some(Fadeout { remaining: 0, .. }) => break,
+                    Some(fadeout) => {
+                        fadeout.remaining -= 1;
+                        (fadeout.remaining + 1) as f32 / fadeout.frames as f32
+                    }
+                    None => 1.0,
+                };
                 let (remainder, sample_pos) = {
                     let sample_pos = voice.position.floor();
                     ((voice.position - sample_pos), sample_pos as usize)
                 };
-                let gain = voice.gain * envelope[env_position] * voice.release_start_gain;
-                *l += gain * cubic(&self.sample_data, 2 * sample_pos, remainder);
-                *r += gain * cubic(&self.sample_data, 2 * sample_pos + 1, remainder);
-                voice.position += ratio;
-                env_position += 1;
+                let frame = if sample_pos < sample_data.stream_from {
+                    Some(read_frame(&interpolator, head, channels * (voice.band_start + sample_pos), channels, remainder))
+                } else {
+                    match &mut voice.stream {
+                        Some(stream) => {
+                            let index = stream.fetch(sample_pos);
+                            let stream: &streaming::Stream = stream;
+                            index.map(|index| read_frame(&interpolator, stream.window(), index, channels, remainder))
+                        }
+                        None => {
+                            voice.position = playback.length;
+                            break;
+                        }
+                    }
+                };
+                let envelope_gain = voice.envelope.next(&mut voice.envelope_state);
+                match frame {
+                    Some((left, right)) => {
+                        let (left, right) = panning.apply(left, right);
+                        let gain = voice.gain * envelope_gain * voice.release_start_gain * fadeout_gain;
+                        *l += gain * left;
+                        *r += gain * right;
+                    }
+                    None => underrun = true,
+                }
+                playback.advance(voice, ratio);
+                voice.frames_played += 1;
             }
-            voice.last_envelope_gain = *envelope
-                .get(env_position)
-                .unwrap_or(&envelope[env_position - 1]);
-            self.envelope
-                .update_state(&mut voice.envelope_state, env_position);
-        }
-        let real_sample_length = self.real_sample_length;
-        self.voices.retain(|voice| {
-            voice.position < real_sample_length && voice.envelope_state.is_active()
-        });
+            if underrun {
+                sample_data.report_underrun();
+            }
+            voice.last_envelope_gain = voice.envelope.peek(voice.envelope_state);
+        }
+        let is_alive = |voice: &Voice| {
+            playback.is_inside(voice.position) && voice.envelope_state.is_active()
+                && !matches!(voice.fadeout, Some(Fadeout { remaining: 0, .. }))
+        };
+        for voice in self.voices.iter_mut().filter(|v| !is_alive(v)) {
+            if let Some(stream) = voice.stream.take() {
+                sample_data.release_stream(stream);
+            }
+        }
+        self.voices.retain(is_alive);
     }
 }
 
-fn cubic(sample_data: &[f32], pos: usize, remainder: f64) -> f32 {
-    let len = sample_data.len();
+fn read_frame<I: Interpolator, T: SampleFormat>(interpolator: &I,
+                                                data: &[T],
+                                                index: usize,
+                                                channels: usize,
+                                                remainder: f64) -> (f32, f32) {
+    let left = interpolator.interpolate(data, index, channels, remainder);
+    let right = match channels {
+        1 => left,
+        _ => interpolator.interpolate(data, index + 1, channels, remainder),
+    };
+    (left, right)
+}
+
+#[derive(Clone, Copy)]
+struct Playback {
+    direction: Direction,
+    loop_mode: LoopMode,
+    length: f64,
+    loop_start: f64,
+    loop_end: f64,
+}
 
-    let p0 = sample_data[((pos + len) - 2) % len] as f64;
-    let p1 = sample_data[pos] as f64;
-    let p2 = sample_data[pos + 2] as f64;
-    let p3 = sample_data[pos + 4] as f64;
+impl Playback {
+    fn is_inside(&self, position: f64) -> bool {
+        position >= 0.0 && position < self.length
+    }
+
+    fn is_looping(&self, voice: &Voice) -> bool {
+        match self.loop_mode {
+            LoopMode::LoopContinuous => true,
+            LoopMode::LoopSustain => !voice.envelope_state.is_releasing(),
+            _ => false,
+        }
+    }
 
-    let a = remainder;
-    let b = 1.0 - a;
-    let c = a * b;
+    fn advance(&self, voice: &mut Voice, ratio: f64) {
+        let in_loop = voice.position >= self.loop_start && voice.position < self.loop_end;
+        let loop_length = self.loop_end - self.loop_start;
 
-    ((1.0 + 1.5 * c) * (p1 * b + p2 * a) - 0.5 * c * (p0 * b + p1 + p2 + p3 * a)) as f32
+        match self.direction {
+            Direction::Forward => {
+                voice.position += ratio;
+                if in_loop && voice.position >= self.loop_end && self.is_looping(voice) {
+                    voice.position -= loop_length;
+                } else if voice.position >= self.length && voice.remaining_count > 1 {
+                    voice.position -= self.length;
+                    voice.remaining_count -= 1;
+                }
+            }
+            Direction::Reverse => {
+                voice.position -= ratio;
+                if in_loop && voice.position < self.loop_start && self.is_looping(voice) {
+                    voice.position += loop_length;
+                } else if voice.position < 0.0 && voice.remaining_count > 1 {
+                    voice.position += self.length;
+                    voice.remaining_count -= 1;
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -146,6 +630,7 @@ pub(crate) mod tests {
 
     use super::*;
 
+    use crate::streaming::tests as streaming_tests;
     use std::convert::TryFrom;
     use std::f32::consts::SQRT_2;
     use std::f64::consts::PI;
@@ -160,8 +645,12 @@ pub(crate) mod tests {
         }
     }
 
+    pub(crate) fn interpolation(sample: &Sample) -> Interpolation {
+        sample.interpolation
+    }
+
     pub fn is_playing_note(sample: &Sample, note: wmidi::Note) -> bool {
-        sample.voices.iter().any(|v| v.note == note && !v.envelope_state.is_releasing())
+        sample.voices.iter().any(|v| v.note == note && !v.envelope_state.is_releasing() && !v.is_fading_out())
     }
 
     pub fn is_releasing_note(sample: &Sample, note: wmidi::Note) -> bool {
@@ -178,10 +667,9 @@ pub(crate) mod tests {
     pub(crate) fn make_test_sample(nsamples: usize, samplerate: f64, freq: f64) -> Sample {
         let sample_data = make_test_sample_data(nsamples, samplerate, freq);
         Sample::new(
-            sample_data,
-            nsamples,
+            Arc::new(SampleData::new(sample_data, 2, 1.0)),
             freq,
-            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0, nsamples),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
         )
     }
 
@@ -277,12 +765,12 @@ pub(crate) mod tests {
                           1.0, 0.5];
 
         let sample = Sample::new(
-            sample,
-            16,
+            Arc::new(SampleData::new(sample, 2, 1.0)),
             440.0,
-            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0, 16),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
         );
-        assert_eq!(sample.sample_data.len(), 64);
+        assert_eq!(sample.sample_data.data.len(), (3 + PADDING_FRAMES) * 2);
+        assert_eq!(sample.real_sample_length, 3.0);
     }
 
     #[test]
@@ -309,6 +797,17 @@ pub(crate) mod tests {
         assert_frequency(sample, 48000.0, 415.30);
     }
 
+    #[test]
+    fn test_test_sample_half_tone_up_all_interpolations() {
+        for interpolation in &[Interpolation::Linear, Interpolation::Cubic, Interpolation::Sinc] {
+            let mut sample = make_test_sample(36000, 48000.0, wmidi::Note::A3.to_freq_f64());
+            sample.set_interpolation(*interpolation);
+            let note = wmidi::Note::ASharp3;
+            sample.note_on(note, note.to_freq_f64(), 1.0);
+            assert_frequency(sample, 48000.0, 466.16);
+        }
+    }
+
     #[test]
     fn test_pitch_up_at_start() {
         let mut sample = make_test_sample(36000, 48000.0, wmidi::Note::A3.to_freq_f64());
@@ -346,15 +845,13 @@ pub(crate) mod tests {
                           0.5, 1.0,
                           1.0, 0.5];
 
-        let max_block_length = 8;
         let note = wmidi::Note::C3;
         let frequency = note.to_freq_f64();
 
         let mut sample = Sample::new(
-            sample,
-            max_block_length,
+            Arc::new(SampleData::new(sample, 2, 1.0)),
             frequency,
-            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0, max_block_length),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
         );
 
         sample.note_on(note, frequency, 1.0);
@@ -384,6 +881,142 @@ pub(crate) mod tests {
         assert!(!sample.is_playing());
     }
 
+    #[test]
+    fn packed_playback_matches_float() {
+        for bits in &[16, 24] {
+            let scale = (1 << (bits - 1)) as f32;
+            let data: Vec<f32> = make_test_sample_data(300, 48000.0, 440.0).iter()
+                .map(|s| (s * 0.9 * scale).round() / scale)
+                .collect();
+            for interpolation in &[Interpolation::Linear, Interpolation::Cubic, Interpolation::Sinc] {
+                let mut outputs = vec![SampleData::new(data.clone(), 2, 1.0), SampleData::packed(data.clone(), 2, 1.0, Some(*bits))]
+                    .into_iter()
+                    .map(|sample_data| {
+                        let mut sample = Sample::new(Arc::new(sample_data),
+                                                     440.0,
+                                                     envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0));
+                        sample.set_interpolation(*interpolation);
+                        sample.note_on(wmidi::Note::A3, 440.0 * 1.3, 1.0);
+                        let (mut out_l, mut out_r) = (vec![0.0; 300], vec![0.0; 300]);
+                        sample.process(&mut out_l, &mut out_r);
+                        (out_l, out_r)
+                    });
+                let float = outputs.next().unwrap();
+                let packed = outputs.next().unwrap();
+                assert!(float.0.iter().any(|s| *s != 0.0));
+                assert_eq!(float, packed);
+            }
+        }
+    }
+
+    #[test]
+    fn packed_sample_data_size() {
+        let data = make_test_sample_data(100, 48000.0, 440.0);
+        assert!(matches!(SampleData::packed(data.clone(), 2, 1.0, Some(16)).data, Storage::Int16(_)));
+        assert!(matches!(SampleData::packed(data.clone(), 2, 1.0, Some(24)).data, Storage::Int24(_)));
+        assert!(matches!(SampleData::packed(data.clone(), 2, 1.0, None).data, Storage::Float(_)));
+
+        let quad = SampleData::packed(vec![0.5; 8], 4, 1.0, Some(16));
+        assert!(matches!(quad.data, Storage::Float(_)));
+        assert_eq!(quad.frames(), 2);
+    }
+
+    #[test]
+    fn mono_sample_process() {
+        let sample_data = vec![1.0, 0.5, 0.25];
+
+        let note = wmidi::Note::C3;
+        let frequency = note.to_freq_f64();
+
+        let mut sample = Sample::new(
+            Arc::new(SampleData::new(sample_data, 1, 1.0)),
+            frequency,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+
+        sample.note_on(note, frequency, 1.0);
+
+        let mut out_left: [f32; 4] = [0.0; 4];
+        let mut out_right: [f32; 4] = [0.0; 4];
+
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [1.0, 0.5, 0.25, 0.0]);
+        assert_eq!(out_right, [1.0, 0.5, 0.25, 0.0]);
+
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn multi_channel_sample_data() {
+        let quad = SampleData::new(vec![1.0, 2.0, 3.0, 4.0,
+                                        0.5, 0.5, 0.5, 0.5], 4, 1.0);
+        assert_eq!(quad.channels, 2);
+        assert_eq!(quad.frames(), 2);
+        let rounded = |data: &[f32]| data.iter().map(|v| (v * 1000.0).round() / 1000.0).collect::<Vec<f32>>();
+        assert_eq!(rounded(&f32::slice(&quad.data)[..4]), [1.828, 2.828,
+                                                           0.5, 0.5]);
+
+        let three = SampleData::new(vec![1.0, 2.0, 3.0], 3, 1.0);
+        assert_eq!(rounded(&f32::slice(&three.data)[..2]), [1.828, 2.414]);
+
+        let surround = SampleData::new(vec![1.0, 2.0, 4.0, 8.0, 1.0, 2.0,
+                                            1.0, 1.0, 1.0, 1.0, 1.0, 1.0], 6, 1.0);
+        assert_eq!(rounded(&f32::slice(&surround.data)[..4]), [1.879, 2.586,
+                                                               1.0, 1.0]);
+
+        let odd = SampleData::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], 7, 1.0);
+        assert_eq!(f32::slice(&odd.data)[..2], [4.0, 4.0]);
+
+        let mono = SampleData::new(vec![1.0, 2.0, 3.0], 1, 1.0);
+        assert_eq!(mono.channels, 1);
+        assert_eq!(mono.frames(), 3);
+    }
+
+    #[test]
+    fn tables_are_framed_by_their_other_end() {
+        let tables: Vec<f32> = (0..20).map(|i| i as f32).collect();
+        let data = SampleData::tables(tables, 2, 1.0);
+        let head = f32::slice(&data.data);
+        let band = |b: usize| &head[data.band_start(b) - interpolation::FRAMES_BEFORE..data.band_start(b) + 10 + interpolation::FRAMES_AFTER];
+        let expected = |first: usize| -> Vec<f32> {
+            (0..interpolation::FRAMES_BEFORE + 10 + interpolation::FRAMES_AFTER)
+                .map(|i| (first + (i + 10 - interpolation::FRAMES_BEFORE % 10) % 10) as f32)
+                .collect()
+        };
+        assert_eq!(band(0), &expected(0)[..]);
+        assert_eq!(band(1), &expected(10)[..]);
+    }
+
+    #[test]
+    fn bands_by_playback_speed() {
+        let data = SampleData::tables(vec![0.0; 40], 4, 1.0);
+        assert_eq!(data.frames(), 10);
+        assert_eq!(data.band(0.25), 0);
+        assert_eq!(data.band(1.0), 0);
+        assert_eq!(data.band(1.5), 1);
+        assert_eq!(data.band(4.0), 2);
+        assert_eq!(data.band(4.5), 3);
+        assert_eq!(data.band(100.0), 3);
+    }
+
+    #[test]
+    fn generated_table_loops_seamlessly() {
+        let (table, samplerate, bands) = crate::generators::Waveform::Sine.render(440.0, 48000.0);
+        let data = Arc::new(SampleData::tables(table, bands, samplerate));
+        for interpolation in &[Interpolation::Linear, Interpolation::Cubic, Interpolation::Sinc] {
+            let mut sample = Sample::new(data.clone(), 440.0, envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0));
+            sample.set_loop_mode(LoopMode::LoopContinuous);
+            sample.set_interpolation(*interpolation);
+            sample.note_on(wmidi::Note::A3, 440.0 * 0.25, 1.0);
+            let (mut out_l, mut out_r) = (vec![0.0; 9000], vec![0.0; 9000]);
+            sample.process(&mut out_l, &mut out_r);
+            for (i, out) in out_l.iter().enumerate() {
+                let expected = (2.0 * PI * 0.25 * i as f64 / 2048.0).sin();
+                assert!((*out as f64 - expected).abs() < 1e-5, "{:?} at frame {}: {} != {}", interpolation, i, out, expected);
+            }
+        }
+    }
+
     #[test]
     fn sample_two_notes_process() {
         let sample_data = vec![0.0,     2.0,
@@ -397,15 +1030,13 @@ pub(crate) mod tests {
                                0.0,     2.0
         ];
 
-        let max_block_length = 8;
         let note = wmidi::Note::C3;
         let frequency = note.to_freq_f64();
 
         let mut sample = Sample::new(
-            sample_data,
-            max_block_length,
+            Arc::new(SampleData::new(sample_data, 2, 1.0)),
             frequency,
-            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0, max_block_length),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
         );
 
         sample.note_on(note, frequency, 1.0);
@@ -447,8 +1078,6 @@ pub(crate) mod tests {
     fn make_envelope_test_sample(frequency: f64) -> Sample {
         let sample = vec![1.0; 96];
 
-        let max_block_length = 16;
-
         let mut eg = envelopes::Generator::default();
         eg.set_attack(2.0).unwrap();
         eg.set_hold(3.0).unwrap();
@@ -457,10 +1086,9 @@ pub(crate) mod tests {
         eg.set_release(5.0).unwrap();
 
         Sample::new(
-            sample,
-            max_block_length,
+            Arc::new(SampleData::new(sample, 2, 1.0)),
             frequency,
-            envelopes::ADSREnvelope::new(&eg, 1.0, max_block_length),
+            envelopes::ADSREnvelope::new(&eg, 1.0),
         )
     }
 
@@ -695,6 +1323,7 @@ pub(crate) mod tests {
 
         let note = wmidi::Note::C3;
         let frequency = note.to_freq_f64();
+        sample.note_off(note);
         sample.note_on(note, frequency, 1.0);
 
         let mut out_left = [0.0; 8];
@@ -725,6 +1354,7 @@ pub(crate) mod tests {
         assert!(is_playing_note(&sample, note));
         assert!(!is_releasing_note(&sample, note));
 
+        sample.note_off(note);
         sample.note_on(note, frequency, 1.0);
         assert!(sample.voices[0].envelope_state.is_releasing());
         assert!(
@@ -749,10 +1379,9 @@ pub(crate) mod tests {
         let sample_dat = vec![1.0; 1 << 24];
         let eg = envelopes::Generator::default();
         let mut sample = Sample::new(
-            sample_dat,
-            4,
+            Arc::new(SampleData::new(sample_dat, 2, 1.0)),
             1.0,
-            envelopes::ADSREnvelope::new(&eg, 1.0, 4),
+            envelopes::ADSREnvelope::new(&eg, 1.0),
         );
 
         for n in 0u8..127u8 {
@@ -772,25 +1401,477 @@ pub(crate) mod tests {
     }
 
     #[test]
-    fn test_cubic_interpolation() {
-        let d = [0.0, 0.0,
-                 1.0, -1.0,
-                 2.0, -2.0,
-                 3.0, -3.0,
-                 4.0, -4.0,
-                 0.0, 0.0];
+    fn one_shot_ignores_note_off() {
+        let note = wmidi::Note::C3;
+        let frequency = note.to_freq_f64();
+        let mut sample = make_envelope_test_sample(frequency);
+        sample.set_loop_mode(LoopMode::OneShot);
 
-        assert_eq!(cubic(&d, 0, 0.0), 0.0);
-        assert_eq!(cubic(&d, 2, 0.0), 1.0);
-        assert_eq!(cubic(&d, 4, 0.0), 2.0);
-        assert_eq!(cubic(&d, 6, 0.0), 3.0);
+        sample.note_on(note, frequency, 1.0);
 
-        assert_eq!(cubic(&d, 1, 0.0), -0.0);
-        assert_eq!(cubic(&d, 3, 0.0), -1.0);
-        assert_eq!(cubic(&d, 5, 0.0), -2.0);
-        assert_eq!(cubic(&d, 7, 0.0), -3.0);
+        let mut out_left = [0.0; 12];
+        let mut out_right = [0.0; 12];
+        sample.process(&mut out_left, &mut out_right);
+
+        sample.note_off(note);
+        assert!(is_playing_note(&sample, note));
+        assert!(!is_releasing_note(&sample, note));
+
+        let mut out_left = [0.0; 12];
+        let mut out_right = [0.0; 12];
+        sample.process(&mut out_left, &mut out_right);
+
+        let out: Vec<f32> = out_left.iter().map(|v| (v*100.0).round()/100.0).collect();
+        assert_eq!(out.as_slice(), [0.6; 12]);
+
+        sample.all_notes_off();
+        assert!(!is_playing_note(&sample, note));
+        assert!(is_releasing_note(&sample, note));
+    }
+
+    #[test]
+    fn one_shot_plays_until_sample_end() {
+        let sample_data = vec![1.0, 0.5,
+                               0.5, 1.0,
+                               1.0, 0.5];
+
+        let note = wmidi::Note::C3;
+        let frequency = note.to_freq_f64();
+
+        let mut sample = Sample::new(
+            Arc::new(SampleData::new(sample_data, 2, 1.0)),
+            frequency,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        sample.set_loop_mode(LoopMode::OneShot);
+
+        sample.note_on(note, frequency, 1.0);
+        sample.note_off(note);
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        sample.process(&mut out_left, &mut out_right);
+
+        assert_eq!(out_left, [1.0, 0.5, 1.0, 0.0]);
+        assert_eq!(out_right, [0.5, 1.0, 0.5, 0.0]);
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn count_repeats_sample() {
+        let sample_data = vec![1.0, 0.5,
+                               0.5, 1.0,
+                               1.0, 0.5];
+
+        let note = wmidi::Note::C3;
+        let frequency = note.to_freq_f64();
+
+        let mut sample = Sample::new(
+            Arc::new(SampleData::new(sample_data, 2, 1.0)),
+            frequency,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        sample.set_loop_mode(LoopMode::OneShot);
+        sample.set_count(2);
+
+        sample.note_on(note, frequency, 1.0);
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        sample.process(&mut out_left, &mut out_right);
+
+        assert_eq!(out_left, [1.0, 0.5, 1.0, 1.0]);
+        assert_eq!(out_right, [0.5, 1.0, 0.5, 0.5]);
+        assert!(sample.is_playing());
+
+        let mut out_left = [0.0; 4];
+        let mut out_right = [0.0; 4];
+        sample.process(&mut out_left, &mut out_right);
+
+        assert_eq!(out_left, [0.5, 1.0, 0.0, 0.0]);
+        assert_eq!(out_right, [1.0, 0.5, 0.0, 0.0]);
+        assert!(!sample.is_playing());
+    }
+
+    fn make_ramp_test_sample(frames: usize) -> Sample {
+        let sample_data = (0..frames)
+            .flat_map(|i| vec![(i + 1) as f32, -((i + 1) as f32)])
+            .collect();
+
+        Sample::new(
+            Arc::new(SampleData::new(sample_data, 2, 1.0)),
+            wmidi::Note::C3.to_freq_f64(),
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        )
+    }
+
+    fn pull_ramp_test_sample(sample: &mut Sample) -> ([f32; 8], [f32; 8]) {
+        let mut out_left = [0.0; 8];
+        let mut out_right = [0.0; 8];
+        sample.process(&mut out_left, &mut out_right);
+        (out_left, out_right)
+    }
+
+    #[test]
+    fn loop_continuous_forward() {
+        let note = wmidi::Note::C3;
+        let mut sample = make_ramp_test_sample(4);
+        sample.set_loop_mode(LoopMode::LoopContinuous);
+        sample.set_loop_points(1, Some(2));
+
+        sample.note_on(note, note.to_freq_f64(), 1.0);
+
+        let (out_left, out_right) = pull_ramp_test_sample(&mut sample);
+        assert_eq!(out_left, [1.0, 2.0, 3.0, 2.0, 3.0, 2.0, 3.0, 2.0]);
+        assert_eq!(out_right, [-1.0, -2.0, -3.0, -2.0, -3.0, -2.0, -3.0, -2.0]);
+        assert!(sample.is_playing());
+    }
+
+    #[test]
+    fn loop_sustain_plays_out_after_note_off() {
+        let note = wmidi::Note::C3;
+        let mut sample = make_ramp_test_sample(4);
+        let mut eg = envelopes::Generator::default();
+        eg.set_release(5.0).unwrap();
+        sample.set_envelope(envelopes::ADSREnvelope::new(&eg, 1.0));
+        sample.set_loop_mode(LoopMode::LoopSustain);
+        sample.set_loop_points(1, Some(2));
+
+        sample.note_on(note, note.to_freq_f64(), 1.0);
+
+        let (out_left, _) = pull_ramp_test_sample(&mut sample);
+        assert_eq!(out_left, [1.0, 2.0, 3.0, 2.0, 3.0, 2.0, 3.0, 2.0]);
+        assert_eq!(sample.voices[0].position, 2.0);
+
+        sample.note_off(note);
+
+        let mut out_left = [0.0; 1];
+        let mut out_right = [0.0; 1];
+        sample.process(&mut out_left, &mut out_right);
+        assert_eq!(sample.voices[0].position, 3.0);
+
+        sample.process(&mut out_left, &mut out_right);
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn reverse_sample_process() {
+        let note = wmidi::Note::C3;
+        let mut sample = make_ramp_test_sample(4);
+        sample.set_direction(Direction::Reverse);
+
+        sample.note_on(note, note.to_freq_f64(), 1.0);
+
+        let (out_left, out_right) = pull_ramp_test_sample(&mut sample);
+        assert_eq!(out_left, [4.0, 3.0, 2.0, 1.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(out_right, [-4.0, -3.0, -2.0, -1.0, 0.0, 0.0, 0.0, 0.0]);
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn reverse_sample_offset() {
+        let note = wmidi::Note::C3;
+        let mut sample = make_ramp_test_sample(4);
+        sample.set_direction(Direction::Reverse);
+        sample.set_offset(1);
+
+        sample.note_on(note, note.to_freq_f64(), 1.0);
+
+        let (out_left, _) = pull_ramp_test_sample(&mut sample);
+        assert_eq!(out_left, [3.0, 2.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn forward_sample_offset() {
+        let note = wmidi::Note::C3;
+        let mut sample = make_ramp_test_sample(4);
+        sample.set_offset(2);
+
+        sample.note_on(note, note.to_freq_f64(), 1.0);
+
+        let (out_left, _) = pull_ramp_test_sample(&mut sample);
+        assert_eq!(out_left, [3.0, 4.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn resampled_offset_and_loop_points() {
+        let mut sample_data = SampleData::new(vec![0.0; 2 * 200], 2, 96000.0);
+        sample_data.set_file_samplerate(48000.0);
+        let mut sample = Sample::new(Arc::new(sample_data),
+                                     440.0,
+                                     envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0));
+        sample.set_offset(10);
+        sample.set_loop_points(20, Some(49));
+
+        assert_eq!(sample.offset, 20.0);
+        assert_eq!(sample.loop_start, 40.0);
+        assert_eq!(sample.loop_end, 100.0);
+    }
+
+    #[test]
+    fn reverse_loop_continuous() {
+        let note = wmidi::Note::C3;
+        let mut sample = make_ramp_test_sample(4);
+        sample.set_direction(Direction::Reverse);
+        sample.set_loop_mode(LoopMode::LoopContinuous);
+        sample.set_loop_points(1, Some(2));
+
+        sample.note_on(note, note.to_freq_f64(), 1.0);
+
+        let (out_left, out_right) = pull_ramp_test_sample(&mut sample);
+        assert_eq!(out_left, [4.0, 3.0, 2.0, 3.0, 2.0, 3.0, 2.0, 3.0]);
+        assert_eq!(out_right, [-4.0, -3.0, -2.0, -3.0, -2.0, -3.0, -2.0, -3.0]);
+        assert!(sample.is_playing());
+    }
+
+    #[test]
+    fn reverse_count() {
+        let note = wmidi::Note::C3;
+        let mut sample = make_ramp_test_sample(4);
+        sample.set_direction(Direction::Reverse);
+        sample.set_loop_mode(LoopMode::OneShot);
+        sample.set_count(2);
+
+        sample.note_on(note, note.to_freq_f64(), 1.0);
+
+        let (out_left, _) = pull_ramp_test_sample(&mut sample);
+        assert_eq!(out_left, [4.0, 3.0, 2.0, 1.0, 4.0, 3.0, 2.0, 1.0]);
+        assert!(!sample.is_playing());
+    }
+
+    fn make_streamed_test_sample(data: &[f32],
+                                 config: streaming::StreamingConfig,
+                                 open: streaming::ReaderOpener) -> (Sample, streaming::StreamHandle) {
+        let frames = data.len() / 2;
+        let mut streamer = streaming::DiskStreamer::new(config);
+        let source = streamer.register_file(streaming::StreamFile {
+            path: std::path::PathBuf::from("test.wav"),
+            channels: 2,
+        });
+        let head = data[..streamer.preload_frames() * 2].to_vec();
+        let mut sample_data = SampleData::streamed(head, 2, 1.0, frames);
+        sample_data.set_stream_source(source);
+        let sample = Sample::new(
+            Arc::new(sample_data),
+            440.0,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        (sample, streamer.spawn(open))
+    }
+
+    fn streaming_test_config(max_streams: usize) -> streaming::StreamingConfig {
+        streaming::StreamingConfig { preload_frames: 32, buffer_frames: 512, max_streams }
+    }
+
+    #[test]
+    fn streamed_playback_matches_preloaded() {
+        let data = make_test_sample_data(300, 48000.0, 440.0);
+        let mut preloaded = Sample::new(
+            Arc::new(SampleData::new(data.clone(), 2, 1.0)),
+            440.0,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        let (mut streamed, handle) = make_streamed_test_sample(&data,
+                                                               streaming_test_config(2),
+                                                               streaming_tests::memory_opener(data.clone()));
+
+        let note = wmidi::Note::A3;
+        preloaded.note_on(note, 440.0 * 1.3, 1.0);
+        streamed.note_on(note, 440.0 * 1.3, 1.0);
+
+        let stream = streamed.voices[0].stream.as_ref().unwrap();
+        streaming_tests::wait_for(|| stream.buffered_frames() == 300 - 17 + PADDING_FRAMES);
+
+        let (mut expected_l, mut expected_r) = (vec![0.0; 300], vec![0.0; 300]);
+        let (mut out_l, mut out_r) = (vec![0.0; 300], vec![0.0; 300]);
+        preloaded.process(&mut expected_l, &mut expected_r);
+        streamed.process(&mut out_l, &mut out_r);
+
+        assert_eq!(out_l, expected_l);
+        assert_eq!(out_r, expected_r);
+        assert!(out_l[100..200].iter().any(|s| *s != 0.0));
+        assert!(!streamed.is_playing());
+        assert_eq!(handle.stats(), streaming::StreamStats::default());
+    }
+
+    #[test]
+    fn streamed_underrun() {
+        let data = make_test_sample_data(300, 48000.0, 440.0);
+        let (mut sample, handle) = make_streamed_test_sample(&data,
+                                                             streaming_test_config(2),
+                                                             Box::new(|_: &streaming::StreamFile, _| None));
+
+        sample.note_on(wmidi::Note::A3, 440.0, 1.0);
+
+        let (mut out_l, mut out_r) = (vec![0.0; 300], vec![0.0; 300]);
+        sample.process(&mut out_l, &mut out_r);
+
+        assert_eq!(out_l[..24], data.iter().step_by(2).take(24).cloned().collect::<Vec<f32>>()[..]);
+        assert!(out_l[24..].iter().all(|s| *s == 0.0));
+        assert_eq!(handle.stats(), streaming::StreamStats { underruns: 1, missed_streams: 0 });
+    }
+
+    #[test]
+    fn voice_pool_is_bounded() {
+        let mut sample = Sample::new(
+            Arc::new(SampleData::new(make_test_sample_data(100, 48000.0, 440.0), 2, 1.0)),
+            440.0,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        sample.set_declick_frames(100);
+        for note in 0..VOICE_SLOTS as u8 + 4 {
+            sample.note_on(wmidi::Note::try_from(note).unwrap(), 440.0, 1.0);
+        }
+        assert_eq!(sample.voices.len(), VOICE_SLOTS);
+        assert_eq!(sample.voices.capacity(), VOICE_SLOTS);
+        assert_eq!(sample.active_voice_count(), MAX_VOICES);
+        let first_playing = VOICE_SLOTS as u8 + 4 - MAX_VOICES as u8;
+        assert!(!is_playing_note(&sample, wmidi::Note::try_from(first_playing - 1).unwrap()));
+        assert!(is_playing_note(&sample, wmidi::Note::try_from(first_playing).unwrap()));
+        assert!(is_playing_note(&sample, wmidi::Note::try_from(VOICE_SLOTS as u8 + 3).unwrap()));
+    }
+
+    #[test]
+    fn stolen_voice_fades_out() {
+        let mut sample = Sample::new(
+            Arc::new(SampleData::new(vec![1.0; 200], 2, 1.0)),
+            440.0,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        sample.set_declick_frames(4);
+        sample.note_on(wmidi::Note::A3, 440.0, 1.0);
+        sample.note_on(wmidi::Note::C3, 440.0, 0.5);
+        let (mut out_l, mut out_r) = ([0.0; 2], [0.0; 2]);
+        sample.process(&mut out_l, &mut out_r);
+        assert_eq!(sample.active_voices().map(|(_, v)| v.frames_played).collect::<Vec<_>>(), [2, 2]);
+
+        sample.steal_voice(0);
+        assert_eq!(sample.active_voice_count(), 1);
+        assert_eq!(sample.active_voices().next().map(|(i, v)| (i, v.note, v.level)), Some((1, wmidi::Note::C3, 0.5)));
+        sample.note_off(wmidi::Note::A3);
+        sample.all_notes_off();
+        assert!(!sample.voices[0].envelope_state.is_releasing());
+
+        sample.voices.truncate(1);
+        let (mut out_l, mut out_r) = ([0.0; 6], [0.0; 6]);
+        sample.process(&mut out_l, &mut out_r);
+        assert_eq!(out_l, [1.0, 0.75, 0.5, 0.25, 0.0, 0.0]);
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn release_voices_up_to_velocity() {
+        let mut sample = make_test_sample(100, 48000.0, 440.0);
+        sample.set_velocity(100);
+        sample.note_on(wmidi::Note::C3, 440.0, 0.4);
+        sample.set_velocity(50);
+        sample.note_on(wmidi::Note::C3, 440.0, 0.8);
+        sample.note_on(wmidi::Note::D3, 440.0, 0.2);
+        assert!(!is_releasing_note(&sample, wmidi::Note::C3));
+
+        sample.release_voices(wmidi::Note::C3, 60);
+        assert!(sample.voices[1].envelope_state.is_releasing());
+        assert!(!sample.voices[0].envelope_state.is_releasing());
+        assert!(!sample.voices[2].envelope_state.is_releasing());
+    }
+
+    #[test]
+    fn choked_voices_fade_out() {
+        let mut sample = Sample::new(
+            Arc::new(SampleData::new(vec![1.0; 200], 2, 1.0)),
+            440.0,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        sample.note_on(wmidi::Note::A3, 440.0, 1.0);
+        sample.note_on(wmidi::Note::C3, 440.0, 0.5);
+        sample.choke(2);
+        assert_eq!(sample.active_voice_count(), 0);
+
+        let (mut out_l, mut out_r) = ([0.0; 4], [0.0; 4]);
+        sample.process(&mut out_l, &mut out_r);
+        assert_eq!(out_l, [1.5, 0.75, 0.0, 0.0]);
+        assert!(!sample.is_playing());
+
+        sample.note_on(wmidi::Note::A3, 440.0, 1.0);
+        sample.choke(0);
+        sample.process(&mut out_l, &mut out_r);
+        assert!(!sample.is_playing());
+    }
+
+    #[test]
+    fn full_pool_fades_out_oldest_voice() {
+        let mut sample = Sample::new(
+            Arc::new(SampleData::new(vec![1.0; 200], 2, 1.0)),
+            440.0,
+            envelopes::ADSREnvelope::new(&envelopes::Generator::default(), 1.0),
+        );
+        sample.set_declick_frames(4);
+        let gain = 1.0 / MAX_VOICES as f32;
+        for note in 0..MAX_VOICES as u8 {
+            sample.note_on(wmidi::Note::try_from(note).unwrap(), 440.0, gain);
+        }
+        let (mut out_l, mut out_r) = ([0.0; 2], [0.0; 2]);
+        sample.process(&mut out_l, &mut out_r);
+
+        sample.note_on(wmidi::Note::try_from(MAX_VOICES as u8).unwrap(), 440.0, 0.0);
+        assert_eq!(sample.active_voice_count(), MAX_VOICES);
+        assert!(sample.voices[0].is_fading_out());
+        let (mut out_l, mut out_r) = ([0.0; 6], [0.0; 6]);
+        sample.process(&mut out_l, &mut out_r);
+        let expected = [1.0, 1.0 - gain / 4.0, 1.0 - gain / 2.0, 1.0 - 3.0 * gain / 4.0, 1.0 - gain, 1.0 - gain];
+        for (out, expected) in Iterator::zip(out_l.iter(), expected.iter()) {
+            assert!((out - expected).abs() < 1e-6, "{:?}", out_l);
+        }
+        assert_eq!(sample.voices.len(), MAX_VOICES);
+    }
+
+    #[test]
+    fn full_slots_cut_voice_closest_to_silence() {
+        let mut sample = make_test_sample(100, 48000.0, 440.0);
+        sample.set_declick_frames(100);
+        for note in 0..VOICE_SLOTS as u8 {
+            sample.note_on(wmidi::Note::try_from(note).unwrap(), 440.0, 1.0);
+        }
+        sample.voices[5].fadeout = Some(Fadeout { remaining: 1, frames: 100 });
+        sample.note_on(wmidi::Note::try_from(VOICE_SLOTS as u8).unwrap(), 440.0, 1.0);
+        assert_eq!(sample.voices.len(), VOICE_SLOTS);
+        assert!(sample.voices.iter().any(|v| v.note == wmidi::Note::try_from(0).unwrap()));
+        assert!(!sample.voices.iter().any(|v| v.note == wmidi::Note::try_from(5).unwrap()));
+    }
+
+    #[test]
+    fn streamed_playback_does_not_allocate() {
+        let data = make_test_sample_data(300, 48000.0, 440.0);
+        let (mut sample, _handle) = make_streamed_test_sample(&data,
+                                                              streaming_test_config(2),
+                                                              streaming_tests::memory_opener(data.clone()));
+        let (mut out_l, mut out_r) = (vec![0.0; 64], vec![0.0; 64]);
+        let allocations = crate::allocations::count_allocations(|| {
+            for note in 0..MAX_VOICES as u8 + 4 {
+                sample.note_on(wmidi::Note::try_from(note).unwrap(), 440.0, 1.0);
+                sample.process(&mut out_l, &mut out_r);
+            }
+            sample.all_notes_off();
+            sample.process(&mut out_l, &mut out_r);
+        });
+        assert_eq!(allocations, 0);
+    }
+
+    #[test]
+    fn streamed_no_free_stream() {
+        let data = make_test_sample_data(300, 48000.0, 440.0);
+        let (mut sample, handle) = make_streamed_test_sample(&data,
+                                                             streaming_test_config(1),
+                                                             streaming_tests::memory_opener(data.clone()));
+
+        sample.note_on(wmidi::Note::A3, 440.0, 1.0);
+        sample.note_on(wmidi::Note::A4, 440.0, 1.0);
+        assert_eq!(handle.stats(), streaming::StreamStats { underruns: 0, missed_streams: 1 });
 
-        assert_eq!(cubic(&d, 4, 0.5), 2.5);
-        assert_eq!(cubic(&d, 5, 0.5), -2.5);
+        let (mut out_l, mut out_r) = (vec![0.0; 100], vec![0.0; 100]);
+        sample.process(&mut out_l, &mut out_r);
+        assert!(!is_playing_note(&sample, wmidi::Note::A4));
     }
 }
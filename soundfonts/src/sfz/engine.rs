@@ -3,17 +3,29 @@ use std::convert::TryFrom;
 use std::error;
 use std::fmt;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use log::warn;
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
 
 use crate::engine;
 use crate::envelopes;
 use crate::errors::*;
+use crate::decoding;
+use crate::generators;
+use crate::interpolation;
+use crate::loading;
+use crate::panning;
+use crate::resampling;
 use crate::sample;
-use crate::sndfile;
-use crate::sndfile::SndFileIO;
+use crate::source;
+use crate::storage;
+use crate::streaming;
 use crate::utils;
+use crate::voices;
 
 use super::parser;
 
@@ -214,6 +226,15 @@ impl Default for Trigger {
     }
 }
 
+// How the voices of a region end when another region of its `off_by` group is played.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(super) enum OffMode {
+    #[default]
+    Fast,
+    Normal,
+    Time,
+}
+
 #[derive(Clone)]
 pub struct RegionData {
     pub(super) key_range: NoteRange,
@@ -226,9 +247,15 @@ pub struct RegionData {
     pitch_keytrack: f64,
 
     amp_veltrack: f32,
+    amp_keytrack: f32,
+    amp_keycenter: wmidi::Note,
 
     volume: f32,
 
+    pan: f32,
+    width: f32,
+    position: f32,
+
     sample: String,
     rt_decay: f32,
 
@@ -236,9 +263,28 @@ pub struct RegionData {
 
     trigger: Trigger,
 
+    loop_mode: sample::LoopMode,
+    count: u32,
+    // Whether the opcodes were given, a `count` without `loop_mode` makes the region one shot.
+    loop_mode_given: bool,
+    count_given: bool,
+    direction: sample::Direction,
+    offset: u32,
+    loop_start: u32,
+    loop_end: Option<u32>,
+
     group: u32,
     off_by: u32,
 
+    polyphony: Option<usize>,
+    note_polyphony: Option<usize>,
+    note_selfmask: bool,
+
+    off_mode: OffMode,
+    off_time: f32,
+
+    sample_quality: Option<interpolation::Interpolation>,
+
     on_ccs: HashMap<u8, ControlValRange>,
 
     pub(super) random_range: RandomRange,
@@ -255,18 +301,43 @@ impl Default for RegionData {
             pitch_keytrack: 1.0,
 
             amp_veltrack: 1.0,
+            amp_keytrack: 0.0,
+            amp_keycenter: wmidi::Note::C3,
 
             ampeg: Default::default(),
 
             volume: Default::default(),
+
+            pan: 0.0,
+            width: 1.0,
+            position: 0.0,
+
             sample: Default::default(),
             rt_decay: Default::default(),
             tune: Default::default(),
             trigger: Default::default(),
 
+            loop_mode: Default::default(),
+            count: 1,
+            loop_mode_given: false,
+            count_given: false,
+            direction: Default::default(),
+            offset: Default::default(),
+            loop_start: Default::default(),
+            loop_end: None,
+
             group: Default::default(),
             off_by: Default::default(),
 
+            polyphony: None,
+            note_polyphony: None,
+            note_selfmask: true,
+
+            off_mode: Default::default(),
+            off_time: 0.006,
+
+            sample_quality: None,
+
             on_ccs: HashMap::new(),
 
             random_range: Default::default(),
@@ -280,6 +351,17 @@ impl RegionData {
         Ok(())
     }
 
+    pub(super) fn set_amp_keytrack(&mut self, v: f32) -> Result<(), RangeError> {
+        self.amp_keytrack = range_check(v, -96.0, 12.0, "amp_keytrack")?;
+        Ok(())
+    }
+
+    pub(super) fn set_amp_keycenter(&mut self, v: i32) -> Result<(), RangeError> {
+        let v = range_check(v, 0, 127, "amp_keycenter")? as u8;
+        self.amp_keycenter = unsafe { wmidi::Note::from_u8_unchecked(v) };
+        Ok(())
+    }
+
     pub(super) fn set_pitch_keycenter(&mut self, v: u32) -> Result<(), RangeError> {
         let v = range_check(v, 0, 127, "pich_keycenter")? as u8;
         self.pitch_keycenter = unsafe { wmidi::Note::from_u8_unchecked(v as u8) };
@@ -300,6 +382,12 @@ impl RegionData {
         Ok(())
     }
 
+    pub(super) fn set_sample_quality(&mut self, v: u32) -> Result<(), RangeError> {
+        let v = range_check(v, 0, 10, "sample_quality")?;
+        self.sample_quality = Some(interpolation::Interpolation::from_sample_quality(v));
+        Ok(())
+    }
+
     pub(super) fn set_tune(&mut self, v: i32) -> Result<(), RangeError> {
         self.tune = range_check(v, -100, 100, "tune")? as f64 / 100.0;
         Ok(())
@@ -314,6 +402,50 @@ impl RegionData {
         self.trigger = t;
     }
 
+    pub(super) fn set_loop_mode(&mut self, m: sample::LoopMode) {
+        self.loop_mode = m;
+        self.loop_mode_given = true;
+    }
+
+    pub(super) fn set_count(&mut self, v: u32) -> Result<(), RangeError> {
+        self.count = range_check(v, 1, u32::MAX, "count")?;
+        self.count_given = true;
+        Ok(())
+    }
+
+    // Called once all opcodes of the region are taken.
+    pub(super) fn resolve_loop_mode(&mut self) {
+        if self.count_given && !self.loop_mode_given {
+            self.loop_mode = sample::LoopMode::OneShot;
+        }
+    }
+
+    pub(super) fn set_direction(&mut self, d: sample::Direction) {
+        self.direction = d;
+    }
+
+    pub(super) fn set_offset(&mut self, v: u32) {
+        self.offset = v;
+    }
+
+    pub(super) fn set_loop_start(&mut self, v: u32) -> Result<(), RangeError> {
+        if let Some(end) = self.loop_end {
+            if v > end {
+                return Err(RangeError::flipped_range("loop_start", v, end));
+            }
+        }
+        self.loop_start = v;
+        Ok(())
+    }
+
+    pub(super) fn set_loop_end(&mut self, v: u32) -> Result<(), RangeError> {
+        if v < self.loop_start {
+            return Err(RangeError::flipped_range("loop_end", v, self.loop_start));
+        }
+        self.loop_end = Some(v);
+        Ok(())
+    }
+
     pub(super) fn set_group(&mut self, v: u32) {
         self.group = v;
     }
@@ -322,6 +454,61 @@ impl RegionData {
         self.off_by = v;
     }
 
+    pub(super) fn set_polyphony(&mut self, v: u32) -> Result<(), RangeError> {
+        self.polyphony = Some(range_check(v, 1, u16::MAX as u32, "polyphony")? as usize);
+        Ok(())
+    }
+
+    pub(super) fn set_note_polyphony(&mut self, v: u32) -> Result<(), RangeError> {
+        self.note_polyphony = Some(range_check(v, 1, u16::MAX as u32, "note_polyphony")? as usize);
+        Ok(())
+    }
+
+    pub(super) fn set_note_selfmask(&mut self, v: bool) {
+        self.note_selfmask = v;
+    }
+
+    pub(super) fn set_off_mode(&mut self, m: OffMode) {
+        self.off_mode = m;
+    }
+
+    pub(super) fn set_pan(&mut self, v: f32) -> Result<(), RangeError> {
+        self.pan = range_check(v, -100.0, 100.0, "pan")? / 100.0;
+        Ok(())
+    }
+
+    pub(super) fn set_width(&mut self, v: f32) -> Result<(), RangeError> {
+        self.width = range_check(v, -100.0, 100.0, "width")? / 100.0;
+        Ok(())
+    }
+
+    pub(super) fn set_position(&mut self, v: f32) -> Result<(), RangeError> {
+        self.position = range_check(v, -100.0, 100.0, "position")? / 100.0;
+        Ok(())
+    }
+
+    pub(super) fn set_off_time(&mut self, v: f32) -> Result<(), RangeError> {
+        self.off_time = range_check(v, 0.0, 100.0, "off_time")?;
+        Ok(())
+    }
+
+    fn is_streamable(&self) -> bool {
+        matches!(self.loop_mode, sample::LoopMode::NoLoop | sample::LoopMode::OneShot)
+            && self.direction == sample::Direction::Forward && self.count == 1 && self.offset == 0
+    }
+
+    fn note_frequency(&self, note: wmidi::Note) -> f64 {
+        let native_freq = self.pitch_keycenter.to_freq_f64();
+        let key_pitchshift = (note.to_freq_f64() / native_freq).powf(self.pitch_keytrack);
+        let tune_pitchshift = 2.0f64.powf(1.0 / 12.0 * self.tune);
+        native_freq * key_pitchshift * tune_pitchshift
+    }
+
+    // The gain in dB `amp_keytrack` gives to `note`.
+    fn amp_keytrack_db(&self, note: wmidi::Note) -> f32 {
+        self.amp_keytrack * (u8::from(note) as f32 - u8::from(self.amp_keycenter) as f32)
+    }
+
     pub(super) fn push_on_lo_cc(&mut self, channel: u32, v: i32) -> Result<(), RangeError> {
         let channel = channel as u8;
         match self.on_ccs.get_mut(&channel) {
@@ -349,6 +536,32 @@ impl RegionData {
     }
 }
 
+// A set of MIDI notes that never allocates.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct NoteSet([u64; 2]);
+
+impl NoteSet {
+    fn insert(&mut self, note: u8) {
+        self.0[(note >> 6) as usize & 1] |= 1 << (note & 63);
+    }
+
+    fn remove(&mut self, note: u8) {
+        self.0[(note >> 6) as usize & 1] &= !(1 << (note & 63));
+    }
+
+    fn contains(&self, note: u8) -> bool {
+        self.0[(note >> 6) as usize & 1] & (1 << (note & 63)) != 0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0 == [0, 0]
+    }
+
+    fn notes(self) -> impl Iterator<Item = wmidi::Note> {
+        (0..128u8).filter(move |n| self.contains(*n)).filter_map(|n| wmidi::Note::try_from(n).ok())
+    }
+}
+
 pub(super) struct Region {
     params: RegionData,
 
@@ -359,52 +572,62 @@ pub(super) struct Region {
     host_samplerate: f64,
 
     last_note_on: Option<(wmidi::Note, wmidi::Velocity)>,
-    notes_for_release_trigger: HashSet<wmidi::Note>,
+    notes_for_release_trigger: NoteSet,
 
-    other_notes_on: HashSet<u8>,
+    other_notes_on: NoteSet,
     time_since_note_on: f64,
 
     sustain_pedal_pushed: bool,
+    controllers: [u8; 128],
 
     once_immune_against_group_events: bool,
 }
 
 impl Region {
     fn new(params: RegionData,
-           sample_data: Vec<f32>,
-           host_samplerate: f64,
-           sample_samplerate: f64,
-           max_block_length: usize) -> Region {
-
-        let amp_envelope = envelopes::ADSREnvelope::new(&params.ampeg,
-                                                        host_samplerate as f32,
-                                                        max_block_length);
-        let freq_shift = host_samplerate / sample_samplerate;
-        let sample = sample::Sample::new(sample_data,
-                                         max_block_length,
-                                         params.pitch_keycenter.to_freq_f64() * freq_shift,
-                                         amp_envelope);
+           sample_data: Arc<sample::SampleData>,
+           host_samplerate: f64) -> Region {
+
+        let amp_envelope = envelopes::ADSREnvelope::new(&params.ampeg, host_samplerate as f32);
+        let freq_shift = host_samplerate / sample_data.samplerate();
+        let mut sample = sample::Sample::new(sample_data,
+                                             params.pitch_keycenter.to_freq_f64() * freq_shift,
+                                             amp_envelope);
+        sample.set_loop_mode(params.loop_mode);
+        sample.set_count(params.count);
+        sample.set_direction(params.direction);
+        sample.set_offset(params.offset);
+        sample.set_loop_points(params.loop_start, params.loop_end);
+        sample.set_interpolation(params.sample_quality.unwrap_or_default());
+        sample.set_declick_frames((voices::DECLICK_SECONDS * host_samplerate).round() as usize);
+        sample.set_panning(panning::Panning::new(params.pan, params.width, params.position));
 
         Region {
-            params: params,
+            params,
 
-            sample: sample,
+            sample,
 
             gain: 1.0,
 
-            host_samplerate: host_samplerate,
+            host_samplerate,
 
             last_note_on: None,
-            notes_for_release_trigger: HashSet::new(),
-            other_notes_on: HashSet::new(),
+            notes_for_release_trigger: NoteSet::default(),
+            other_notes_on: NoteSet::default(),
             time_since_note_on: 0.0,
 
             sustain_pedal_pushed: false,
+            controllers: [0; 128],
 
             once_immune_against_group_events: false,
         }
     }
 
+    // Regions with a `sample_quality` keep their own interpolation.
+    fn set_interpolation(&mut self, interpolation: interpolation::Interpolation) {
+        self.sample.set_interpolation(self.params.sample_quality.unwrap_or(interpolation));
+    }
+
     fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
         self.time_since_note_on += out_left.len() as f64 / self.host_samplerate;
 
@@ -437,15 +660,24 @@ impl Region {
         };
 
         self.gain = utils::dB_to_gain(
-            self.params.volume + velocity_db * self.params.amp_veltrack.abs() + rt_decay,
+            self.params.volume + velocity_db * self.params.amp_veltrack.abs() + rt_decay
+                + self.params.amp_keytrack_db(note),
         );
 
-        let native_freq = self.params.pitch_keycenter.to_freq_f64();
-        let key_pitchshift = (note.to_freq_f64() / native_freq).powf(self.params.pitch_keytrack);
-        let tune_pitchshift = 2.0f64.powf(1.0 / 12.0 * self.params.tune);
-        let current_note_frequency = native_freq * key_pitchshift * tune_pitchshift;
+        let current_note_frequency = self.params.note_frequency(note);
+        self.sample.set_envelope(envelopes::ADSREnvelope::for_note(&self.params.ampeg,
+                                                                   self.host_samplerate as f32,
+                                                                   velocity,
+                                                                   &self.controllers));
 
         self.time_since_note_on = 0.0;
+        // With `note_selfmask` a softer note does not release a voice of the same note started
+        // with a higher velocity.
+        match self.params.note_selfmask {
+            true => self.sample.release_voices(note, velocity),
+            false => self.sample.note_off(note),
+        }
+        self.sample.set_velocity(velocity);
         self.sample.note_on(note, current_note_frequency, self.gain);
     }
 
@@ -461,10 +693,9 @@ impl Region {
                 Trigger::Release => self.last_note_on
                     .map_or((), |(note, vel)| self.note_on(note, vel)),
                 _ => {
-                    for note in self.notes_for_release_trigger.clone() {
+                    for note in std::mem::take(&mut self.notes_for_release_trigger).notes() {
                         self.note_off(note);
                     }
-                    self.notes_for_release_trigger.clear();
                 }
             }
         }
@@ -498,13 +729,13 @@ impl Region {
             _ => {}
         }
         self.note_on(note, velocity);
-        self.notes_for_release_trigger.remove(&note);
+        self.notes_for_release_trigger.remove(u8::from(note));
         true
     }
 
     fn handle_note_off(&mut self, note: wmidi::Note) -> bool {
         if !self.params.key_range.covering(note) {
-            self.other_notes_on.remove(&u8::from(note));
+            self.other_notes_on.remove(u8::from(note));
             return false;
         }
         match self.params.trigger {
@@ -519,7 +750,7 @@ impl Region {
                 if !self.sustain_pedal_pushed {
                     self.note_off(note);
                 } else {
-                    self.notes_for_release_trigger.insert(note);
+                    self.notes_for_release_trigger.insert(u8::from(note));
                 }
                 false
             }
@@ -530,6 +761,7 @@ impl Region {
                             control_number: wmidi::ControlNumber,
                             control_value: wmidi::ControlValue) -> bool {
         let (cnum, cval) = (u8::from(control_number), u8::from(control_value));
+        self.controllers[cnum as usize] = cval;
 
         match cnum {
             64 => self.sustain_pedal(cval >= 64),
@@ -573,7 +805,12 @@ impl Region {
             return;
         }
         if group == self.params.group || group == self.params.off_by {
-            self.sample.all_notes_off();
+            let off_time = match self.params.off_mode {
+                OffMode::Fast => voices::DECLICK_SECONDS,
+                OffMode::Normal => return self.sample.all_notes_off(),
+                OffMode::Time => self.params.off_time as f64,
+            };
+            self.sample.choke((off_time * self.host_samplerate).round() as usize);
         }
     }
 
@@ -585,19 +822,23 @@ impl Region {
 #[derive(Debug)]
 pub enum EngineError {
     ParserError(parser::ParserError),
-    SndFileError(sndfile::SndFileError),
     IOError(io::Error),
-    UnspecifiedSndFileError(String),
+    DecodeError(PathBuf),
+    SampleNotFound(PathBuf),
+    Cancelled,
+    MemoryBudgetExceeded(usize, usize),
 }
 
 impl fmt::Display for EngineError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &*self {
             EngineError::ParserError(pe) => std::fmt::Display::fmt(&pe, f),
-            EngineError::SndFileError(sfe) => fmt::Debug::fmt(&sfe, f),
             EngineError::IOError(ioe) => fmt::Display::fmt(&ioe, f),
-            EngineError::UnspecifiedSndFileError(sf) => {
-                write!(f, "Unspecified error from sndfile while reading {}", sf)
+            EngineError::DecodeError(path) => write!(f, "Could not decode sample file {}", path.display()),
+            EngineError::SampleNotFound(path) => write!(f, "Sample file not found: {}", path.display()),
+            EngineError::Cancelled => write!(f, "Loading cancelled"),
+            EngineError::MemoryBudgetExceeded(needed, budget) => {
+                write!(f, "Instrument needs {} bytes of memory, the budget is {} bytes", needed, budget)
             }
         }
     }
@@ -607,58 +848,439 @@ impl error::Error for EngineError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             EngineError::ParserError(ref e) => Some(e),
-            EngineError::SndFileError(_) => None, // SndFileError should implement std::errer::Error
             EngineError::IOError(ref e) => Some(e),
             _ => None,
         }
     }
 }
 
+struct SampleFile {
+    path: PathBuf,
+    streamable: bool,
+}
+
+// The distinct sample files of an instrument in the order of their first use. A file is only
+// streamed if all the regions playing it can be streamed.
+#[derive(Default)]
+struct SampleFiles {
+    files: Vec<SampleFile>,
+    indices: HashMap<PathBuf, usize>,
+}
+
+impl SampleFiles {
+    fn add(&mut self, path: PathBuf, streamable: bool) -> usize {
+        match self.indices.get(&path) {
+            Some(&index) => {
+                self.files[index].streamable &= streamable;
+                index
+            }
+            None => {
+                self.indices.insert(path.clone(), self.files.len());
+                self.files.push(SampleFile { path, streamable });
+                self.files.len() - 1
+            }
+        }
+    }
+}
+
+// Which voices count against a voice limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VoiceScope {
+    // The `polyphony` of a group, or of a region without a group.
+    Polyphony(usize),
+    // The `note_polyphony` of a group for the note played.
+    NotePolyphony(usize),
+    Engine,
+}
+
+// Counts the active voices against the voice limits. The counts are taken once after a MIDI
+// event started voices and are kept up to date while voices are stolen.
+#[derive(Default)]
+struct VoiceCounter {
+    // The polyphony scope and the group of each region, numbered from 0.
+    scopes: Vec<usize>,
+    groups: Vec<usize>,
+    scope_voices: Vec<usize>,
+    note_voices: Vec<usize>,
+    total: usize,
+}
+
+impl VoiceCounter {
+    fn new(regions: &[Region]) -> VoiceCounter {
+        let mut scope_indices = HashMap::new();
+        let mut group_indices = HashMap::new();
+        let mut counter = VoiceCounter::default();
+        for (r, region) in regions.iter().enumerate() {
+            let group = region.params.group;
+            let scope = match group {
+                0 => (0, r),
+                _ => (group, 0),
+            };
+            let next = scope_indices.len();
+            counter.scopes.push(*scope_indices.entry(scope).or_insert(next));
+            let next = group_indices.len();
+            counter.groups.push(*group_indices.entry(group).or_insert(next));
+        }
+        counter.scope_voices = vec![0; scope_indices.len()];
+        counter.note_voices = vec![0; group_indices.len()];
+        counter
+    }
+
+    fn count(&mut self, regions: &[Region], note: Option<wmidi::Note>) {
+        self.scope_voices.iter_mut().for_each(|n| *n = 0);
+        self.note_voices.iter_mut().for_each(|n| *n = 0);
+        self.total = 0;
+        for (r, region) in regions.iter().enumerate() {
+            for (_, info) in region.sample.active_voices() {
+                self.scope_voices[self.scopes[r]] += 1;
+                if Some(info.note) == note {
+                    self.note_voices[self.groups[r]] += 1;
+                }
+                self.total += 1;
+            }
+        }
+    }
+
+    fn voices(&self, scope: VoiceScope) -> usize {
+        match scope {
+            VoiceScope::Polyphony(scope) => self.scope_voices[scope],
+            VoiceScope::NotePolyphony(group) => self.note_voices[group],
+            VoiceScope::Engine => self.total,
+        }
+    }
+
+    // Fades out the voice `stealing` picks among the active voices in `scope`. Returns false if
+    // there is no such voice.
+    fn steal(&mut self,
+             regions: &mut [Region],
+             stealing: voices::VoiceStealing,
+             note: Option<wmidi::Note>,
+             scope: VoiceScope) -> bool {
+        let (scopes, groups) = (&self.scopes, &self.groups);
+        let in_scope = |r: usize, info: &voices::VoiceInfo| match scope {
+            VoiceScope::Polyphony(scope) => scopes[r] == scope,
+            VoiceScope::NotePolyphony(group) => groups[r] == group && Some(info.note) == note,
+            VoiceScope::Engine => true,
+        };
+        let victim = regions.iter()
+            .enumerate()
+            .flat_map(|(r, region)| region.sample.active_voices().map(move |(v, info)| (r, v, info)))
+            .filter(|(r, _, info)| in_scope(*r, info))
+            .min_by(|a, b| stealing.compare(&a.2, &b.2, note));
+        match victim {
+            Some((r, v, info)) => {
+                regions[r].sample.steal_voice(v);
+                self.scope_voices[self.scopes[r]] -= 1;
+                if Some(info.note) == note {
+                    self.note_voices[self.groups[r]] -= 1;
+                }
+                self.total -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 pub struct Engine {
     pub(super) regions: Vec<Region>,
+    streams: Option<streaming::StreamHandle>,
+
+    files: Vec<(PathBuf, Arc<sample::SampleData>)>,
+    stream_buffer_bytes: usize,
+    sfz_file: Option<PathBuf>,
+
+    max_voices: usize,
+    voice_stealing: voices::VoiceStealing,
+    voice_counter: VoiceCounter,
+
+    events: engine::EventQueue,
+
+    // Used by `midi_event`, which must not allocate.
+    rng: StdRng,
+    activated_groups: Vec<u32>,
+    triggered_regions: Vec<usize>,
 }
 
 impl Engine {
-    pub fn new(sfz_file: String, host_samplerate: f64, max_block_length: usize) -> Result<Engine, EngineError> {
+    pub fn new(sfz_file: String, host_samplerate: f64) -> Result<Engine, EngineError> {
+        Self::load(sfz_file, host_samplerate, loading::LoadOptions::default())
+    }
+
+    pub fn new_streaming(sfz_file: String,
+                         host_samplerate: f64,
+                         config: streaming::StreamingConfig) -> Result<Engine, EngineError> {
+        let options = loading::LoadOptions { streaming: Some(config), ..Default::default() };
+        Self::load(sfz_file, host_samplerate, options)
+    }
+
+    pub fn with_options(sfz_file: String,
+                        host_samplerate: f64,
+                        options: loading::LoadOptions) -> Result<Engine, EngineError> {
+        Self::load(sfz_file, host_samplerate, options)
+    }
+
+    fn load(sfz_file: String,
+            host_samplerate: f64,
+            options: loading::LoadOptions) -> Result<Engine, EngineError> {
         let mut fh = std::fs::File::open(&sfz_file).map_err(|e| EngineError::IOError(e))?;
         let mut sfz_text = String::new();
         io::Read::read_to_string(&mut fh, &mut sfz_text)
             .map_err(|e| EngineError::IOError(e))?;
 
+        let sample_path = Path::new(&sfz_file).parent().unwrap();
+        let source = Arc::new(source::FileSystemSource::new(sample_path));
+        let mut engine = Self::from_sfz_text(sfz_text, source, host_samplerate, options)?;
+        engine.sfz_file = Some(PathBuf::from(sfz_file));
+        Ok(engine)
+    }
+
+    pub fn from_sfz_text(sfz_text: String,
+                         source: Arc<dyn source::SampleSource>,
+                         host_samplerate: f64,
+                         options: loading::LoadOptions) -> Result<Engine, EngineError> {
         let region_data = parser::parse_sfz_text(sfz_text)
             .map_err(|pe| EngineError::ParserError(pe))?;
 
-        let sample_path = Path::new(&sfz_file).parent().unwrap();
+        let mut sample_files = SampleFiles::default();
+        let file_indices = region_data.iter()
+            .map(|rd| match generators::Waveform::from_sample_name(&rd.sample) {
+                Some(_) => Ok(None),
+                None => {
+                    let path = source.resolve(&rd.sample)
+                        .map_err(EngineError::SampleNotFound)?;
+                    let streamable = rd.is_streamable() && source.seekable(&path);
+                    Ok(Some(sample_files.add(path, streamable)))
+                }
+            })
+            .collect::<Result<Vec<Option<usize>>, EngineError>>()?;
+
+        let streaming_config = match options.memory_budget {
+            Some(budget) => {
+                let infos = sample_files.files.par_iter()
+                    .map(|file| SampleInfo::probe(&*source, &file.path))
+                    .collect::<Result<Vec<SampleInfo>, EngineError>>()?;
+                Self::check_memory_budget(budget, &sample_files.files, &infos, host_samplerate, &options)?
+            }
+            None => options.streaming,
+        };
 
-        let regions: Result<Vec<(RegionData, Vec<f32>, f64)>, _> = region_data.iter()
-            .map( |rd| {
-                let sample_file = rd.sample.replace("\\", &std::path::MAIN_SEPARATOR.to_string());
-                println!("{}", sample_file);
-                let mut snd = sndfile::OpenOptions::ReadOnly(sndfile::ReadOptions::Auto)
-                    .from_path(sample_path.join(&sample_file))
-                    .map_err(|sfe| EngineError::SndFileError(sfe))?;
-                let sample = snd.read_all_to_vec()
-                    .map_err(|_| EngineError::UnspecifiedSndFileError(sample_file))?;
-                let sample_samplerate = snd.get_samplerate() as f64;
-                if host_samplerate != sample_samplerate {
-                    warn!("Sample rate of file {} differs from host sample rate. Reccomend resampling or using other host sample rate", rd.sample);
+        let mut disk_streamer = streaming_config.map(streaming::DiskStreamer::new);
+        let preload_frames = disk_streamer.as_ref().map(|ds| ds.preload_frames());
+        let decoded = Self::load_sample_files(&sample_files.files, &*source, &options, |file| {
+            decode_sample(&*source, &file.path, host_samplerate, &options, preload_frames.filter(|_| file.streamable))
+        })?;
+
+        let samples: Vec<Arc<sample::SampleData>> = decoded.into_iter().zip(sample_files.files.iter())
+            .map(|((mut sample, file_channels), file)| {
+                if let (true, Some(disk_streamer)) = (sample.is_streamed(), disk_streamer.as_mut()) {
+                    sample.set_stream_source(disk_streamer.register_file(streaming::StreamFile {
+                        path: file.path.clone(),
+                        channels: file_channels,
+                    }));
                 }
-                Ok((rd.clone(), sample, sample_samplerate))
-        }).collect();
-        println!("loaded");
-        regions.map(|data| Self::from_region_array(data, host_samplerate, max_block_length))
+                Arc::new(sample)
+            })
+            .collect();
+
+        let files = sample_files.files.iter().map(|file| file.path.clone()).zip(samples.iter().cloned()).collect();
+
+        let regions = region_data.iter().zip(file_indices)
+            .map(|(rd, index)| match (index, generators::Waveform::from_sample_name(&rd.sample)) {
+                (Some(index), _) => (rd.clone(), samples[index].clone()),
+                (None, waveform) => {
+                    let (rd, sample) = Self::generate_sample(rd, waveform.unwrap(), host_samplerate);
+                    (rd, Arc::new(sample))
+                }
+            })
+            .collect();
+
+        let mut engine = Self::from_region_array(regions, host_samplerate);
+        engine.set_interpolation(options.interpolation);
+        engine.set_max_voices(options.max_voices.unwrap_or(voices::DEFAULT_MAX_VOICES));
+        engine.set_voice_stealing(options.voice_stealing);
+        engine.files = files;
+        engine.stream_buffer_bytes = streaming_config.map_or(0, |config| config.buffer_bytes());
+        engine.streams = disk_streamer.map(|ds| ds.spawn(Box::new(move |file: &streaming::StreamFile, start| {
+            let mut decoder = decoding::Decoder::new(source.open(&file.path).ok()?)?;
+            match decoder.seek(start) {
+                true => Some(Box::new(decoder) as Box<dyn streaming::FrameReader>),
+                false => None,
+            }
+        })));
+        Ok(engine)
     }
 
-    fn from_region_array(reg_data_sample: Vec<(RegionData, Vec<f32>, f64)>,
-                         host_samplerate: f64,
-                         max_block_length: usize) -> Engine {
+    fn load_sample_files<T, F>(files: &[SampleFile],
+                               source: &dyn source::SampleSource,
+                               options: &loading::LoadOptions,
+                               decode: F) -> Result<Vec<T>, EngineError>
+    where T: Send, F: Fn(&SampleFile) -> Result<T, EngineError> + Sync {
+        let sizes: Vec<u64> = files.iter().map(|file| source.size(&file.path)).collect();
+        let progress = loading::ProgressTracker::new(options.progress.as_ref(), files.len(), sizes.iter().sum());
+
+        files.par_iter()
+            .zip(sizes.par_iter())
+            .map(|(file, size)| {
+                if options.cancel.is_cancelled() {
+                    return Err(EngineError::Cancelled);
+                }
+                let sample = decode(file)?;
+                progress.file_done(*size);
+                Ok(sample)
+            })
+            .collect()
+    }
+
+    // Returns the streaming configuration to load with so that the instrument fits into the budget.
+    fn check_memory_budget(budget: loading::MemoryBudget,
+                           files: &[SampleFile],
+                           infos: &[SampleInfo],
+                           host_samplerate: f64,
+                           options: &loading::LoadOptions) -> Result<Option<streaming::StreamingConfig>, EngineError> {
+        let needed_bytes = |config: Option<streaming::StreamingConfig>| {
+            let preload_frames = config.map(|c| c.effective_preload_frames());
+            let sample_bytes: usize = files.iter().zip(infos)
+                .map(|(file, info)| {
+                    let layout = info.layout(host_samplerate, options, preload_frames.filter(|_| file.streamable));
+                    info.memory_bytes(layout, host_samplerate)
+                })
+                .sum();
+            sample_bytes + config.map_or(0, |c| c.buffer_bytes())
+        };
+
+        let needed = needed_bytes(options.streaming);
+        if needed <= budget.bytes {
+            return Ok(options.streaming);
+        }
+        if budget.stream_if_exceeded && options.streaming.is_none() {
+            let config = streaming::StreamingConfig::default();
+            let streamed = needed_bytes(Some(config));
+            if streamed <= budget.bytes {
+                info!("Instrument needs {} bytes of memory, streaming samples to stay within the budget", needed);
+                return Ok(Some(config));
+            }
+            return Err(EngineError::MemoryBudgetExceeded(streamed, budget.bytes));
+        }
+        Err(EngineError::MemoryBudgetExceeded(needed, budget.bytes))
+    }
+
+    fn generate_sample(rd: &RegionData,
+                       waveform: generators::Waveform,
+                       host_samplerate: f64) -> (RegionData, sample::SampleData) {
+        let (sample, sample_samplerate, bands) = waveform.render(rd.pitch_keycenter.to_freq_f64(), host_samplerate);
+        let mut rd = rd.clone();
+        if rd.loop_mode == sample::LoopMode::NoLoop {
+            rd.loop_mode = sample::LoopMode::LoopContinuous;
+        }
+        rd.offset = 0;
+        rd.loop_start = 0;
+        rd.loop_end = None;
+        (rd, sample::SampleData::tables(sample, bands, sample_samplerate))
+    }
+
+    fn from_region_array(reg_data_sample: Vec<(RegionData, Arc<sample::SampleData>)>,
+                         host_samplerate: f64) -> Engine {
+        let regions: Vec<Region> = reg_data_sample.into_iter()
+            .map(|(rd, sample)| Region::new(rd, sample, host_samplerate))
+            .collect();
         Engine {
-            regions: reg_data_sample.iter()
-                .map(|(rd, sample, s_samplerate)| Region::new(rd.clone(),
-                                                              sample.to_vec(),
-                                                              host_samplerate, *s_samplerate,
-                                                              max_block_length))
+            voice_counter: VoiceCounter::new(&regions),
+            activated_groups: Vec::with_capacity(regions.len()),
+            triggered_regions: Vec::with_capacity(regions.len()),
+            regions,
+            streams: None,
+
+            files: Vec::new(),
+            stream_buffer_bytes: 0,
+            sfz_file: None,
+
+            max_voices: voices::DEFAULT_MAX_VOICES,
+            voice_stealing: voices::VoiceStealing::default(),
+
+            events: engine::EventQueue::new(),
+
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    pub fn stream_stats(&self) -> Option<streaming::StreamStats> {
+        self.streams.as_ref().map(|s| s.stats())
+    }
+
+    pub fn memory_usage(&self) -> loading::MemoryUsage {
+        let mut seen = HashSet::new();
+        let sample_bytes = self.regions.iter()
+            .map(|r| r.sample.sample_data())
+            .filter(|sample_data| seen.insert(Arc::as_ptr(sample_data)))
+            .map(|sample_data| sample_data.memory_bytes())
+            .sum();
+
+        loading::MemoryUsage {
+            sample_bytes,
+            stream_buffer_bytes: self.stream_buffer_bytes,
+            files: self.files.iter()
+                .map(|(path, sample_data)| loading::FileMemory {
+                    path: path.clone(),
+                    bytes: sample_data.memory_bytes(),
+                    streamed: sample_data.is_streamed(),
+                })
+                .collect(),
+        }
+    }
+
+    // The SFZ file and the sample files of an engine loaded from a file, the files that make a
+    // reload necessary when they change.
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        match &self.sfz_file {
+            Some(sfz_file) => std::iter::once(sfz_file.clone())
+                .chain(self.files.iter().map(|(path, _)| path.clone()))
                 .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: interpolation::Interpolation) {
+        for r in &mut self.regions {
+            r.set_interpolation(interpolation);
+        }
+    }
+
+    pub fn set_max_voices(&mut self, max_voices: usize) {
+        self.max_voices = max_voices;
+    }
+
+    pub fn set_voice_stealing(&mut self, voice_stealing: voices::VoiceStealing) {
+        self.voice_stealing = voice_stealing;
+    }
+
+    // Steals voices until the `polyphony` and `note_polyphony` of the regions that just started
+    // voices and the engine's voice limit are kept. `note` is the note of the MIDI event.
+    fn enforce_voice_limits(&mut self, note: Option<wmidi::Note>) {
+        if self.triggered_regions.is_empty() {
+            return;
+        }
+        let stealing = self.voice_stealing;
+        let counter = &mut self.voice_counter;
+        counter.count(&self.regions, note);
+        for &i in &self.triggered_regions {
+            let (polyphony, note_polyphony) = (self.regions[i].params.polyphony, self.regions[i].params.note_polyphony);
+            if let Some(limit) = polyphony {
+                let scope = VoiceScope::Polyphony(counter.scopes[i]);
+                while counter.voices(scope) > limit && counter.steal(&mut self.regions, stealing, note, scope) {}
+            }
+            if let (Some(limit), Some(_)) = (note_polyphony, note) {
+                let scope = VoiceScope::NotePolyphony(counter.groups[i]);
+                while counter.voices(scope) > limit && counter.steal(&mut self.regions, stealing, note, scope) {}
+            }
+        }
+        while counter.voices(VoiceScope::Engine) > self.max_voices
+            && counter.steal(&mut self.regions, stealing, note, VoiceScope::Engine) {}
+    }
+
+    fn render(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+        for r in &mut self.regions {
+            r.process(out_left, out_right);
         }
     }
 
@@ -672,37 +1294,162 @@ impl Engine {
         !self.regions.iter().any(|r| r.sample.is_playing())
     }
 
-    pub fn dummy(host_samplerate: f64, max_block_length: usize) -> Engine {
-        Engine::from_region_array(Vec::new(), host_samplerate, max_block_length)
+    pub fn dummy(host_samplerate: f64) -> Engine {
+        Engine::from_region_array(Vec::new(), host_samplerate)
     }
 }
 
+// What the header of a sample file tells.
+#[derive(Debug, Clone, Copy)]
+struct SampleInfo {
+    frames: usize,
+    channels: usize,
+    samplerate: f64,
+    bits: Option<u32>,
+    // Only files the decoder can seek in are streamed.
+    seekable: bool,
+}
+
+// How a decoded sample file is held in memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SampleLayout {
+    Resampled(resampling::ResampleQuality),
+    Streamed(usize),
+    Packed,
+    Float,
+}
+
+impl SampleInfo {
+    fn from_decoder(decoder: &decoding::Decoder) -> SampleInfo {
+        SampleInfo {
+            frames: decoder.frames(),
+            channels: decoder.channels(),
+            samplerate: decoder.samplerate(),
+            bits: decoder.pcm_bits(),
+            seekable: decoder.seekable(),
+        }
+    }
+
+    fn probe(source: &dyn source::SampleSource, path: &Path) -> Result<SampleInfo, EngineError> {
+        let reader = source.open(path).map_err(EngineError::IOError)?;
+        decoding::Decoder::new(reader)
+            .map(|decoder| SampleInfo::from_decoder(&decoder))
+            .ok_or_else(|| EngineError::DecodeError(path.to_path_buf()))
+    }
+
+    // With `preload_frames` only the beginning of longer files is kept in memory.
+    fn layout(&self, host_samplerate: f64, options: &loading::LoadOptions, preload_frames: Option<usize>) -> SampleLayout {
+        match (options.resampling, preload_frames) {
+            (Some(quality), _) if self.samplerate != host_samplerate => SampleLayout::Resampled(quality),
+            (_, Some(preload_frames)) if self.seekable && self.frames > preload_frames => SampleLayout::Streamed(preload_frames),
+            _ if options.packed_samples => SampleLayout::Packed,
+            _ => SampleLayout::Float,
+        }
+    }
+
+    fn memory_bytes(&self, layout: SampleLayout, host_samplerate: f64) -> usize {
+        let float_bytes = std::mem::size_of::<f32>();
+        let (frames, sample_bytes) = match layout {
+            SampleLayout::Resampled(_) => {
+                (resampling::resampled_frames(self.frames, self.samplerate, host_samplerate), float_bytes)
+            }
+            SampleLayout::Streamed(preload_frames) => (preload_frames, float_bytes),
+            SampleLayout::Packed if self.channels <= 2 => (self.frames, storage::Storage::packed_sample_bytes(self.bits)),
+            _ => (self.frames, float_bytes),
+        };
+        (frames + sample::PADDING_FRAMES) * self.channels.min(2) * sample_bytes
+    }
+}
+
+// Returns the decoded sample and the number of channels in the file.
+fn decode_sample(source: &dyn source::SampleSource,
+                 path: &Path,
+                 host_samplerate: f64,
+                 options: &loading::LoadOptions,
+                 preload_frames: Option<usize>) -> Result<(sample::SampleData, usize), EngineError> {
+    let reader = source.open(path).map_err(EngineError::IOError)?;
+    let mut decoder = decoding::Decoder::new(reader)
+        .ok_or_else(|| EngineError::DecodeError(path.to_path_buf()))?;
+
+    let info = SampleInfo::from_decoder(&decoder);
+    let layout = info.layout(host_samplerate, options, preload_frames);
+    if host_samplerate != info.samplerate && !matches!(layout, SampleLayout::Resampled(_)) {
+        warn!("Sample rate of file {} differs from host sample rate. Reccomend resampling or using other host sample rate", path.display());
+    }
+
+    let sample_data = match layout {
+        SampleLayout::Resampled(quality) => {
+            let data = resampling::resample(&decoder.read_all(), info.channels, info.samplerate, host_samplerate, quality);
+            let mut sample_data = sample::SampleData::new(data, info.channels, host_samplerate);
+            sample_data.set_file_samplerate(info.samplerate);
+            sample_data
+        }
+        SampleLayout::Streamed(preload_frames) => {
+            let mut head = vec![0.0; preload_frames * info.channels];
+            let read = decoder.read(&mut head);
+            head.truncate(read * info.channels);
+            sample::SampleData::streamed(head, info.channels, info.samplerate, info.frames)
+        }
+        SampleLayout::Packed => sample::SampleData::packed(decoder.read_all(), info.channels, info.samplerate, info.bits),
+        SampleLayout::Float => sample::SampleData::new(decoder.read_all(), info.channels, info.samplerate),
+    };
+    Ok((sample_data, info.channels))
+}
+
 impl engine::EngineTrait for Engine {
     fn midi_event(&mut self, midi_msg: &wmidi::MidiMessage) {
-        let mut activated_groups = HashSet::new();
-        let random_value = rand::random();
-        for r in &mut self.regions {
+        self.activated_groups.clear();
+        self.triggered_regions.clear();
+        let random_value = self.rng.gen();
+        for (i, r) in self.regions.iter_mut().enumerate() {
             if r.pass_midi_msg(midi_msg, random_value) {
+                self.triggered_regions.push(i);
                 let group = r.group();
-                if group > 0 {
-                    activated_groups.insert(group);
+                if group > 0 && !self.activated_groups.contains(&group) {
+                    self.activated_groups.push(group);
                 }
             }
         }
-        for group in activated_groups {
+        for group in &self.activated_groups {
             for r in &mut self.regions {
-                r.group_activated(group);
+                r.group_activated(*group);
+            }
+        }
+        let note = match midi_msg {
+            wmidi::MidiMessage::NoteOn(_, note, _) | wmidi::MidiMessage::NoteOff(_, note, _) => Some(*note),
+            _ => None,
+        };
+        self.enforce_voice_limits(note);
+    }
+
+    fn queue_midi_event(&mut self, frame: usize, midi_msg: &wmidi::MidiMessage) {
+        if let Some(midi_msg) = midi_msg.clone().drop_unowned_sysex() {
+            if let Err(midi_msg) = self.events.push(frame, midi_msg) {
+                // Nothing may overtake the events queued before, so the due ones go first.
+                while let Some((_, queued)) = self.events.pop_before(frame + 1) {
+                    self.midi_event(&queued);
+                }
+                self.midi_event(&midi_msg);
             }
         }
     }
 
+    // Renders up to each queued event and applies it at its frame.
     fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
-        if out_left.len() * out_right.len() == 0 {
+        let frames = out_left.len().min(out_right.len());
+        if frames == 0 {
             return;
         }
-        for r in &mut self.regions {
-            r.process(out_left, out_right);
+        let mut start = 0;
+        while let Some((frame, midi_msg)) = self.events.pop_before(frames) {
+            if frame > start {
+                self.render(&mut out_left[start..frame], &mut out_right[start..frame]);
+                start = frame;
+            }
+            self.midi_event(&midi_msg);
         }
+        self.render(&mut out_left[start..frames], &mut out_right[start..frames]);
+        self.events.advance(frames);
     }
 }
 
@@ -713,10 +1460,9 @@ mod tests {
     use super::*;
     use crate::engine::EngineTrait;
 
-    use crate::sndfile;
-    use crate::sndfile::SndFileIO;
-
     use crate::sample::tests as sampletests;
+    use crate::sample::SampleData;
+    use std::sync::Arc;
     use crate::sample::tests::f32_eq;
 
     use wmidi::*;
@@ -731,11 +1477,8 @@ mod tests {
         assert_eq!(rd.vel_range.lo, Velocity::MIN);
 
         assert_eq!(rd.amp_veltrack, 1.0);
-/* FIXME: How to test this?
-        let mut env = envelopes::ADSREnvelope::new(&rd.ampeg, 1.0, 4);
-        let (sustain_env, _) = env.active_envelope();
-        assert_eq!(*sustain_env.as_slice(), [1.0; 4]);
-*/
+        let env = envelopes::ADSREnvelope::new(&rd.ampeg, 1.0);
+        assert_eq!(env.peek(envelopes::State::Sustain), 1.0);
         assert_eq!(rd.tune, 0.0)
     }
 
@@ -819,19 +1562,137 @@ mod tests {
     }
 
     #[test]
-    fn parse_sfz_invalid_opcode_line() {
-        match parse_sfz_text("<region> foo=42 lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "Unknown key: foo"),
-            _ => panic!("Not seen expected error"),
-        }
+    fn parse_sfz_invalid_opcode_line() {
+        match parse_sfz_text("<region> foo=42 lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "Unknown key: foo"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_sfz_invalid_non_int_value_line() {
+        match parse_sfz_text("<region> hikey=aa lokey=23".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "Invalid key: aa"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_loop_mode_and_count() {
+        let regions = parse_sfz_text("<region> loop_mode=one_shot <region> loop_mode=no_loop <region> count=3".to_string()).unwrap();
+        assert_eq!(regions.len(), 3);
+        assert_eq!(regions[0].loop_mode, sample::LoopMode::OneShot);
+        assert_eq!(regions[0].count, 1);
+        assert_eq!(regions[1].loop_mode, sample::LoopMode::NoLoop);
+        assert_eq!(regions[2].loop_mode, sample::LoopMode::OneShot);
+        assert_eq!(regions[2].count, 3);
+
+        match parse_sfz_text("<region> loop_mode=foo".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "Unknown key: foo"),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> count=0".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "count out of range: 1 <= 0 <= 4294967295"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn count_does_not_override_loop_mode() {
+        let regions = parse_sfz_text("<region> count=2 loop_mode=loop_continuous <region> loop_mode=loop_continuous count=2
+                                      <group> loop_mode=no_loop <region> count=2 <group> count=2 <region>".to_string()).unwrap();
+        let modes: Vec<_> = regions.iter().map(|r| r.loop_mode).collect();
+        assert_eq!(modes, [sample::LoopMode::LoopContinuous, sample::LoopMode::LoopContinuous,
+                           sample::LoopMode::NoLoop, sample::LoopMode::OneShot]);
+    }
+
+    #[test]
+    fn parse_direction_offset_loop_points() {
+        let regions = parse_sfz_text("<region> direction=reverse offset=200 loop_mode=loop_continuous loop_start=100 loop_end=300 <region> loopstart=10 loopend=20 loop_mode=loop_sustain".to_string()).unwrap();
+        assert_eq!(regions[0].direction, sample::Direction::Reverse);
+        assert_eq!(regions[0].offset, 200);
+        assert_eq!(regions[0].loop_mode, sample::LoopMode::LoopContinuous);
+        assert_eq!(regions[0].loop_start, 100);
+        assert_eq!(regions[0].loop_end, Some(300));
+        assert_eq!(regions[1].direction, sample::Direction::Forward);
+        assert_eq!(regions[1].loop_mode, sample::LoopMode::LoopSustain);
+        assert_eq!(regions[1].loop_start, 10);
+        assert_eq!(regions[1].loop_end, Some(20));
+
+        match parse_sfz_text("<region> direction=sideways".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "Unknown key: sideways"),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> loop_end=10 loop_start=20".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "Flipped range loop_start: 20 <-> 10"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_sample_quality() {
+        let regions = parse_sfz_text("<region> sample_quality=1 <region> sample_quality=2 <region> sample_quality=5 <region>".to_string()).unwrap();
+        assert_eq!(regions[0].sample_quality, Some(interpolation::Interpolation::Linear));
+        assert_eq!(regions[1].sample_quality, Some(interpolation::Interpolation::Cubic));
+        assert_eq!(regions[2].sample_quality, Some(interpolation::Interpolation::Sinc));
+        assert_eq!(regions[3].sample_quality, None);
+
+        match parse_sfz_text("<region> sample_quality=11".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "sample_quality out of range: 0 <= 11 <= 10"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_polyphony() {
+        let regions = parse_sfz_text("<group> polyphony=4 note_polyphony=2 <region> <region> polyphony=1 <group> <region>".to_string()).unwrap();
+        assert_eq!((regions[0].polyphony, regions[0].note_polyphony), (Some(4), Some(2)));
+        assert_eq!((regions[1].polyphony, regions[1].note_polyphony), (Some(1), Some(2)));
+        assert_eq!((regions[2].polyphony, regions[2].note_polyphony), (None, None));
+
+        match parse_sfz_text("<region> note_polyphony=0".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "note_polyphony out of range: 1 <= 0 <= 65535"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn parse_off_mode_and_note_selfmask() {
+        let regions = parse_sfz_text("<region> off_mode=normal note_selfmask=off <region> off_mode=time off_time=0.5 <region>".to_string()).unwrap();
+        assert_eq!((regions[0].off_mode, regions[0].note_selfmask), (OffMode::Normal, false));
+        assert_eq!((regions[1].off_mode, regions[1].off_time), (OffMode::Time, 0.5));
+        assert_eq!((regions[2].off_mode, regions[2].note_selfmask), (OffMode::Fast, true));
+
+        match parse_sfz_text("<region> off_mode=slow".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "Unknown key: slow"),
+            _ => panic!("Not seen expected error"),
+        }
+        match parse_sfz_text("<region> note_selfmask=yes".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "Unknown key: yes"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn engine_interpolation_keeps_region_sample_quality() {
+        let regions = parse_sfz_text("<region> sample_quality=1 <region>".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(regions.into_iter()
+                                                   .map(|rd| (rd, Arc::new(SampleData::new(vec![0.0; 8], 2, 1.0))))
+                                                   .collect(),
+                                                   1.0);
+        assert_eq!(sampletests::interpolation(&engine.regions[0].sample), interpolation::Interpolation::Linear);
+        assert_eq!(sampletests::interpolation(&engine.regions[1].sample), interpolation::Interpolation::Cubic);
+
+        engine.set_interpolation(interpolation::Interpolation::Sinc);
+        assert_eq!(sampletests::interpolation(&engine.regions[0].sample), interpolation::Interpolation::Linear);
+        assert_eq!(sampletests::interpolation(&engine.regions[1].sample), interpolation::Interpolation::Sinc);
     }
 
     #[test]
-    fn parse_sfz_invalid_non_int_value_line() {
-        match parse_sfz_text("<region> hikey=aa lokey=23".to_string()) {
-            Err(e) => assert_eq!(format!("{}", e), "Invalid key: aa"),
-            _ => panic!("Not seen expected error"),
-        }
+    fn streamable_regions() {
+        let regions = parse_sfz_text("<region> sample=a.wav <region> loop_mode=one_shot <region> count=2 <region> direction=reverse <region> offset=10 <region> loop_mode=loop_continuous <region> loop_mode=loop_sustain".to_string()).unwrap();
+        let streamable: Vec<bool> = regions.iter().map(|rd| rd.is_streamable()).collect();
+        assert_eq!(streamable, [true, true, false, false, false, false, false]);
     }
 
     /* FIXME: How to test this?
@@ -973,6 +1834,104 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_ampeg_modulation() {
+        assert!(parse_sfz_text("<region> ampeg_delay=0.5 ampeg_start=20 ampeg_vel2delay=-0.5 ampeg_vel2attack=2 ampeg_vel2hold=1 ampeg_vel2decay=3 ampeg_vel2sustain=-30 ampeg_vel2release=0.5 ampeg_attack_oncc1=2.5 ampeg_sustain_oncc74=-50 ampeg_delay_oncc2=1".to_string()).is_ok());
+
+        match parse_sfz_text("<region> ampeg_start=105".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "ampeg_start out of range: 0 <= 105 <= 100"),
+            _ => panic!("Not seen expected error")
+        }
+        match parse_sfz_text("<region> ampeg_vel2release=-105".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "ampeg_vel2release out of range: -100 <= -105 <= 100"),
+            _ => panic!("Not seen expected error")
+        }
+        match parse_sfz_text("<region> ampeg_hold_oncc1=105".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "ampeg_hold_onccN out of range: -100 <= 105 <= 100"),
+            _ => panic!("Not seen expected error")
+        }
+        match parse_sfz_text("<region> ampeg_attack_oncc130=1".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "cc number out of range: 0 <= 130 <= 127"),
+            _ => panic!("Not seen expected error")
+        }
+        match parse_sfz_text("<region> ampeg_depth_oncc1=1".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "Unknown key: ampeg_depth_on"),
+            _ => panic!("Not seen expected error")
+        }
+    }
+
+    #[test]
+    fn parse_ampeg_shapes() {
+        let render = |sfz: &str| {
+            let regions = parse_sfz_text(sfz.to_string()).unwrap();
+            let mut region = Region::new(regions[0].clone(), Arc::new(SampleData::new(vec![1.0; 32], 2, 1.0)), 1.0);
+            region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+            let mut out_left: [f32; 4] = [0.0; 4];
+            let mut out_right: [f32; 4] = [0.0; 4];
+            region.process(&mut out_left, &mut out_right);
+            out_left.iter().map(|v| (v*100.0).round()/100.0).collect::<Vec<f32>>()
+        };
+
+        assert_eq!(render("<region> ampeg_attack=2").as_slice(), [0.0, 0.5, 1.0, 1.0]);
+        assert_eq!(render("<region> ampeg_attack=2 ampeg_attack_shape=exponential").as_slice(), [0.0, 0.98, 1.0, 1.0]);
+        assert_eq!(render("<region> ampeg_attack=2 ampeg_attack_shape=-8").as_slice(), [0.0, 0.98, 1.0, 1.0]);
+        assert_eq!(render("<region> ampeg_attack=2 ampeg_attack_shape=-10.36").as_slice(), [0.0, 0.99, 1.0, 1.0]);
+        assert_eq!(render("<region> ampeg_attack=2 ampeg_attack_shape=logarithmic").as_slice(), [0.0, 0.02, 1.0, 1.0]);
+        assert_eq!(render("<region> ampeg_attack=2 ampeg_attack_shape=2.5").as_slice(), [0.0, 0.22, 1.0, 1.0]);
+        assert_eq!(render("<region> ampeg_decay=2 ampeg_sustain=50 ampeg_decay_shape=0").as_slice(), [1.0, 0.75, 0.5, 0.5]);
+
+        for shape in &["curved", "nan"] {
+            match parse_sfz_text(format!("<region> ampeg_release_shape={}", shape)) {
+                Err(e) => assert_eq!(format!("{}", e), format!("Unknown key: {}", shape)),
+                _ => panic!("Not seen expected error")
+            }
+        }
+    }
+
+    #[test]
+    fn parse_pan_width_position() {
+        let regions = parse_sfz_text("<region> pan=-30 width=50 position=20".to_string()).unwrap();
+        assert_eq!(regions[0].pan, -0.3);
+        assert_eq!(regions[0].width, 0.5);
+        assert_eq!(regions[0].position, 0.2);
+
+        let rd = RegionData::default();
+        assert_eq!((rd.pan, rd.width, rd.position), (0.0, 1.0, 0.0));
+
+        match parse_sfz_text("<region> pan=101".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "pan out of range: -100 <= 101 <= 100"),
+            _ => panic!("Not seen expected error")
+        }
+        match parse_sfz_text("<region> width=-120".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "width out of range: -100 <= -120 <= 100"),
+            _ => panic!("Not seen expected error")
+        }
+        match parse_sfz_text("<region> position=100.5".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "position out of range: -100 <= 100.5 <= 100"),
+            _ => panic!("Not seen expected error")
+        }
+    }
+
+    #[test]
+    fn parse_amp_keytrack_and_keycenter() {
+        let regions = parse_sfz_text("<region> amp_keytrack=-1.5 amp_keycenter=64 <region> amp_keycenter=e4".to_string()).unwrap();
+        assert_eq!(regions[0].amp_keytrack, -1.5);
+        assert_eq!(regions[0].amp_keycenter, Note::E3);
+        assert_eq!(regions[1].amp_keycenter, Note::E3);
+
+        let rd = RegionData::default();
+        assert_eq!((rd.amp_keytrack, rd.amp_keycenter), (0.0, Note::C3));
+
+        match parse_sfz_text("<region> amp_keytrack=13".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "amp_keytrack out of range: -96 <= 13 <= 12"),
+            _ => panic!("Not seen expected error")
+        }
+        match parse_sfz_text("<region> amp_keycenter=128".to_string()) {
+            Err(e) => assert_eq!(format!("{}", e), "amp_keycenter out of range: 0 <= 128 <= 127"),
+            _ => panic!("Not seen expected error")
+        }
+    }
+
     #[test]
     fn parse_sfz_comment_in_line() {
         let regions = parse_sfz_text("<region> hivel=42 lovel=23 // foo".to_string()).unwrap();
@@ -1474,7 +2433,7 @@ mod tests {
                           0.5, 1.0,
                           1.0, 0.5];
 
-        let mut region = Region::new(RegionData::default(), sample, 1.0, 1.0, 8);
+        let mut region = Region::new(RegionData::default(), Arc::new(SampleData::new(sample, 2, 1.0)), 1.0);
 
         region.note_on(Note::C3, Velocity::MAX);
 
@@ -1516,7 +2475,7 @@ mod tests {
         let mut region_data = RegionData::default();
         region_data.set_volume(-20.0).unwrap();
 
-        let mut region = Region::new(region_data, sample, 1.0, 1.0, 8);
+        let mut region = Region::new(region_data, Arc::new(SampleData::new(sample, 2, 1.0)), 1.0);
 
         region.note_on(Note::C3, Velocity::MAX);
 
@@ -1539,7 +2498,7 @@ mod tests {
         )
         .unwrap();
 
-        let mut region = Region::new(regions.get(0).unwrap().clone(), sample, 1.0, 1.0, 16);
+        let mut region = Region::new(regions[0].clone(), Arc::new(SampleData::new(sample, 2, 1.0)), 1.0);
         region.note_on(Note::C3, Velocity::MAX);
 
         let mut out_left: [f32; 12] = [0.0; 12];
@@ -1567,7 +2526,7 @@ mod tests {
         )
         .unwrap();
 
-        let mut region = Region::new(regions.get(0).unwrap().clone(), sample, 1.0, 1.0, 12);
+        let mut region = Region::new(regions[0].clone(), Arc::new(SampleData::new(sample, 2, 1.0)), 1.0);
         region.note_on(Note::C3, Velocity::MAX);
 
         let mut out_left: [f32; 12] = [0.0; 12];
@@ -1624,9 +2583,9 @@ mod tests {
                            -0.5, -0.5,
                            0.0, 0.5];
 
-        let mut engine = Engine::from_region_array(vec![(RegionData::default(), sample1, 1.0),
-                                                        (RegionData::default(), sample2, 1.0)],
-                                                   1.0, 16);
+        let mut engine = Engine::from_region_array(vec![(RegionData::default(), Arc::new(SampleData::new(sample1, 2, 1.0))),
+                                                        (RegionData::default(), Arc::new(SampleData::new(sample2, 2, 1.0)))],
+                                                   1.0);
 
         engine.regions[0].note_on(Note::C3, Velocity::MAX);
         engine.regions[1].note_on(Note::C3, Velocity::MAX);
@@ -1648,9 +2607,9 @@ mod tests {
         assert_eq!(out_right[2], 1.0);
     }
 
-    fn make_dummy_region(rd: RegionData, samplerate: f64, max_block_length: usize) -> Region {
+    fn make_dummy_region(rd: RegionData, samplerate: f64) -> Region {
         let sample = vec![1.0; 96];
-        Region::new(rd, sample, samplerate, samplerate, max_block_length)
+        Region::new(rd, Arc::new(SampleData::new(sample, 2, samplerate)), samplerate)
     }
 
     fn pull_samples(region: &mut Region, nsamples: usize) -> (Vec<f32>, Vec<f32>) {
@@ -1668,7 +2627,7 @@ mod tests {
         let mut rd = RegionData::default();
         rd.key_range.set_hi(70).unwrap();
         rd.key_range.set_lo(60).unwrap();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::E2, Velocity::MAX), 0.0);
         assert!(!sample::tests::is_playing_note(&region.sample, Note::E2));
@@ -1693,7 +2652,7 @@ mod tests {
         let mut rd = RegionData::default();
         rd.vel_range.set_hi(70).unwrap();
         rd.vel_range.set_lo(60).unwrap();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(90).unwrap()), 0.0);
@@ -1724,7 +2683,7 @@ mod tests {
         rd.push_on_hi_cc(64, 127).unwrap();
         rd.push_on_hi_cc(42, 23).unwrap();
 
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::ControlChange(Channel::Ch1,
                                                                 ControlNumber::try_from(23).unwrap(),
@@ -1753,7 +2712,7 @@ mod tests {
     fn note_trigger_release() {
         let mut rd = RegionData::default();
         rd.set_trigger(Trigger::Release);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
         assert!(!sample::tests::is_playing_note(&region.sample, Note::C3));
@@ -1763,12 +2722,31 @@ mod tests {
         assert_eq!(region.gain, 0.24607849215698431397);
     }
 
+    #[test]
+    fn note_off_one_shot() {
+        let mut rd = RegionData::default();
+        rd.set_loop_mode(sample::LoopMode::OneShot);
+        let mut region = make_dummy_region(rd, 1.0);
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
+
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
+        assert!(!sample::tests::is_releasing_note(&region.sample, Note::C3));
+
+        for _ in 0..48 {
+            pull_samples(&mut region, 2);
+        }
+        assert!(!region.sample.is_playing());
+    }
+
     #[test]
     fn trigger_release_rt_decay() {
             let mut rd = RegionData::default();
         rd.set_trigger(Trigger::Release);
         rd.set_rt_decay(3.0).unwrap();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
@@ -1785,7 +2763,7 @@ mod tests {
         let mut rd = RegionData::default();
         rd.set_trigger(Trigger::Release);
         rd.set_rt_decay(3.0).unwrap();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
@@ -1802,7 +2780,7 @@ mod tests {
         let mut rd = RegionData::default();
         rd.set_trigger(Trigger::Release);
         rd.set_rt_decay(3.0).unwrap();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
@@ -1822,7 +2800,7 @@ mod tests {
     fn note_trigger_release_sustain_pedal() {
             let mut rd = RegionData::default();
         rd.set_trigger(Trigger::Release);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         // sustain pedal on
         region.pass_midi_msg(&MidiMessage::ControlChange(
@@ -1864,7 +2842,7 @@ mod tests {
 
         let mut rd = RegionData::default();
         rd.set_trigger(Trigger::Release);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
         assert!(!sample::tests::is_playing_note(&region.sample, Note::C3));
@@ -1892,7 +2870,7 @@ mod tests {
     fn note_trigger_release_key() {
         let mut rd = RegionData::default();
         rd.set_trigger(Trigger::ReleaseKey);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
         assert!(!region.sample.is_playing());
@@ -1909,7 +2887,7 @@ mod tests {
         rd.set_trigger(Trigger::ReleaseKey);
         rd.vel_range.set_hi(70).unwrap();
         rd.vel_range.set_lo(60).unwrap();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(90).unwrap()), 0.0);
         assert!(!region.sample.is_playing());
@@ -1932,7 +2910,7 @@ mod tests {
     fn note_trigger_release_key_sustain_pedal() {
             let mut rd = RegionData::default();
         rd.set_trigger(Trigger::ReleaseKey);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         // sustain pedal on
         region.pass_midi_msg(&MidiMessage::ControlChange(
@@ -1972,7 +2950,7 @@ mod tests {
 
         let mut rd = RegionData::default();
         rd.set_trigger(Trigger::ReleaseKey);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
         assert!(!region.sample.is_playing());
@@ -2000,7 +2978,7 @@ mod tests {
         rd.key_range.set_hi(60).unwrap();
         rd.key_range.set_lo(60).unwrap();
         rd.set_trigger(Trigger::First);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
         assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
@@ -2009,7 +2987,7 @@ mod tests {
         rd.key_range.set_hi(60).unwrap();
         rd.key_range.set_lo(60).unwrap();
         rd.set_trigger(Trigger::First);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3,  Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
@@ -2019,7 +2997,7 @@ mod tests {
         rd.key_range.set_hi(60).unwrap();
         rd.key_range.set_lo(60).unwrap();
         rd.set_trigger(Trigger::First);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3,  Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::A3,  Velocity::MAX), 0.0);
@@ -2033,7 +3011,7 @@ mod tests {
         rd.key_range.set_hi(60).unwrap();
         rd.key_range.set_lo(60).unwrap();
         rd.set_trigger(Trigger::Legato);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
         assert!(!region.sample.is_playing());
@@ -2042,7 +3020,7 @@ mod tests {
         rd.key_range.set_hi(60).unwrap();
         rd.key_range.set_lo(60).unwrap();
         rd.set_trigger(Trigger::Legato);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3,  Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
@@ -2052,7 +3030,7 @@ mod tests {
         rd.key_range.set_hi(60).unwrap();
         rd.key_range.set_lo(60).unwrap();
         rd.set_trigger(Trigger::Legato);
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3,  Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::A3,  Velocity::MAX), 0.0);
@@ -2063,7 +3041,7 @@ mod tests {
     #[test]
     fn note_off_sustain_pedal() {
         let rd = RegionData::default();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
         assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
@@ -2092,7 +3070,7 @@ mod tests {
     #[test]
     fn note_on_during_release() {
         let rd = RegionData::default();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
@@ -2105,7 +3083,7 @@ mod tests {
     #[test]
     fn note_on_off_during_release() {
         let rd = RegionData::default();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
         region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
@@ -2120,7 +3098,7 @@ mod tests {
     fn note_on_off_detuned() {
         let mut rd = RegionData::default();
         rd.tune = 1.0;
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
         assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
@@ -2133,7 +3111,7 @@ mod tests {
     #[test]
     fn note_remain_sustain_pedal() {
         let rd = RegionData::default();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
         assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
@@ -2165,7 +3143,7 @@ mod tests {
     #[test]
     fn note_off_polyphonic_sustain_pedal() {
         let rd = RegionData::default();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3,  Velocity::MAX), 0.0);
         assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
@@ -2205,7 +3183,7 @@ mod tests {
     #[test]
     fn note_on_note_on_sustain_pedal() {
         let rd = RegionData::default();
-        let mut region = make_dummy_region(rd, 1.0, 2);
+        let mut region = make_dummy_region(rd, 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
         assert!(sample::tests::is_playing_note(&region.sample, Note::C3));
@@ -2257,7 +3235,7 @@ mod tests {
                           0.5, -0.5];
 
         let mut engine =
-            Engine::from_region_array(vec![(RegionData::default(), sample, 1.0)], 1.0, 16);
+            Engine::from_region_array(vec![(RegionData::default(), Arc::new(SampleData::new(sample, 2, 1.0)))], 1.0);
 
         let mut out_left: [f32; 1] = [0.0];
         let mut out_right: [f32; 1] = [0.0];
@@ -2294,7 +3272,7 @@ mod tests {
         sample.resize(48, 1.0);
         let regions = parse_sfz_text("<region> ampeg_attack=2 ampeg_hold=3 ampeg_decay=4 ampeg_sustain=60 ampeg_release=5".to_string()).unwrap();
 
-        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), sample, 1.0)], 1.0, 16);
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), Arc::new(SampleData::new(sample, 2, 1.0)))], 1.0);
 
         let mut out_left: [f32; 12] = [0.0; 12];
         let mut out_right: [f32; 12] = [0.0; 12];
@@ -2325,10 +3303,54 @@ mod tests {
     }
 
 
+    #[test]
+    fn note_on_ampeg_delay_start_and_modulation() {
+        let sample = vec![1.0; 32];
+        let regions = parse_sfz_text("<region> ampeg_delay=2 ampeg_start=50 ampeg_attack=2 ampeg_vel2attack=2 ampeg_attack_oncc1=-4".to_string()).unwrap();
+        let mut region = Region::new(regions[0].clone(), Arc::new(SampleData::new(sample, 2, 1.0)), 1.0);
+
+        let mut out_left: [f32; 8] = [0.0; 8];
+        let mut out_right: [f32; 8] = [0.0; 8];
+
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.0, 0.0, 0.5, 0.625, 0.75, 0.875, 1.0, 1.0]);
+
+        region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(1).unwrap(), ControlValue::MAX), 0.0);
+        region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+
+        let mut out_left: [f32; 4] = [0.0; 4];
+        let mut out_right: [f32; 4] = [0.0; 4];
+        region.process(&mut out_left, &mut out_right);
+        assert_eq!(out_left, [0.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn region_pan_width_position() {
+        let render = |sfz: &str, sample: Vec<f32>, channels: usize| {
+            let regions = parse_sfz_text(sfz.to_string()).unwrap();
+            let mut region = Region::new(regions[0].clone(), Arc::new(SampleData::new(sample, channels, 1.0)), 1.0);
+            region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
+            let mut out_left: [f32; 1] = [0.0];
+            let mut out_right: [f32; 1] = [0.0];
+            region.process(&mut out_left, &mut out_right);
+            ((out_left[0] * 1000.0).round() / 1000.0, (out_right[0] * 1000.0).round() / 1000.0)
+        };
+
+        assert_eq!(render("<region>", vec![1.0, 0.5, 1.0, 0.5], 2), (1.0, 0.5));
+        assert_eq!(render("<region> pan=-100", vec![1.0, 1.0], 1), (1.414, 0.0));
+        assert_eq!(render("<region> pan=100", vec![1.0, 1.0], 1), (0.0, 1.414));
+        assert_eq!(render("<region> position=-100", vec![1.0, 1.0], 1), (1.414, 0.0));
+        assert_eq!(render("<region> width=0", vec![1.0, 0.5, 1.0, 0.5], 2), (0.75, 0.75));
+        assert_eq!(render("<region> width=-100", vec![1.0, 0.5, 1.0, 0.5], 2), (0.5, 1.0));
+        assert_eq!(render("<region> width=0 position=100", vec![1.0, 0.0, 1.0, 0.0], 2), (0.0, 0.707));
+    }
+
     #[test]
     fn note_on_velocity() {
         let sample = vec![1.0, 1.0];
-        let mut region = Region::new(RegionData::default(), sample, 1.0, 1.0, 16);
+        let mut region = Region::new(RegionData::default(), Arc::new(SampleData::new(sample, 2, 1.0)), 1.0);
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(63).unwrap()), 0.0);
 
         let mut out_left: [f32; 1] = [0.0];
@@ -2339,13 +3361,29 @@ mod tests {
         assert_eq!(out_right[0], 0.24607849215698431397);
     }
 
+    #[test]
+    fn note_on_amp_keytrack() {
+        let regions = parse_sfz_text("<region> amp_keytrack=-6 amp_keycenter=62".to_string()).unwrap();
+        let mut region = Region::new(regions[0].clone(), Arc::new(SampleData::new(vec![1.0; 8], 2, 1.0)), 1.0);
+
+        let mut gain = |note: Note| {
+            region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, note, Velocity::MAX), 0.0);
+            region.pass_midi_msg(&MidiMessage::NoteOff(Channel::Ch1, note, Velocity::MAX), 0.0);
+            region.gain
+        };
+
+        assert_eq!(gain(Note::D3), 1.0);
+        assert_eq!(gain(Note::E3), utils::dB_to_gain(-12.0));
+        assert_eq!(gain(Note::C3), utils::dB_to_gain(12.0));
+    }
+
     #[test]
     fn note_on_gain_veltrack() {
         let sample = vec![1.0, 1.0];
         let mut rd = RegionData::default();
         rd.set_amp_veltrack(0.0).unwrap();
 
-        let mut region = Region::new(rd, sample.clone(), 1.0, 1.0, 16);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample.clone(), 2, 1.0)), 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
 
@@ -2369,7 +3407,7 @@ mod tests {
         let mut rd = RegionData::default();
         rd.set_amp_veltrack(-100.0).unwrap();
 
-        let mut region = Region::new(rd, sample.clone(), 1.0, 1.0, 16);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample.clone(), 2, 1.0)), 1.0);
 
         region.pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MIN), 0.0);
 
@@ -2400,7 +3438,7 @@ mod tests {
         let region = parse_sfz_text("<region> lokey=60 hikey=60".to_string()).unwrap()[0].clone();
 
         let mut engine =
-            Engine::from_region_array(vec![(region.clone(), sample.clone(), 1.0)], 1.0, 16);
+            Engine::from_region_array(vec![(region.clone(), Arc::new(SampleData::new(sample.clone(), 2, 1.0)))], 1.0);
 
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX));
 
@@ -2412,7 +3450,7 @@ mod tests {
         assert!(f32_eq(out_right[0], 0.0));
 
         let mut engine =
-            Engine::from_region_array(vec![(region.clone(), sample.clone(), 1.0)], 1.0, 16);
+            Engine::from_region_array(vec![(region.clone(), Arc::new(SampleData::new(sample.clone(), 2, 1.0)))], 1.0);
 
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
 
@@ -2442,7 +3480,7 @@ mod tests {
         rd.pitch_keycenter = Note::A3;
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 440.0);
@@ -2451,7 +3489,7 @@ mod tests {
         rd.pitch_keycenter = Note::A3;
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A4, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 880.0);
@@ -2461,7 +3499,7 @@ mod tests {
         rd.set_pitch_keytrack(0.0).unwrap();
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 440.0);
@@ -2471,7 +3509,7 @@ mod tests {
         rd.set_pitch_keytrack(0.0).unwrap();
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A4, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 440.0);
@@ -2481,7 +3519,7 @@ mod tests {
         rd.set_pitch_keytrack(-100.0).unwrap();
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 440.0);
@@ -2491,7 +3529,7 @@ mod tests {
         rd.set_pitch_keytrack(-100.0).unwrap();
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A4, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 220.0);
@@ -2501,7 +3539,7 @@ mod tests {
         rd.set_pitch_keytrack(1200.0).unwrap();
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 440.0);
@@ -2511,7 +3549,7 @@ mod tests {
         rd.set_pitch_keytrack(1200.0).unwrap();
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::ASharp3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 880.0);
@@ -2526,7 +3564,7 @@ mod tests {
         rd.pitch_keycenter = Note::A3;
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 440.0);
@@ -2536,7 +3574,7 @@ mod tests {
         rd.tune = 1.0;
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::Ab3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 440.0);
@@ -2546,7 +3584,7 @@ mod tests {
         rd.tune = -1.0;
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::ASharp3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 440.0);
@@ -2556,7 +3594,7 @@ mod tests {
         rd.tune = 1.0;
 
         let sample_data = sampletests::make_test_sample_data(nsamples, samplerate, 440.0);
-        let mut region = Region::new(rd, sample_data, samplerate, samplerate, nsamples);
+        let mut region = Region::new(rd, Arc::new(SampleData::new(sample_data, 2, samplerate)), samplerate);
 
         region.note_on(Note::A3, Velocity::MAX);
         sampletests::assert_frequency(region.sample, samplerate, 466.16);
@@ -2571,10 +3609,9 @@ mod tests {
             parse_sfz_text(region_text)
                 .unwrap()
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), Arc::new(SampleData::new(Vec::new(), 2, 1.0))))
                 .collect(),
             1.0,
-            1,
         );
         for i in 0..2 {
             engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX), 0.0);
@@ -2589,10 +3626,9 @@ mod tests {
             parse_sfz_text(region_text)
                 .unwrap()
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), Arc::new(SampleData::new(Vec::new(), 2, 1.0))))
                 .collect(),
             1.0,
-            1,
         );
         for i in 0..2 {
             engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::A3, Velocity::MAX), 0.5);
@@ -2607,10 +3643,9 @@ mod tests {
             parse_sfz_text(region_text)
                 .unwrap()
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), Arc::new(SampleData::new(Vec::new(), 2, 1.0))))
                 .collect(),
             1.0,
-            1,
         );
         for i in 0..2 {
             engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.0);
@@ -2625,10 +3660,9 @@ mod tests {
             parse_sfz_text(region_text)
                 .unwrap()
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), Arc::new(SampleData::new(Vec::new(), 2, 1.0))))
                 .collect(),
             1.0,
-            1,
         );
         for i in 0..2 {
             engine.regions[i].pass_midi_msg(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX), 0.5);
@@ -2646,10 +3680,9 @@ mod tests {
             parse_sfz_text(region_text)
                 .unwrap()
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), Arc::new(SampleData::new(Vec::new(), 2, 1.0))))
                 .collect(),
             1.0,
-            1,
         );
         engine.midi_event(&MidiMessage::NoteOn(
             Channel::Ch1,
@@ -2665,10 +3698,9 @@ mod tests {
             parse_sfz_text(region_text)
                 .unwrap()
                 .iter()
-                .map(|reg| (reg.clone(), Vec::new(), 1.0))
+                .map(|reg| (reg.clone(), Arc::new(SampleData::new(Vec::new(), 2, 1.0))))
                 .collect(),
             1.0,
-            1,
         );
         engine.midi_event(&MidiMessage::NoteOn(
             Channel::Ch1,
@@ -2701,7 +3733,7 @@ mod tests {
         .to_string();
         let regions = parse_sfz_text(region_text).unwrap();
 
-        let mut engine = Engine::from_region_array(regions.iter().map(|reg| (reg.clone(), vec![1.0; 96], 1.0)).collect(), 1.0, 1);
+        let mut engine = Engine::from_region_array(regions.iter().map(|reg| (reg.clone(), Arc::new(SampleData::new(vec![1.0; 96], 2, 1.0)))).collect(), 1.0);
 
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A1, Velocity::MAX));
         pull_samples_engine(&mut engine, 1);
@@ -2783,7 +3815,7 @@ mod tests {
 
         let regions = parse_sfz_text(region_text).unwrap();
 
-        let mut engine = Engine::from_region_array(regions.iter().map(|reg| (reg.clone(), vec![1.0; 96], 1.0)).collect(), 1.0, 1);
+        let mut engine = Engine::from_region_array(regions.iter().map(|reg| (reg.clone(), Arc::new(SampleData::new(vec![1.0; 96], 2, 1.0)))).collect(), 1.0);
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::try_from(20).unwrap()));
         pull_samples_engine(&mut engine, 1);
         assert!(!engine.regions[0].sample.is_playing());
@@ -2850,10 +3882,9 @@ mod tests {
         let mut engine = Engine::from_region_array(
             regions
                 .iter()
-                .map(|reg| (reg.clone(), vec![1.0; 96], 1.0))
+                .map(|reg| (reg.clone(), Arc::new(SampleData::new(vec![1.0; 96], 2, 1.0))))
                 .collect(),
             1.0,
-            1,
         );
 
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::A2, Velocity::MAX));
@@ -2899,10 +3930,8 @@ mod tests {
 
     #[test]
     fn test_real_sample() {
-        let mut snd = sndfile::OpenOptions::ReadOnly(sndfile::ReadOptions::Auto)
-            .from_path("assets/gmidi-grand-piano-C4.flac")
-            .unwrap();
-        let sample = snd.read_all_to_vec().unwrap();
+        let reader = source::SampleSource::open(&source::FileSystemSource::new("assets"), Path::new("assets/gmidi-grand-piano-C4.flac")).unwrap();
+        let sample = decoding::Decoder::new(reader).unwrap().read_all();
         assert_eq!(sample.len(), 824977 * 2);
 
         let mut reference = [vec![0.0f32; 2048], sample.clone()].concat();
@@ -2915,7 +3944,7 @@ mod tests {
 
         let goal = (30 * 48000) / 1024;
 
-        let mut engine = Engine::new("assets/simple-test-instrument.sfz".to_string(), 48000.0, 1024).unwrap();
+        let mut engine = Engine::new("assets/simple-test-instrument.sfz".to_string(), 48000.0).unwrap();
 
         engine.process(&mut out_left, &mut out_right);
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
@@ -2940,7 +3969,6 @@ mod tests {
         let mut engine = Engine::new(
             "assets/samplerate-shift-test.sfz".to_string(),
             48000.0,
-            1024,
         )
         .unwrap();
 
@@ -2975,7 +4003,6 @@ mod tests {
         let mut engine = Engine::new(
             "assets/samplerate-shift-test.sfz".to_string(),
             44100.0,
-            1024,
         )
         .unwrap();
 
@@ -3010,7 +4037,6 @@ mod tests {
         let mut engine = Engine::new(
             "assets/samplerate-shift-test.sfz".to_string(),
             44100.0,
-            1024,
         )
         .unwrap();
 
@@ -3045,7 +4071,6 @@ mod tests {
         let mut engine = Engine::new(
             "assets/samplerate-shift-test.sfz".to_string(),
             48000.0,
-            1024,
         )
         .unwrap();
 
@@ -3078,11 +4103,595 @@ mod tests {
         );
     }
 
+    #[test]
+    fn generated_saw_keeps_its_harmonics() {
+        let regions = parse_sfz_text("<region> sample=*saw pitch_keycenter=69".to_string()).unwrap();
+        let (rd, sample) = Engine::generate_sample(&regions[0], generators::Waveform::Saw, 48000.0);
+
+        // The amplitude of `frequency` in `signal`.
+        let amplitude = |signal: &[f32], frequency: f64| {
+            let (re, im) = signal.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, s)| {
+                let phase = 2.0 * std::f64::consts::PI * frequency * i as f64 / 48000.0;
+                (re + *s as f64 * phase.cos(), im + *s as f64 * phase.sin())
+            });
+            2.0 * (re * re + im * im).sqrt() / signal.len() as f64
+        };
+
+        let sample = Arc::new(sample);
+        for (note, frequency) in [(Note::A1, 110.0), (Note::A3, 440.0), (Note::A5, 1760.0)].iter() {
+            let mut region = Region::new(rd.clone(), sample.clone(), 48000.0);
+            region.note_on(*note, Velocity::MAX);
+            let mut out_left = vec![0.0; 4800];
+            let mut out_right = vec![0.0; 4800];
+            region.process(&mut out_left, &mut out_right);
+
+            let fundamental = amplitude(&out_left, *frequency);
+            assert!(amplitude(&out_left, 2.0 * frequency) > 0.4 * fundamental);
+            assert!(amplitude(&out_left, 3.0 * frequency) > 0.25 * fundamental);
+        }
+    }
+
+    #[test]
+    fn generator_sample_region() {
+        let regions = parse_sfz_text("<region> sample=*sine pitch_keycenter=69".to_string()).unwrap();
+
+        for (note, freq) in [(Note::A3, 440.0), (Note::A4, 880.0)].iter() {
+            let (rd, sample) = Engine::generate_sample(&regions[0], generators::Waveform::Sine, 48000.0);
+            assert_eq!(rd.loop_mode, sample::LoopMode::LoopContinuous);
+
+            let mut region = Region::new(rd, Arc::new(sample), 48000.0);
+            region.note_on(*note, Velocity::MAX);
+
+            let mut out_left = vec![0.0; 48 * 1024];
+            let mut out_right = vec![0.0; 48 * 1024];
+            for i in 0..48 {
+                region.process(&mut out_left[i * 1024..(i + 1) * 1024],
+                               &mut out_right[i * 1024..(i + 1) * 1024]);
+            }
+            assert!(region.sample.is_playing());
+            assert_eq!(out_left, out_right);
+            sampletests::assert_frequency_result_sample(&out_left, 48000.0, *freq);
+        }
+    }
+
+    #[test]
+    fn missing_sample_reports_tried_path() {
+        let dir = std::env::temp_dir().join(format!("sonarigo-missing-sample-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sfz_file = dir.join("instrument.sfz");
+        std::fs::write(&sfz_file, "<region> sample=.\\Samples\\..\\samples\\c4.wav").unwrap();
+
+        let result = Engine::new(sfz_file.to_string_lossy().into_owned(), 48000.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(e) => assert_eq!(format!("{}", e),
+                                 format!("Sample file not found: {}", dir.join("samples/c4.wav").display())),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn engine_from_sfz_text() {
+        let source = Arc::new(source::MemorySource::new());
+        let engine = Engine::from_sfz_text("<region> sample=*sine key=60 <region> sample=*saw key=62".to_string(),
+                                           source.clone(),
+                                           48000.0,
+                                           loading::LoadOptions::default()).unwrap();
+        assert_eq!(engine.regions.len(), 2);
+
+        match Engine::from_sfz_text("<region> sample=.\\Samples\\c4.wav".to_string(),
+                                    source,
+                                    48000.0,
+                                    loading::LoadOptions::default()) {
+            Err(e) => assert_eq!(format!("{}", e), "Sample file not found: Samples/c4.wav"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn engine_from_sfz_text_undecodable_sample() {
+        let mut source = source::MemorySource::new();
+        source.insert("samples/c4.wav", b"not a sound file".to_vec());
+        match Engine::from_sfz_text("<region> sample=Samples\\C4.wav".to_string(),
+                                    Arc::new(source),
+                                    48000.0,
+                                    loading::LoadOptions::default()) {
+            Err(e) => assert_eq!(format!("{}", e), "Could not decode sample file samples/c4.wav"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn sample_files_deduplicated() {
+        let mut files = SampleFiles::default();
+        assert_eq!(files.add(PathBuf::from("samples/a.wav"), true), 0);
+        assert_eq!(files.add(PathBuf::from("samples/b.wav"), true), 1);
+        assert_eq!(files.add(PathBuf::from("samples/a.wav"), false), 0);
+        assert_eq!(files.add(PathBuf::from("samples/b.wav"), true), 1);
+
+        assert_eq!(files.files.len(), 2);
+        assert!(!files.files[0].streamable);
+        assert!(files.files[1].streamable);
+    }
+
+    fn make_sample_files(n: usize) -> Vec<SampleFile> {
+        (0..n).map(|i| SampleFile { path: PathBuf::from(format!("samples/{}.wav", i)), streamable: false }).collect()
+    }
+
+    #[test]
+    fn load_sample_files_in_order_with_progress() {
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let options = loading::LoadOptions {
+            progress: Some(Box::new(move |p| sink.lock().unwrap().push(p))),
+            ..Default::default()
+        };
+
+        let files = make_sample_files(20);
+        let loaded = Engine::load_sample_files(&files, &source::MemorySource::new(), &options, |file| Ok(file.path.clone())).unwrap();
+        assert_eq!(loaded, files.iter().map(|f| f.path.clone()).collect::<Vec<_>>());
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 20);
+        assert!(reports.iter().all(|p| p.files_total == 20));
+        assert_eq!(reports.iter().map(|p| p.files_done).max(), Some(20));
+    }
+
+    #[test]
+    fn load_sample_files_error() {
+        let files = make_sample_files(4);
+        let result = Engine::load_sample_files(&files, &source::MemorySource::new(), &loading::LoadOptions::default(), |file| {
+            match file.path == Path::new("samples/2.wav") {
+                true => Err(EngineError::DecodeError(PathBuf::from("samples/2.wav"))),
+                false => Ok(()),
+            }
+        });
+        match result {
+            Err(e) => assert_eq!(format!("{}", e), "Could not decode sample file samples/2.wav"),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn load_sample_files_cancelled() {
+        let options = loading::LoadOptions::default();
+        let files = make_sample_files(100);
+        let decoded = std::sync::atomic::AtomicUsize::new(0);
+        let result = Engine::load_sample_files(&files, &source::MemorySource::new(), &options, |_| {
+            if decoded.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 10 {
+                options.cancel.cancel();
+            }
+            Ok(())
+        });
+        match result {
+            Err(e) => assert_eq!(format!("{}", e), "Loading cancelled"),
+            _ => panic!("Not seen expected error"),
+        }
+        assert!(decoded.load(std::sync::atomic::Ordering::SeqCst) < 100);
+    }
+
+    #[test]
+    fn regions_share_sample_data() {
+        let sample = Arc::new(SampleData::new(vec![1.0; 96], 2, 1.0));
+        let regions = parse_sfz_text("<region> key=60 <region> key=62".to_string()).unwrap();
+
+        let engine = Engine::from_region_array(
+            regions.into_iter().map(|rd| (rd, sample.clone())).collect(),
+            1.0,
+        );
+
+        assert_eq!(Arc::strong_count(&sample), 3);
+        drop(engine);
+        assert_eq!(Arc::strong_count(&sample), 1);
+    }
+
+    #[test]
+    fn sample_layout() {
+        let info = SampleInfo { frames: 1000, channels: 2, samplerate: 48000.0, bits: Some(16), seekable: true };
+        let mut options = loading::LoadOptions::default();
+        assert_eq!(info.layout(48000.0, &options, None), SampleLayout::Float);
+        assert_eq!(info.layout(48000.0, &options, Some(100)), SampleLayout::Streamed(100));
+        assert_eq!(info.layout(48000.0, &options, Some(1000)), SampleLayout::Float);
+        let unseekable = SampleInfo { seekable: false, ..info };
+        assert_eq!(unseekable.layout(48000.0, &options, Some(100)), SampleLayout::Float);
+
+        options.packed_samples = true;
+        assert_eq!(info.layout(48000.0, &options, None), SampleLayout::Packed);
+        assert_eq!(info.layout(48000.0, &options, Some(100)), SampleLayout::Streamed(100));
+
+        options.resampling = Some(resampling::ResampleQuality::Fast);
+        assert_eq!(info.layout(48000.0, &options, None), SampleLayout::Packed);
+        assert_eq!(info.layout(44100.0, &options, Some(100)), SampleLayout::Resampled(resampling::ResampleQuality::Fast));
+    }
+
+    #[test]
+    fn sample_memory_bytes() {
+        let info = SampleInfo { frames: 1000, channels: 2, samplerate: 48000.0, bits: Some(24), seekable: true };
+        let padding = sample::PADDING_FRAMES;
+        assert_eq!(info.memory_bytes(SampleLayout::Float, 48000.0), (1000 + padding) * 2 * 4);
+        assert_eq!(info.memory_bytes(SampleLayout::Packed, 48000.0), (1000 + padding) * 2 * 3);
+        assert_eq!(info.memory_bytes(SampleLayout::Streamed(100), 48000.0), (100 + padding) * 2 * 4);
+        assert_eq!(info.memory_bytes(SampleLayout::Resampled(resampling::ResampleQuality::Fast), 96000.0),
+                   (2000 + padding) * 2 * 4);
+
+        let quad = SampleInfo { frames: 1000, channels: 4, samplerate: 48000.0, bits: Some(16), seekable: true };
+        assert_eq!(quad.memory_bytes(SampleLayout::Packed, 48000.0), (1000 + padding) * 2 * 4);
+
+        let data = SampleData::packed(vec![0.0; 2000], 2, 48000.0, Some(24));
+        assert_eq!(data.memory_bytes(), info.memory_bytes(SampleLayout::Packed, 48000.0));
+    }
+
+    #[test]
+    fn memory_budget() {
+        let files = vec![SampleFile { path: PathBuf::from("a.wav"), streamable: true },
+                         SampleFile { path: PathBuf::from("b.wav"), streamable: false }];
+        let infos = [SampleInfo { frames: 20_000_000, channels: 2, samplerate: 48000.0, bits: None, seekable: true }; 2];
+        let full = 2 * (20_000_000 + sample::PADDING_FRAMES) * 2 * 4;
+        let config = streaming::StreamingConfig::default();
+        let streamed = (20_000_000 + sample::PADDING_FRAMES) * 2 * 4
+            + (config.effective_preload_frames() + sample::PADDING_FRAMES) * 2 * 4
+            + config.buffer_bytes();
+        let options = loading::LoadOptions::default();
+        let check = |bytes, stream_if_exceeded| {
+            let budget = loading::MemoryBudget { bytes, stream_if_exceeded };
+            Engine::check_memory_budget(budget, &files, &infos, 48000.0, &options).map(|c| c.is_some())
+        };
+
+        assert!(matches!(check(full, false), Ok(false)));
+        assert!(matches!(check(full - 1, false), Err(EngineError::MemoryBudgetExceeded(n, b)) if n == full && b == full - 1));
+        assert!(matches!(check(full - 1, true), Ok(true)));
+        assert!(matches!(check(streamed, true), Ok(true)));
+        assert!(matches!(check(streamed - 1, true), Err(EngineError::MemoryBudgetExceeded(n, _)) if n == streamed));
+
+        match check(10, false) {
+            Err(e) => assert_eq!(format!("{}", e), format!("Instrument needs {} bytes of memory, the budget is 10 bytes", full)),
+            _ => panic!("Not seen expected error"),
+        }
+    }
+
+    #[test]
+    fn engine_memory_usage() {
+        let shared = Arc::new(SampleData::new(vec![1.0; 96], 2, 1.0));
+        let regions = parse_sfz_text("<region> key=60 <region> key=62 ampeg_release=2 <region> key=64".to_string()).unwrap();
+        let mut engine = Engine::from_region_array(vec![(regions[0].clone(), shared.clone()),
+                                                        (regions[1].clone(), shared.clone()),
+                                                        (regions[2].clone(), Arc::new(SampleData::new(vec![1.0; 10], 1, 1.0)))],
+                                                   100.0);
+        engine.files.push((PathBuf::from("a.wav"), shared.clone()));
+
+        let usage = engine.memory_usage();
+        assert_eq!(usage.sample_bytes, (48 + sample::PADDING_FRAMES) * 2 * 4 + (10 + sample::PADDING_FRAMES) * 4);
+        assert_eq!(usage.stream_buffer_bytes, 0);
+        assert_eq!(usage.files, [loading::FileMemory {
+            path: PathBuf::from("a.wav"),
+            bytes: shared.memory_bytes(),
+            streamed: false,
+        }]);
+        assert_eq!(usage.total_bytes(), usage.sample_bytes);
+    }
+
+    #[test]
+    fn engine_watched_files() {
+        let dir = std::env::temp_dir().join(format!("sonarigo-watched-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sfz_file = dir.join("generated.sfz");
+        std::fs::write(&sfz_file, "<region> sample=*sine").unwrap();
+        let engine = Engine::new(sfz_file.to_string_lossy().to_string(), 48000.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(engine.unwrap().watched_files(), vec![sfz_file.clone()]);
+
+        let mut engine = Engine::dummy(48000.0);
+        engine.files.push((PathBuf::from("samples/a.wav"), Arc::new(SampleData::new(vec![1.0; 2], 1, 1.0))));
+        assert!(engine.watched_files().is_empty());
+        engine.sfz_file = Some(sfz_file.clone());
+        assert_eq!(engine.watched_files(), [sfz_file, PathBuf::from("samples/a.wav")]);
+    }
+
+    #[test]
+    fn note_set() {
+        let mut set = NoteSet::default();
+        assert!(set.is_empty());
+        set.insert(0);
+        set.insert(64);
+        set.insert(127);
+        assert!(set.contains(0) && set.contains(64) && set.contains(127));
+        assert!(!set.contains(63));
+        set.remove(64);
+        assert_eq!(set.notes().collect::<Vec<Note>>(), [Note::LOWEST_NOTE, Note::HIGHEST_NOTE]);
+        set.remove(0);
+        set.remove(127);
+        assert!(set.is_empty());
+    }
+
+    fn voice_limit_engine(sfz: &str) -> Engine {
+        Engine::from_region_array(parse_sfz_text(sfz.to_string()).unwrap()
+                                  .into_iter()
+                                  .map(|rd| (rd, Arc::new(SampleData::new(vec![1.0; 8000], 2, 1000.0))))
+                                  .collect(),
+                                  1000.0)
+    }
+
+    fn play_note(engine: &mut Engine, note: Note, velocity: u8) {
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, note, Velocity::try_from(velocity).unwrap()));
+        let (mut out_left, mut out_right) = ([0.0; 4], [0.0; 4]);
+        engine.process(&mut out_left, &mut out_right);
+    }
+
+    fn active_voices(engine: &Engine) -> Vec<usize> {
+        engine.regions.iter().map(|r| r.sample.active_voice_count()).collect()
+    }
+
+    #[test]
+    fn region_polyphony() {
+        let mut engine = voice_limit_engine("<region> lokey=60 hikey=72 polyphony=2 <region> lokey=60 hikey=72");
+        for note in &[Note::C3, Note::D3, Note::E3] {
+            play_note(&mut engine, *note, 127);
+        }
+        assert_eq!(active_voices(&engine), [2, 3]);
+        assert!(!sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::D3));
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::E3));
+    }
+
+    #[test]
+    fn group_polyphony_limits_all_regions_of_the_group() {
+        let mut engine = voice_limit_engine("<group> group=1 polyphony=2 ampeg_release=1 off_mode=normal <region> key=60 <region> key=62 <region> key=64
+                                             <group> group=2 <region> key=60");
+        for note in &[Note::C3, Note::D3, Note::E3] {
+            play_note(&mut engine, *note, 127);
+        }
+        assert_eq!(active_voices(&engine), [0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn note_polyphony() {
+        let mut engine = voice_limit_engine("<group> ampeg_release=1 <region> lokey=60 hikey=62 note_polyphony=1 <region> key=60 group=2");
+        engine.midi_event(&MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(64).unwrap(), ControlValue::MAX));
+        for note in &[Note::C3, Note::C3, Note::CSharp3, Note::C3] {
+            play_note(&mut engine, *note, 127);
+            engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, *note, Velocity::MIN));
+        }
+        assert_eq!(active_voices(&engine), [2, 3]);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::CSharp3));
+    }
+
+    #[test]
+    fn engine_max_voices() {
+        let mut engine = voice_limit_engine("<region>");
+        assert_eq!(engine.max_voices, voices::DEFAULT_MAX_VOICES);
+        engine.set_max_voices(3);
+        for note in &[Note::C3, Note::D3, Note::E3, Note::F3, Note::G3] {
+            play_note(&mut engine, *note, 127);
+        }
+        assert_eq!(active_voices(&engine), [3]);
+        assert!(!sample::tests::is_playing_note(&engine.regions[0].sample, Note::D3));
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::E3));
+    }
+
+    #[test]
+    fn voice_limits_only_enforced_when_voices_start() {
+        let mut engine = voice_limit_engine("<region>");
+        for note in &[Note::C3, Note::D3, Note::E3] {
+            play_note(&mut engine, *note, 127);
+        }
+        engine.set_max_voices(1);
+        engine.midi_event(&MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(1).unwrap(), ControlValue::MAX));
+        engine.midi_event(&MidiMessage::NoteOff(Channel::Ch1, Note::G3, Velocity::MIN));
+        assert_eq!(active_voices(&engine), [3]);
+
+        play_note(&mut engine, Note::F3, 127);
+        assert_eq!(active_voices(&engine), [1]);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::F3));
+    }
+
+    #[test]
+    fn voice_counter_scopes() {
+        let engine = voice_limit_engine("<region> <group> group=3 <region> <region> <group> <region> <region> group=3");
+        let counter = &engine.voice_counter;
+        assert_eq!(counter.scopes, [0, 1, 1, 2, 1]);
+        assert_eq!(counter.groups, [0, 1, 1, 0, 1]);
+        assert_eq!((counter.scope_voices.len(), counter.note_voices.len()), (3, 2));
+    }
+
+    #[test]
+    fn voice_stealing_modes() {
+        let mut engine = voice_limit_engine("<region>");
+        engine.set_max_voices(3);
+        engine.set_voice_stealing(voices::VoiceStealing::Quietest);
+        for (note, velocity) in &[(Note::C3, 100), (Note::D3, 20), (Note::E3, 80), (Note::F3, 90)] {
+            play_note(&mut engine, *note, *velocity);
+        }
+        assert!(!sample::tests::is_playing_note(&engine.regions[0].sample, Note::D3));
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        let mut engine = voice_limit_engine("<region>");
+        engine.set_max_voices(3);
+        engine.set_voice_stealing(voices::VoiceStealing::SameNote);
+        for note in &[Note::C3, Note::D3, Note::E3, Note::D3] {
+            play_note(&mut engine, *note, 127);
+        }
+        assert_eq!(active_voices(&engine), [3]);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::D3));
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::E3));
+    }
+
+    #[test]
+    fn stolen_voice_is_declicked() {
+        let mut engine = voice_limit_engine("<region>");
+        engine.set_max_voices(1);
+        play_note(&mut engine, Note::C3, 127);
+        engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        assert_eq!(active_voices(&engine), [1]);
+
+        let (mut out_left, mut out_right) = ([0.0; 8], [0.0; 8]);
+        engine.process(&mut out_left, &mut out_right);
+        let expected = [2.0, 1.8, 1.6, 1.4, 1.2, 1.0, 1.0, 1.0];
+        for (out, expected) in out_left.iter().zip(expected.iter()) {
+            assert!((out - expected).abs() < 1e-6, "{:?}", out_left);
+        }
+        assert!(!sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+    }
+
+    #[test]
+    fn off_modes() {
+        let choked_engine = |off_mode: &str| {
+            let mut engine = voice_limit_engine(&format!("<region> key=62 group=2 off_by=1 ampeg_release=1 {}
+                                                          <region> key=60 group=1", off_mode));
+            play_note(&mut engine, Note::D3, 127);
+            play_note(&mut engine, Note::C3, 127);
+            engine
+        };
+        let mut out = ([0.0; 12], [0.0; 12]);
+
+        let mut engine = choked_engine("");
+        assert_eq!(active_voices(&engine), [0, 1]);
+        assert!(engine.regions[0].sample.is_playing());
+        engine.process(&mut out.0, &mut out.1);
+        assert!(!engine.regions[0].sample.is_playing());
+
+        let mut engine = choked_engine("off_mode=time off_time=0.02");
+        assert_eq!(active_voices(&engine), [0, 1]);
+        engine.process(&mut out.0, &mut out.1);
+        assert!(engine.regions[0].sample.is_playing());
+        engine.process(&mut out.0, &mut out.1);
+        assert!(!engine.regions[0].sample.is_playing());
+
+        let mut engine = choked_engine("off_mode=normal");
+        assert_eq!(active_voices(&engine), [1, 1]);
+        assert!(sample::tests::is_releasing_note(&engine.regions[0].sample, Note::D3));
+        engine.process(&mut out.0, &mut out.1);
+        engine.process(&mut out.0, &mut out.1);
+        assert!(engine.regions[0].sample.is_playing());
+    }
+
+    #[test]
+    fn note_selfmask() {
+        let mut engine = voice_limit_engine("<region> key=60 ampeg_release=1");
+        play_note(&mut engine, Note::C3, 100);
+        play_note(&mut engine, Note::C3, 50);
+        assert!(!sample::tests::is_releasing_note(&engine.regions[0].sample, Note::C3));
+        play_note(&mut engine, Note::C3, 100);
+        assert!(sample::tests::is_releasing_note(&engine.regions[0].sample, Note::C3));
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        // The velocities are compared, not the gains.
+        let mut engine = voice_limit_engine("<region> key=60 ampeg_release=1 amp_veltrack=-100");
+        play_note(&mut engine, Note::C3, 100);
+        play_note(&mut engine, Note::C3, 50);
+        assert!(!sample::tests::is_releasing_note(&engine.regions[0].sample, Note::C3));
+
+        let mut engine = voice_limit_engine("<region> key=60 ampeg_release=1 note_selfmask=off");
+        play_note(&mut engine, Note::C3, 100);
+        play_note(&mut engine, Note::C3, 50);
+        assert!(sample::tests::is_releasing_note(&engine.regions[0].sample, Note::C3));
+        assert_eq!(active_voices(&engine), [2]);
+    }
+
+    #[test]
+    fn full_event_queue_keeps_event_order() {
+        let mut engine = voice_limit_engine("<region> ampeg_release=1");
+        engine.queue_midi_event(0, &MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+        for _ in 2..engine::MAX_QUEUED_EVENTS {
+            engine.queue_midi_event(0, &MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(1).unwrap(), ControlValue::MAX));
+        }
+        engine.queue_midi_event(8, &MidiMessage::NoteOn(Channel::Ch1, Note::D3, Velocity::MAX));
+        engine.queue_midi_event(0, &MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MIN));
+        assert!(sample::tests::is_releasing_note(&engine.regions[0].sample, Note::C3));
+        assert!(!sample::tests::is_playing_note(&engine.regions[0].sample, Note::D3));
+
+        let (mut out_left, mut out_right) = ([0.0; 16], [0.0; 16]);
+        engine.process(&mut out_left, &mut out_right);
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::D3));
+    }
+
+    #[test]
+    fn queued_events_are_sample_accurate() {
+        let mut engine = voice_limit_engine("<region>");
+        engine.queue_midi_event(20, &MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MIN));
+        engine.queue_midi_event(5, &MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+
+        let (mut out_left, mut out_right) = ([0.0; 16], [0.0; 16]);
+        engine.process(&mut out_left, &mut out_right);
+        assert!(out_left[..5].iter().all(|s| *s == 0.0));
+        assert!(out_left[5..].iter().all(|s| *s == 1.0));
+        assert!(sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+
+        let (mut out_left, mut out_right) = ([0.0; 16], [0.0; 16]);
+        engine.process(&mut out_left, &mut out_right);
+        assert!(out_left[..4].iter().all(|s| *s == 1.0));
+        assert!(out_left[4] < 1.0);
+        assert!(!sample::tests::is_playing_note(&engine.regions[0].sample, Note::C3));
+    }
+
+    #[test]
+    fn process_buffers_of_any_length() {
+        let sfz = "<region> ampeg_attack=0.05 ampeg_decay=0.1 ampeg_sustain=50 ampeg_release=0.2";
+        let render = |buffer_length: usize| {
+            let mut engine = Engine::from_region_array(parse_sfz_text(sfz.to_string()).unwrap()
+                                                       .into_iter()
+                                                       .map(|rd| (rd, Arc::new(SampleData::new(vec![1.0; 2000], 2, 1000.0))))
+                                                       .collect(),
+                                                       1000.0);
+            let mut out_left = vec![0.0; 1200];
+            let mut out_right = vec![0.0; 1200];
+            engine.queue_midi_event(10, &MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
+            engine.queue_midi_event(600, &MidiMessage::NoteOff(Channel::Ch1, Note::C3, Velocity::MIN));
+            for (l, r) in Iterator::zip(out_left.chunks_mut(buffer_length), out_right.chunks_mut(buffer_length)) {
+                engine.process(l, r);
+            }
+            out_left
+        };
+        let reference = render(16);
+        assert!(reference.iter().any(|s| *s > 0.4));
+        assert_eq!(render(1200), reference);
+        assert_eq!(render(100), reference);
+    }
+
+    #[test]
+    fn midi_event_and_process_do_not_allocate() {
+        let sfz = "<group> group=1 <region> key=60 <region> key=62 loop_mode=loop_continuous
+                   <group> group=2 off_by=1 <region> key=64
+                   <group> <region> key=65 trigger=release <region> key=67 trigger=first
+                   <region> key=67 trigger=legato <region> key=69 lorand=0 hirand=0.5
+                   <region> key=69 lorand=0.5 hirand=1 <region> on_locc1=10 on_hicc1=127
+                   <group> group=3 polyphony=2 note_polyphony=1 <region> key=72 <region> key=60";
+        let data = sampletests::make_test_sample_data(2000, 48000.0, 440.0);
+        let mut engine = Engine::from_region_array(parse_sfz_text(sfz.to_string()).unwrap()
+                                                   .into_iter()
+                                                   .map(|rd| (rd, Arc::new(SampleData::new(data.clone(), 2, 48000.0))))
+                                                   .collect(),
+                                                   48000.0);
+        engine.set_max_voices(12);
+        let (mut out_left, mut out_right) = (vec![0.0; 64], vec![0.0; 64]);
+
+        let allocations = crate::allocations::count_allocations(|| {
+            for i in 0..sample::MAX_VOICES + 8 {
+                for key in &[60u8, 62, 64, 65, 67, 69, 72] {
+                    let note = Note::try_from(*key).unwrap();
+                    engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, note, Velocity::MAX));
+                    engine.process(&mut out_left, &mut out_right);
+                    if i % 2 == 0 {
+                        engine.midi_event(&MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(64).unwrap(), ControlValue::MAX));
+                    }
+                    engine.queue_midi_event(i % 70, &MidiMessage::NoteOff(Channel::Ch1, note, Velocity::MIN));
+                }
+                engine.midi_event(&MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(64).unwrap(), ControlValue::MIN));
+                engine.midi_event(&MidiMessage::ControlChange(Channel::Ch1, ControlNumber::try_from(1).unwrap(), ControlValue::try_from(20).unwrap()));
+                engine.process(&mut out_left, &mut out_right);
+            }
+            engine.fadeout();
+            engine.process(&mut out_left, &mut out_right);
+        });
+        assert_eq!(allocations, 0);
+        assert!(out_left.iter().any(|s| *s != 0.0));
+    }
+
     #[test]
     fn test_unreasonable_process_calls_zero_length_buffer() {
         let sample = vec![0.1, -0.1];
         let mut engine =
-            Engine::from_region_array(vec![(RegionData::default(), sample, 1.0)], 1.0, 16);
+            Engine::from_region_array(vec![(RegionData::default(), Arc::new(SampleData::new(sample, 2, 1.0)))], 1.0);
 
         let mut out_left = Vec::new();
         let mut out_right = Vec::new();
@@ -3100,7 +4709,7 @@ mod tests {
         let mut rd = RegionData::default();
         rd.ampeg.set_release(0.2).unwrap();
 
-        let mut engine = Engine::from_region_array(vec![(rd, sample, 100.0)], 100.0, 24);
+        let mut engine = Engine::from_region_array(vec![(rd, Arc::new(SampleData::new(sample, 2, 100.0)))], 100.0);
 
         engine.midi_event(&MidiMessage::NoteOn(Channel::Ch1, Note::C3, Velocity::MAX));
 
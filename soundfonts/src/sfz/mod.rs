@@ -1,2 +1,3 @@
 mod parser;
 pub mod engine;
+pub(crate) mod paths;
@@ -6,7 +6,9 @@ use std::num::{ParseIntError, ParseFloatError};
 use std::str::Chars;
 
 use super::engine;
+use crate::envelopes;
 use crate::errors::*;
+use crate::sample;
 
 #[derive(Debug)]
 pub enum ParserError {
@@ -200,46 +202,77 @@ fn parse_opcode(chars: &mut Chars) -> Result<(Option<(String, String)>, NextChar
 
 fn take_opcode(region: &mut engine::RegionData, key: &str, value: &str) -> Result<(), ParserError> {
     match key {
-        "lokey" => region.key_range.set_lo(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
-        "hikey" => region.key_range.set_hi(parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?).map_err(|re| ParserError::RangeError(re)),
-        "pitch_keycenter" => region.set_pitch_keycenter(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
+        "lokey" => region.key_range.set_lo(parse_key(value).map_err(ParserError::NoteParseError)?).map_err(ParserError::RangeError),
+        "hikey" => region.key_range.set_hi(parse_key(value).map_err(ParserError::NoteParseError)?).map_err(ParserError::RangeError),
+        "pitch_keycenter" => region.set_pitch_keycenter(value.parse::<u32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
         "key" => {
-            let key = parse_key(value).map_err(|ne| ParserError::NoteParseError(ne))?;
+            let key = parse_key(value).map_err(ParserError::NoteParseError)?;
             match key {
                 k if k < 0 => Err(RangeError::out_of_range("key", 0, 127, key)),
                 k => region.key_range.set_hi(k).and_then(|_| region.key_range.set_lo(k)).and_then(|_| region.set_pitch_keycenter((k as u8).into()))
             }
-        }.map_err(|re| ParserError::RangeError(re)),
-        "lovel" => region.vel_range.set_lo(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "hivel" => region.vel_range.set_hi(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "lorand" => region.random_range.set_lo(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "hirand" => region.random_range.set_hi(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "tune" => region.set_tune(value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "volume" => region.set_volume(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "rt_decay" => region.set_rt_decay(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "pitch_keytrack" => region.set_pitch_keytrack(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "amp_veltrack" => region.set_amp_veltrack(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "ampeg_attack" => region.ampeg.set_attack(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "ampeg_hold" => region.ampeg.set_hold(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "ampeg_decay" => region.ampeg.set_decay(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "ampeg_sustain" => region.ampeg.set_sustain(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "ampeg_release" => region.ampeg.set_release(value.parse::<f32>().map_err(|pe| ParserError::ParseFloatError(pe))?).map_err(|re| ParserError::RangeError(re)),
-        "group" => { region.set_group(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?); Ok(()) },
-        "off_by" => { region.set_off_by(value.parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?); Ok(()) },
+        }.map_err(ParserError::RangeError),
+        "lovel" => region.vel_range.set_lo(value.parse::<i32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+        "hivel" => region.vel_range.set_hi(value.parse::<i32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+        "lorand" => region.random_range.set_lo(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "hirand" => region.random_range.set_hi(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "tune" => region.set_tune(value.parse::<i32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+        "volume" => region.set_volume(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "pan" => region.set_pan(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "width" => region.set_width(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "position" => region.set_position(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "rt_decay" => region.set_rt_decay(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "pitch_keytrack" => region.set_pitch_keytrack(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "amp_veltrack" => region.set_amp_veltrack(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "amp_keytrack" => region.set_amp_keytrack(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "amp_keycenter" => region.set_amp_keycenter(parse_key(value).map_err(ParserError::NoteParseError)?).map_err(ParserError::RangeError),
+        "ampeg_delay" => region.ampeg.set_delay(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_start" => region.ampeg.set_start(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_attack" => region.ampeg.set_attack(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_hold" => region.ampeg.set_hold(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_decay" => region.ampeg.set_decay(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_sustain" => region.ampeg.set_sustain(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_release" => region.ampeg.set_release(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_attack_shape" => { region.ampeg.set_attack_shape(parse_shape(value)?); Ok(()) },
+        "ampeg_decay_shape" => { region.ampeg.set_decay_shape(parse_shape(value)?); Ok(()) },
+        "ampeg_release_shape" => { region.ampeg.set_release_shape(parse_shape(value)?); Ok(()) },
+        "ampeg_vel2delay" => region.ampeg.set_vel2delay(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_vel2attack" => region.ampeg.set_vel2attack(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_vel2hold" => region.ampeg.set_vel2hold(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_vel2decay" => region.ampeg.set_vel2decay(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_vel2sustain" => region.ampeg.set_vel2sustain(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "ampeg_vel2release" => region.ampeg.set_vel2release(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
+        "group" => { region.set_group(value.parse::<u32>().map_err(ParserError::ParseIntError)?); Ok(()) },
+        "sample_quality" => region.set_sample_quality(value.parse::<u32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+        "off_by" => { region.set_off_by(value.parse::<u32>().map_err(ParserError::ParseIntError)?); Ok(()) },
+        "polyphony" => region.set_polyphony(value.parse::<u32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+        "note_polyphony" => region.set_note_polyphony(value.parse::<u32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+        "note_selfmask" => { region.set_note_selfmask(parse_on_off(value)?); Ok(()) },
+        "off_mode" => { region.set_off_mode(parse_off_mode(value)?); Ok(()) },
+        "off_time" => region.set_off_time(value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
         "sample" => { region.set_sample(value); Ok(()) },
         "trigger" => { region.set_trigger(parse_trigger(value)?); Ok(()) },
+        "loop_mode" => { region.set_loop_mode(parse_loop_mode(value)?); Ok(()) },
+        "count" => region.set_count(value.parse::<u32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+        "direction" => { region.set_direction(parse_direction(value)?); Ok(()) },
+        "offset" => { region.set_offset(value.parse::<u32>().map_err(ParserError::ParseIntError)?); Ok(()) },
+        "loop_start" | "loopstart" => region.set_loop_start(value.parse::<u32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+        "loop_end" | "loopend" => region.set_loop_end(value.parse::<u32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
         s => match s.find("cc") {
             Some(n) => {
                 let (key_cc, ns) = s.split_at(n);
-                let cc_num = ns.get(2..).unwrap().parse::<u32>().map_err(|pe| ParserError::ParseIntError(pe))?;
+                let cc_num = ns.get(2..).unwrap().parse::<u32>().map_err(ParserError::ParseIntError)?;
                 if cc_num > 127 {
                     Err(ParserError::RangeError(RangeError::out_of_range("cc number", 0, 127, cc_num)))
                 } else {
-                    let value = value.parse::<i32>().map_err(|pe| ParserError::ParseIntError(pe))?;
-
-                    match key_cc {
-                        "on_lo" => region.push_on_lo_cc(cc_num, value).map_err(|re| ParserError::RangeError(re)),
-                        "on_hi" => region.push_on_hi_cc(cc_num, value).map_err(|re| ParserError::RangeError(re)),
+                    let ampeg_stage = key_cc.strip_prefix("ampeg_")
+                        .and_then(|k| k.strip_suffix("_on"))
+                        .and_then(envelopes::Stage::from_name);
+
+                    match (key_cc, ampeg_stage) {
+                        ("on_lo", _) => region.push_on_lo_cc(cc_num, value.parse::<i32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+                        ("on_hi", _) => region.push_on_hi_cc(cc_num, value.parse::<i32>().map_err(ParserError::ParseIntError)?).map_err(ParserError::RangeError),
+                        (_, Some(stage)) => region.ampeg.set_oncc(cc_num, stage, value.parse::<f32>().map_err(ParserError::ParseFloatError)?).map_err(ParserError::RangeError),
                         _ => Err(ParserError::KeyError(key_cc.to_string()))
                     }
                 }
@@ -260,6 +293,54 @@ fn parse_trigger(s: &str) -> Result<engine::Trigger, ParserError> {
         }
 }
 
+fn parse_loop_mode(s: &str) -> Result<sample::LoopMode, ParserError> {
+    match s {
+        "no_loop" => Ok(sample::LoopMode::NoLoop),
+        "one_shot" => Ok(sample::LoopMode::OneShot),
+        "loop_continuous" => Ok(sample::LoopMode::LoopContinuous),
+        "loop_sustain" => Ok(sample::LoopMode::LoopSustain),
+        _ => Err(ParserError::KeyError(s.to_string()))
+    }
+}
+
+fn parse_direction(s: &str) -> Result<sample::Direction, ParserError> {
+    match s {
+        "forward" => Ok(sample::Direction::Forward),
+        "reverse" => Ok(sample::Direction::Reverse),
+        _ => Err(ParserError::KeyError(s.to_string()))
+    }
+}
+
+fn parse_off_mode(s: &str) -> Result<engine::OffMode, ParserError> {
+    match s {
+        "fast" => Ok(engine::OffMode::Fast),
+        "normal" => Ok(engine::OffMode::Normal),
+        "time" => Ok(engine::OffMode::Time),
+        _ => Err(ParserError::KeyError(s.to_string()))
+    }
+}
+
+// Shapes are given by name or as a curvature, see `envelopes::Shape`.
+fn parse_shape(s: &str) -> Result<envelopes::Shape, ParserError> {
+    match s {
+        "linear" => Ok(envelopes::Shape::LINEAR),
+        "exponential" => Ok(envelopes::Shape::Exponential),
+        "logarithmic" => Ok(envelopes::Shape::Curve(8.0)),
+        _ => match s.parse::<f32>() {
+            Ok(curvature) if curvature.is_finite() => Ok(envelopes::Shape::Curve(curvature)),
+            _ => Err(ParserError::KeyError(s.to_string())),
+        }
+    }
+}
+
+fn parse_on_off(s: &str) -> Result<bool, ParserError> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(ParserError::KeyError(s.to_string()))
+    }
+}
+
 
 fn parse_region(chars: &mut Chars, mut region: engine::RegionData) -> Result<(engine::RegionData, NextChar), ParserError> {
 
@@ -306,7 +387,8 @@ pub(super) fn parse_sfz_text(text: String) -> Result<Vec<engine::RegionData>, Pa
                 nc
             }
             "region" => {
-                let (reg, nc) = parse_region(&mut chars, current_group.clone())?;
+                let (mut reg, nc) = parse_region(&mut chars, current_group.clone())?;
+                reg.resolve_loop_mode();
                 regions.push(reg);
                 nc
             }
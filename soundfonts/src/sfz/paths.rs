@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Resolves the `sample` opcode relative to `base`. Backslashes are taken as separators and `.`
+// and `..` are normalised. If the path does not exist as written, each component is looked up
+// case-insensitively. The error contains the normalised path that was tried.
+pub(crate) fn resolve_sample_path(base: &Path, sample: &str) -> Result<PathBuf, PathBuf> {
+    let sample = sample.replace('\\', "/");
+    let (root, components) = match sample.starts_with('/') {
+        true => (PathBuf::from("/"), normalise(&sample)),
+        false => (base.to_path_buf(), normalise(&sample)),
+    };
+
+    let exact: PathBuf = components.iter().fold(root.clone(), |path, c| path.join(c));
+    if exact.exists() {
+        return Ok(exact);
+    }
+
+    components.iter()
+        .try_fold(root, |path, component| {
+            let candidate = path.join(component);
+            if component == ".." || candidate.exists() {
+                return Some(candidate);
+            }
+            find_case_insensitive(&path, component)
+        })
+        .ok_or(exact)
+}
+
+// The known names of a source without a directory tree to walk, which are normalised relative
+// paths, by their lowercase spelling.
+#[derive(Default)]
+pub(crate) struct NameIndex {
+    names: HashMap<String, Vec<PathBuf>>,
+}
+
+impl NameIndex {
+    pub(crate) fn new<'a, I: Iterator<Item = &'a PathBuf>>(names: I) -> NameIndex {
+        let mut index = NameIndex::default();
+        names.for_each(|name| index.insert(name));
+        index
+    }
+
+    pub(crate) fn insert(&mut self, name: &Path) {
+        let matches = self.names.entry(name.to_string_lossy().to_lowercase()).or_default();
+        if let Err(i) = matches.binary_search_by(|m| m.as_path().cmp(name)) {
+            matches.insert(i, name.to_path_buf());
+        }
+    }
+
+    // Resolves `sample` relative to `base`, preferring the name spelled as given.
+    pub(crate) fn resolve(&self, base: &Path, sample: &str) -> Result<PathBuf, PathBuf> {
+        let tried = normalise_path(base, sample);
+        match self.names.get(&tried.to_string_lossy().to_lowercase()) {
+            Some(matches) if matches.contains(&tried) => Ok(tried),
+            Some(matches) => Ok(matches[0].clone()),
+            None => Err(tried),
+        }
+    }
+}
+
+pub(crate) fn normalise_path(base: &Path, sample: &str) -> PathBuf {
+    normalise(&base.join(sample.replace('\\', "/")).to_string_lossy()).iter().collect()
+}
+
+fn normalise(path: &str) -> Vec<String> {
+    let mut components: Vec<String> = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(c) => components.push(c.to_string_lossy().into_owned()),
+            Component::ParentDir => match components.last() {
+                Some(last) if last != ".." => {
+                    components.pop();
+                }
+                _ => components.push("..".to_string()),
+            },
+            _ => {}
+        }
+    }
+    components
+}
+
+fn find_case_insensitive(dir: &Path, component: &str) -> Option<PathBuf> {
+    let component = component.to_lowercase();
+    let mut matches: Vec<PathBuf> = fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().to_lowercase() == component)
+        .map(|entry| entry.path())
+        .collect();
+    matches.sort();
+    matches.into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str, files: &[&str]) -> TestDir {
+            let dir = std::env::temp_dir().join(format!("sonarigo-{}-{}", name, std::process::id()));
+            for file in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, b"").unwrap();
+            }
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn normalise_components() {
+        assert_eq!(normalise("samples/./a/../b.wav"), ["samples", "b.wav"]);
+        assert_eq!(normalise("../../samples//b.wav"), ["..", "..", "samples", "b.wav"]);
+        assert_eq!(normalise("./a/../../b.wav"), ["..", "b.wav"]);
+    }
+
+    #[test]
+    fn resolve_among_names() {
+        let names = [PathBuf::from("piano/samples/c4.wav"), PathBuf::from("piano/Samples/C4.wav")];
+        let index = NameIndex::new(names.iter());
+        assert_eq!(index.resolve(Path::new("piano"), "samples/c4.wav"), Ok(names[0].clone()));
+        assert_eq!(index.resolve(Path::new("piano"), "SAMPLES\\C4.WAV"), Ok(names[1].clone()));
+        assert_eq!(index.resolve(Path::new("piano/x"), "../Samples/./C4.wav"), Ok(names[1].clone()));
+        assert_eq!(index.resolve(Path::new("piano"), "c4.wav"), Err(PathBuf::from("piano/c4.wav")));
+    }
+
+    #[test]
+    fn resolve_exact_match() {
+        let dir = TestDir::new("exact", &["samples/C4.wav"]);
+        assert_eq!(resolve_sample_path(&dir.0, "samples/C4.wav"), Ok(dir.0.join("samples/C4.wav")));
+        assert_eq!(resolve_sample_path(&dir.0, "samples\\C4.wav"), Ok(dir.0.join("samples/C4.wav")));
+    }
+
+    #[test]
+    fn resolve_case_insensitive() {
+        let dir = TestDir::new("case", &["samples/C4.wav", "samples/d4.wav"]);
+        assert_eq!(resolve_sample_path(&dir.0, "Samples/c4.WAV"), Ok(dir.0.join("samples/C4.wav")));
+        assert_eq!(resolve_sample_path(&dir.0, "SAMPLES\\D4.wav"), Ok(dir.0.join("samples/d4.wav")));
+    }
+
+    #[test]
+    fn resolve_normalised() {
+        let dir = TestDir::new("normalised", &["instrument/samples/C4.wav", "shared/E4.wav"]);
+        let base = dir.0.join("instrument");
+        assert_eq!(resolve_sample_path(&base, "./samples/../samples/c4.wav"), Ok(base.join("samples/C4.wav")));
+        assert_eq!(resolve_sample_path(&base, "..\\Shared\\e4.wav"), Ok(base.join("../shared/E4.wav")));
+    }
+
+    #[test]
+    fn resolve_not_found_reports_tried_path() {
+        let dir = TestDir::new("missing", &["samples/C4.wav"]);
+        assert_eq!(resolve_sample_path(&dir.0, "./Samples/../samples/C5.wav"), Err(dir.0.join("samples/C5.wav")));
+        assert_eq!(resolve_sample_path(&dir.0, "other/C4.wav"), Err(dir.0.join("other/C4.wav")));
+    }
+}
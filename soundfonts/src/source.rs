@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::sfz::paths;
+
+pub trait SampleReader: io::Read + io::Seek + Send {}
+
+impl<T: io::Read + io::Seek + Send> SampleReader for T {}
+
+// Where the sample files of an instrument come from.
+pub trait SampleSource: Send + Sync {
+    // Maps the `sample` opcode of a region to the path of a sample file. The error contains the
+    // normalised path that was tried.
+    fn resolve(&self, sample: &str) -> Result<PathBuf, PathBuf>;
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SampleReader>>;
+
+    // The size of the sample file in bytes, used for progress reporting.
+    fn size(&self, path: &Path) -> u64;
+
+    // Whether the file can be read from the middle without reading everything before it. Only
+    // such files are streamed.
+    fn seekable(&self, _path: &Path) -> bool {
+        true
+    }
+}
+
+pub struct FileSystemSource {
+    base: PathBuf,
+}
+
+impl FileSystemSource {
+    pub fn new<P: Into<PathBuf>>(base: P) -> FileSystemSource {
+        FileSystemSource { base: base.into() }
+    }
+}
+
+impl SampleSource for FileSystemSource {
+    fn resolve(&self, sample: &str) -> Result<PathBuf, PathBuf> {
+        paths::resolve_sample_path(&self.base, sample)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SampleReader>> {
+        Ok(Box::new(io::BufReader::new(fs::File::open(path)?)))
+    }
+
+    fn size(&self, path: &Path) -> u64 {
+        fs::metadata(path).map_or(0, |m| m.len())
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySource {
+    files: HashMap<PathBuf, Arc<[u8]>>,
+    index: paths::NameIndex,
+}
+
+impl MemorySource {
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    pub fn insert<P: AsRef<Path>>(&mut self, path: P, data: Vec<u8>) {
+        let path = paths::normalise_path(Path::new(""), &path.as_ref().to_string_lossy());
+        self.index.insert(&path);
+        self.files.insert(path, data.into());
+    }
+}
+
+impl SampleSource for MemorySource {
+    fn resolve(&self, sample: &str) -> Result<PathBuf, PathBuf> {
+        self.index.resolve(Path::new(""), sample)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SampleReader>> {
+        match self.files.get(path) {
+            Some(data) => Ok(Box::new(io::Cursor::new(data.clone()))),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, path.display().to_string())),
+        }
+    }
+
+    fn size(&self, path: &Path) -> u64 {
+        self.files.get(path).map_or(0, |data| data.len() as u64)
+    }
+}
+
+// Reads sample files out of a zip archive. Samples are resolved relative to `base` inside the
+// archive, usually the directory of the SFZ file. Stored entries are read directly from the
+// archive file, compressed entries are inflated as far as they are read.
+pub struct ZipSource {
+    path: PathBuf,
+    archive: Mutex<zip::ZipArchive<fs::File>>,
+    names: HashMap<PathBuf, String>,
+    index: paths::NameIndex,
+    base: PathBuf,
+}
+
+// Where the data of an entry lies in the archive file.
+struct ZipEntry {
+    start: u64,
+    compressed_size: u64,
+    size: u64,
+    deflated: bool,
+}
+
+impl ZipSource {
+    pub fn new<P: AsRef<Path>, B: Into<PathBuf>>(archive: P, base: B) -> io::Result<ZipSource> {
+        let path = archive.as_ref().to_path_buf();
+        let archive = zip::ZipArchive::new(fs::File::open(archive)?)?;
+        let names: HashMap<PathBuf, String> = archive.file_names()
+            .map(|name| (paths::normalise_path(Path::new(""), name), name.to_string()))
+            .collect();
+        let index = paths::NameIndex::new(names.keys());
+        Ok(ZipSource {
+            path,
+            archive: Mutex::new(archive),
+            names,
+            index,
+            base: base.into(),
+        })
+    }
+
+    pub fn read_to_string<P: AsRef<Path>>(&self, path: P) -> io::Result<String> {
+        let mut text = String::new();
+        self.open(path.as_ref())?.read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    fn entry_name(&self, path: &Path) -> io::Result<&str> {
+        self.names.get(path)
+            .map(|name| name.as_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.display().to_string()))
+    }
+
+    fn entry(&self, path: &Path) -> io::Result<ZipEntry> {
+        let name = self.entry_name(path)?;
+        let mut archive = self.archive.lock().map_err(|_| io::Error::other("zip archive lock poisoned"))?;
+        let entry = archive.by_name(name)?;
+        let deflated = match entry.compression() {
+            zip::CompressionMethod::Stored => false,
+            zip::CompressionMethod::Deflated => true,
+            method => return Err(io::Error::other(format!("{}: unsupported compression {}", path.display(), method))),
+        };
+        Ok(ZipEntry {
+            start: entry.data_start(),
+            compressed_size: entry.compressed_size(),
+            size: entry.size(),
+            deflated,
+        })
+    }
+}
+
+impl SampleSource for ZipSource {
+    fn resolve(&self, sample: &str) -> Result<PathBuf, PathBuf> {
+        self.index.resolve(&self.base, sample)
+    }
+
+    // Every reader opens the archive file on its own, so that they can be read in parallel.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn SampleReader>> {
+        let entry = self.entry(path)?;
+        let mut file = fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(entry.start))?;
+        let file = io::BufReader::new(file);
+        match entry.deflated {
+            true => Ok(Box::new(InflatedEntry {
+                decoder: flate2::read::DeflateDecoder::new(file.take(entry.compressed_size)),
+                data: Vec::new(),
+                size: entry.size,
+                pos: 0,
+            })),
+            false => Ok(Box::new(StoredEntry {
+                file,
+                start: entry.start,
+                size: entry.size,
+                pos: 0,
+            })),
+        }
+    }
+
+    fn size(&self, path: &Path) -> u64 {
+        self.entry(path).map_or(0, |entry| entry.size)
+    }
+
+    fn seekable(&self, path: &Path) -> bool {
+        self.entry(path).is_ok_and(|entry| !entry.deflated)
+    }
+}
+
+fn seek_position(pos: SeekFrom, current: u64, size: u64) -> io::Result<u64> {
+    let (base, offset) = match pos {
+        SeekFrom::Start(offset) => return Ok(offset),
+        SeekFrom::Current(offset) => (current, offset),
+        SeekFrom::End(offset) => (size, offset),
+    };
+    base.checked_add_signed(offset)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+}
+
+// An uncompressed entry, read as a window into the archive file.
+struct StoredEntry {
+    file: io::BufReader<fs::File>,
+    start: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl Read for StoredEntry {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.size.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        let read = self.file.read(&mut buf[..left])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for StoredEntry {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = seek_position(pos, self.pos, self.size)?;
+        self.file.seek(SeekFrom::Start(self.start + pos))?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+// A compressed entry. Deflate streams can only be decoded forward, so the inflated data is kept
+// to seek back.
+struct InflatedEntry {
+    decoder: flate2::read::DeflateDecoder<io::Take<io::BufReader<fs::File>>>,
+    data: Vec<u8>,
+    size: u64,
+    pos: u64,
+}
+
+impl Read for InflatedEntry {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = (self.pos + buf.len() as u64).min(self.size);
+        if end > self.data.len() as u64 {
+            let wanted = end - self.data.len() as u64;
+            (&mut self.decoder).take(wanted).read_to_end(&mut self.data)?;
+        }
+        let start = (self.pos as usize).min(self.data.len());
+        let read = (&self.data[start..]).read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for InflatedEntry {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(pos, self.pos, self.size)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    fn read_all(mut reader: Box<dyn SampleReader>) -> Vec<u8> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn memory_source() {
+        let mut source = MemorySource::new();
+        source.insert("Samples/C4.wav", vec![1, 2, 3]);
+        source.insert("./samples/d4.wav", vec![4]);
+
+        let path = source.resolve("samples\\c4.wav").unwrap();
+        assert_eq!(path, PathBuf::from("Samples/C4.wav"));
+        assert_eq!(read_all(source.open(&path).unwrap()), [1, 2, 3]);
+        assert_eq!(source.size(&path), 3);
+
+        assert_eq!(source.resolve("other/../samples/D4.wav"), Ok(PathBuf::from("samples/d4.wav")));
+        assert_eq!(source.resolve("./samples/e4.wav"), Err(PathBuf::from("samples/e4.wav")));
+        assert!(source.open(Path::new("samples/e4.wav")).is_err());
+    }
+
+    #[test]
+    fn zip_source() {
+        let archive = std::env::temp_dir().join(format!("sonarigo-zip-source-{}.zip", std::process::id()));
+        {
+            let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+            let options = zip::write::FileOptions::default();
+            zip.start_file("piano/piano.sfz", options).unwrap();
+            zip.write_all(b"<region> sample=Samples\\C4.wav").unwrap();
+            zip.start_file("piano/samples/c4.wav", options).unwrap();
+            zip.write_all(&[1, 2, 3, 4]).unwrap();
+            zip.start_file("piano/samples/d4.wav", options.compression_method(zip::CompressionMethod::Stored)).unwrap();
+            zip.write_all(&[5, 6, 7]).unwrap();
+            zip.finish().unwrap();
+        }
+
+        let source = ZipSource::new(&archive, "piano").unwrap();
+
+        assert_eq!(source.read_to_string("piano/piano.sfz").unwrap(), "<region> sample=Samples\\C4.wav");
+        let path = source.resolve("Samples\\C4.wav").unwrap();
+        assert_eq!(path, PathBuf::from("piano/samples/c4.wav"));
+        assert_eq!(read_all(source.open(&path).unwrap()), [1, 2, 3, 4]);
+        assert_eq!(source.size(&path), 4);
+        assert!(!source.seekable(&path));
+
+        let stored = source.resolve("samples/d4.wav").unwrap();
+        assert_eq!(read_all(source.open(&stored).unwrap()), [5, 6, 7]);
+        assert_eq!(source.size(&stored), 3);
+        assert!(source.seekable(&stored));
+        assert_eq!(source.resolve("samples/e4.wav"), Err(PathBuf::from("piano/samples/e4.wav")));
+
+        fs::remove_file(&archive).unwrap();
+    }
+
+    #[test]
+    fn seek_in_zip_entries() {
+        let archive = std::env::temp_dir().join(format!("sonarigo-zip-seek-{}.zip", std::process::id()));
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        {
+            let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+            let options = zip::write::FileOptions::default();
+            zip.start_file("deflated.wav", options).unwrap();
+            zip.write_all(&data).unwrap();
+            zip.start_file("stored.wav", options.compression_method(zip::CompressionMethod::Stored)).unwrap();
+            zip.write_all(&data).unwrap();
+            zip.finish().unwrap();
+        }
+
+        let source = ZipSource::new(&archive, "").unwrap();
+        for name in &["deflated.wav", "stored.wav"] {
+            let mut reader = source.open(Path::new(name)).unwrap();
+            let mut buf = [0; 4];
+            assert_eq!(reader.seek(SeekFrom::Start(50_000)).unwrap(), 50_000);
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data[50_000..50_004]);
+            assert_eq!(reader.seek(SeekFrom::Start(10)).unwrap(), 10);
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data[10..14]);
+            assert_eq!(reader.seek(SeekFrom::End(-2)).unwrap(), 99_998);
+            assert_eq!(reader.read(&mut buf).unwrap(), 2);
+            assert_eq!(buf[..2], data[99_998..]);
+            assert_eq!(reader.read(&mut buf).unwrap(), 0);
+            assert!(reader.seek(SeekFrom::Current(-200_000)).is_err());
+        }
+
+        fs::remove_file(&archive).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+// In memory representation of sample data. Integer formats keep 16 and 24 bit sample files at
+// their original size and are converted to float while interpolating.
+pub(crate) enum Storage {
+    Float(Vec<f32>),
+    Int16(Vec<i16>),
+    Int24(Vec<I24>),
+}
+
+impl Storage {
+    // Converts `data` losslessly if it came from a file with `bits` per sample.
+    pub(crate) fn pack(data: Vec<f32>, bits: Option<u32>) -> Storage {
+        match bits {
+            Some(8) | Some(16) => Storage::Int16(data.iter().map(|s| (*s * 32768.0).round().clamp(-32768.0, 32767.0) as i16).collect()),
+            Some(24) => Storage::Int24(data.iter().map(|s| I24::from_f32(*s)).collect()),
+            _ => Storage::Float(data),
+        }
+    }
+
+    // Bytes per sample `pack` ends up with for `bits`.
+    pub(crate) fn packed_sample_bytes(bits: Option<u32>) -> usize {
+        match bits {
+            Some(8) | Some(16) => std::mem::size_of::<i16>(),
+            Some(24) => std::mem::size_of::<I24>(),
+            _ => std::mem::size_of::<f32>(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Storage::Float(data) => data.len(),
+            Storage::Int16(data) => data.len(),
+            Storage::Int24(data) => data.len(),
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        match self {
+            Storage::Float(data) => std::mem::size_of_val(data.as_slice()),
+            Storage::Int16(data) => std::mem::size_of_val(data.as_slice()),
+            Storage::Int24(data) => std::mem::size_of_val(data.as_slice()),
+        }
+    }
+
+    pub(crate) fn resize(&mut self, len: usize) {
+        match self {
+            Storage::Float(data) => data.resize(len, 0.0),
+            Storage::Int16(data) => data.resize(len, 0),
+            Storage::Int24(data) => data.resize(len, I24([0; 3])),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct I24([u8; 3]);
+
+impl I24 {
+    fn from_f32(s: f32) -> I24 {
+        let v = ((s as f64 * 8388608.0).round() as i32).clamp(-8388608, 8388607);
+        let b = v.to_le_bytes();
+        I24([b[0], b[1], b[2]])
+    }
+}
+
+// A sample value in one of the `Storage` formats.
+pub(crate) trait SampleFormat: Copy {
+    fn to_f64(self) -> f64;
+
+    // The data of `storage`, which must hold this format.
+    fn slice(storage: &Storage) -> &[Self];
+}
+
+impl SampleFormat for f32 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn slice(storage: &Storage) -> &[f32] {
+        match storage {
+            Storage::Float(data) => data,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl SampleFormat for i16 {
+    fn to_f64(self) -> f64 {
+        self as f64 / 32768.0
+    }
+
+    fn slice(storage: &Storage) -> &[i16] {
+        match storage {
+            Storage::Int16(data) => data,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl SampleFormat for I24 {
+    fn to_f64(self) -> f64 {
+        let v = i32::from_le_bytes([self.0[0], self.0[1], self.0[2], 0]) << 8 >> 8;
+        v as f64 / 8388608.0
+    }
+
+    fn slice(storage: &Storage) -> &[I24] {
+        match storage {
+            Storage::Int24(data) => data,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unpack<T: SampleFormat>(storage: &Storage) -> Vec<f32> {
+        T::slice(storage).iter().map(|s| s.to_f64() as f32).collect()
+    }
+
+
+    #[test]
+    fn pack_16_bit() {
+        let data = vec![0.0, 0.5, -0.5, -1.0, 32767.0 / 32768.0, -1.0 / 32768.0];
+        let storage = Storage::pack(data.clone(), Some(16));
+        assert_eq!(storage.bytes(), 6 * Storage::packed_sample_bytes(Some(16)));
+        assert_eq!(storage.bytes(), 12);
+        assert_eq!(unpack::<i16>(&storage), data);
+    }
+
+    #[test]
+    fn pack_8_bit_as_16_bit() {
+        let data = vec![0.0, 0.5, -1.0, 127.0 / 128.0];
+        assert_eq!(unpack::<i16>(&Storage::pack(data.clone(), Some(8))), data);
+    }
+
+    #[test]
+    fn pack_24_bit() {
+        let data = vec![0.0, 0.25, -0.25, -1.0, 8388607.0 / 8388608.0, -1.0 / 8388608.0];
+        let storage = Storage::pack(data.clone(), Some(24));
+        assert_eq!(storage.bytes(), 6 * Storage::packed_sample_bytes(Some(24)));
+        assert_eq!(storage.bytes(), 18);
+        assert_eq!(unpack::<I24>(&storage), data);
+    }
+
+    #[test]
+    fn pack_clips_out_of_range() {
+        assert_eq!(unpack::<i16>(&Storage::pack(vec![1.0, -1.5], Some(16))), [32767.0 / 32768.0, -1.0]);
+        assert_eq!(unpack::<I24>(&Storage::pack(vec![1.0, -1.5], Some(24))), [8388607.0 / 8388608.0, -1.0]);
+    }
+
+    #[test]
+    fn other_formats_stay_float() {
+        for bits in &[None, Some(32)] {
+            let storage = Storage::pack(vec![0.1, 0.2], *bits);
+            assert_eq!(storage.bytes(), 2 * Storage::packed_sample_bytes(*bits));
+            assert_eq!(storage.bytes(), 8);
+            assert_eq!(unpack::<f32>(&storage), [0.1, 0.2]);
+        }
+    }
+
+    #[test]
+    fn resize_pads_with_silence() {
+        let mut storage = Storage::pack(vec![0.5], Some(24));
+        storage.resize(3);
+        assert_eq!(storage.len(), 3);
+        assert_eq!(unpack::<I24>(&storage), [0.5, 0.0, 0.0]);
+    }
+}
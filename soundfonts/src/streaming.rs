@@ -0,0 +1,487 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_queue::ArrayQueue;
+
+use crate::interpolation;
+use crate::sample;
+
+// Frames a voice keeps around its playback position, refilled from the ring buffer.
+const WINDOW_FRAMES: usize = 256;
+const READ_CHUNK_FRAMES: usize = 4096;
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamingConfig {
+    pub preload_frames: usize,
+    pub buffer_frames: usize,
+    pub max_streams: usize,
+}
+
+impl StreamingConfig {
+    pub(crate) fn effective_preload_frames(&self) -> usize {
+        self.preload_frames.max(interpolation::FRAMES_BEFORE + interpolation::FRAMES_AFTER + 1)
+    }
+
+    // The memory of the ring buffers and playback windows of all streams.
+    pub(crate) fn buffer_bytes(&self) -> usize {
+        let samples = self.buffer_frames.max(WINDOW_FRAMES) * 2 + WINDOW_FRAMES * 2;
+        self.max_streams * samples * std::mem::size_of::<f32>()
+    }
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            preload_frames: 32768,
+            buffer_frames: 65536,
+            max_streams: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StreamStats {
+    pub underruns: usize,
+    pub missed_streams: usize,
+}
+
+pub(crate) trait FrameReader: Send {
+    // Reads interleaved frames into `buf`, returns the number of frames read, zero at the end of the file.
+    fn read_frames(&mut self, buf: &mut [f32]) -> usize;
+}
+
+pub(crate) type ReaderOpener = Box<dyn Fn(&StreamFile, usize) -> Option<Box<dyn FrameReader>> + Send>;
+
+pub(crate) struct StreamFile {
+    pub(crate) path: PathBuf,
+    pub(crate) channels: usize,
+}
+
+#[derive(Default)]
+struct Request {
+    file: AtomicUsize,
+    start: AtomicUsize,
+    active: AtomicBool,
+}
+
+pub(crate) struct Stream {
+    id: usize,
+    request: Arc<Request>,
+    consumer: rtrb::Consumer<f32>,
+    channels: usize,
+
+    window: Vec<f32>,
+    window_start: usize,
+    window_frames: usize,
+}
+
+impl Stream {
+    fn begin(&mut self, file: usize, channels: usize, start: usize) {
+        self.channels = channels;
+        self.window_start = start;
+        self.window_frames = 0;
+        self.request.file.store(file, Ordering::Relaxed);
+        self.request.start.store(start, Ordering::Relaxed);
+        self.request.active.store(true, Ordering::Release);
+    }
+
+    // Only called by the disk thread, after which no more frames of the previous file are pushed.
+    fn reset(&mut self) {
+        self.request.active.store(false, Ordering::Release);
+        if let Ok(chunk) = self.consumer.read_chunk(self.consumer.slots()) {
+            chunk.commit_all();
+        }
+        self.window_frames = 0;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn buffered_frames(&self) -> usize {
+        self.window_frames + self.consumer.slots() / self.channels
+    }
+
+    pub(crate) fn window(&self) -> &[f32] {
+        &self.window
+    }
+
+    // Makes the frames the interpolators read around `pos` available in the window and returns
+    // the window index of `pos`, or `None` if the disk thread did not keep up.
+    pub(crate) fn fetch(&mut self, pos: usize) -> Option<usize> {
+        let first = pos.saturating_sub(interpolation::FRAMES_BEFORE);
+        let end = pos + interpolation::FRAMES_AFTER + 1;
+        if first < self.window_start {
+            return None;
+        }
+        if end > self.window_start + self.window_frames {
+            self.refill(first);
+        }
+        if first < self.window_start || end > self.window_start + self.window_frames {
+            return None;
+        }
+        Some((pos - self.window_start) * self.channels)
+    }
+
+    fn refill(&mut self, first: usize) {
+        let channels = self.channels;
+        let end = self.window_start + self.window_frames;
+        if first < end {
+            let drop = first - self.window_start;
+            self.window.copy_within(drop * channels..self.window_frames * channels, 0);
+            self.window_frames -= drop;
+            self.window_start = first;
+        } else {
+            let available = self.consumer.slots() / channels;
+            let skip = (first - end).min(available);
+            if let Ok(chunk) = self.consumer.read_chunk(skip * channels) {
+                chunk.commit_all();
+            }
+            self.window_frames = 0;
+            self.window_start = end + skip;
+        }
+
+        let available = self.consumer.slots() / channels;
+        let frames = (WINDOW_FRAMES - self.window_frames).min(available);
+        if let Ok(chunk) = self.consumer.read_chunk(frames * channels) {
+            let (a, b) = chunk.as_slices();
+            let offset = self.window_frames * channels;
+            self.window[offset..offset + a.len()].copy_from_slice(a);
+            self.window[offset + a.len()..offset + a.len() + b.len()].copy_from_slice(b);
+            chunk.commit_all();
+            self.window_frames += frames;
+        }
+    }
+}
+
+pub(crate) struct StreamPool {
+    free: ArrayQueue<Stream>,
+    returned: ArrayQueue<Stream>,
+    underruns: AtomicUsize,
+    missed_streams: AtomicUsize,
+    quit: AtomicBool,
+}
+
+impl StreamPool {
+    pub(crate) fn start(&self, file: usize, channels: usize, start: usize) -> Option<Stream> {
+        match self.free.pop() {
+            Some(mut stream) => {
+                stream.begin(file, channels, start);
+                Some(stream)
+            }
+            None => {
+                self.missed_streams.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn release(&self, stream: Stream) {
+        let _ = self.returned.push(stream);
+    }
+
+    pub(crate) fn report_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> StreamStats {
+        StreamStats {
+            underruns: self.underruns.load(Ordering::Relaxed),
+            missed_streams: self.missed_streams.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct StreamSource {
+    pub(crate) pool: Arc<StreamPool>,
+    pub(crate) file: usize,
+}
+
+enum SlotState {
+    Idle,
+    Reading { reader: Box<dyn FrameReader>, file: usize },
+    Padding { channels: usize },
+    Done,
+}
+
+struct Slot {
+    request: Arc<Request>,
+    producer: rtrb::Producer<f32>,
+    state: SlotState,
+}
+
+impl Slot {
+    fn fill(&mut self, files: &[StreamFile], open: &ReaderOpener, buf: &mut [f32]) -> bool {
+        match &mut self.state {
+            SlotState::Idle => {
+                if !self.request.active.load(Ordering::Acquire) {
+                    return false;
+                }
+                let file = self.request.file.load(Ordering::Relaxed);
+                let start = self.request.start.load(Ordering::Relaxed);
+                self.state = match open(&files[file], start) {
+                    Some(reader) => SlotState::Reading { reader, file },
+                    None => SlotState::Done,
+                };
+                true
+            }
+            SlotState::Reading { reader, file } => {
+                let file_channels = files[*file].channels;
+                let channels = file_channels.min(2);
+                let frames = (self.producer.slots() / channels).min(READ_CHUNK_FRAMES);
+                if frames == 0 {
+                    return false;
+                }
+                let read = reader.read_frames(&mut buf[..frames * file_channels]);
+                if read == 0 {
+                    self.state = SlotState::Padding { channels };
+                    return true;
+                }
+                let data = &buf[..read * file_channels];
+                let routed;
+                let data = if file_channels > 2 {
+                    routed = sample::route_to_stereo(data, file_channels);
+                    &routed[..]
+                } else {
+                    data
+                };
+                if let Ok(chunk) = self.producer.write_chunk_uninit(data.len()) {
+                    chunk.fill_from_iter(data.iter().copied());
+                }
+                true
+            }
+            SlotState::Padding { channels } => {
+                let padding = sample::PADDING_FRAMES * *channels;
+                match self.producer.write_chunk_uninit(padding) {
+                    Ok(chunk) => {
+                        chunk.fill_from_iter(std::iter::repeat(0.0));
+                        self.state = SlotState::Done;
+                        true
+                    }
+                    Err(_) => false,
+                }
+            }
+            SlotState::Done => false,
+        }
+    }
+}
+
+// Owns the stream slots until `spawn` hands the producing ends to the disk thread.
+pub(crate) struct DiskStreamer {
+    config: StreamingConfig,
+    pool: Arc<StreamPool>,
+    slots: Vec<Slot>,
+    files: Vec<StreamFile>,
+}
+
+impl DiskStreamer {
+    pub(crate) fn new(config: StreamingConfig) -> DiskStreamer {
+        let max_streams = config.max_streams.max(1);
+        let pool = StreamPool {
+            free: ArrayQueue::new(max_streams),
+            returned: ArrayQueue::new(max_streams),
+            underruns: AtomicUsize::new(0),
+            missed_streams: AtomicUsize::new(0),
+            quit: AtomicBool::new(false),
+        };
+        let mut slots = Vec::with_capacity(max_streams);
+        for id in 0..max_streams {
+            let (producer, consumer) = rtrb::RingBuffer::new(config.buffer_frames.max(WINDOW_FRAMES) * 2);
+            let request = Arc::new(Request::default());
+            let stream = Stream {
+                id,
+                request: request.clone(),
+                consumer,
+                channels: 2,
+                window: vec![0.0; WINDOW_FRAMES * 2],
+                window_start: 0,
+                window_frames: 0,
+            };
+            let _ = pool.free.push(stream);
+            slots.push(Slot { request, producer, state: SlotState::Idle });
+        }
+
+        DiskStreamer {
+            config,
+            pool: Arc::new(pool),
+            slots,
+            files: Vec::new(),
+        }
+    }
+
+    pub(crate) fn preload_frames(&self) -> usize {
+        self.config.effective_preload_frames()
+    }
+
+    pub(crate) fn register_file(&mut self, file: StreamFile) -> StreamSource {
+        self.files.push(file);
+        StreamSource {
+            pool: self.pool.clone(),
+            file: self.files.len() - 1,
+        }
+    }
+
+    pub(crate) fn spawn(self, open: ReaderOpener) -> StreamHandle {
+        let DiskStreamer { pool, mut slots, files, .. } = self;
+        let handle = StreamHandle { pool: pool.clone() };
+
+        let max_channels = files.iter().map(|f| f.channels).max().unwrap_or(2);
+        thread::spawn(move || {
+            let mut buf = vec![0.0; READ_CHUNK_FRAMES * max_channels];
+            while !pool.quit.load(Ordering::Acquire) {
+                let mut busy = false;
+                while let Some(mut stream) = pool.returned.pop() {
+                    slots[stream.id].state = SlotState::Idle;
+                    stream.reset();
+                    let _ = pool.free.push(stream);
+                    busy = true;
+                }
+                for slot in &mut slots {
+                    busy |= slot.fill(&files, &open, &mut buf);
+                }
+                if !busy {
+                    thread::sleep(IDLE_SLEEP);
+                }
+            }
+        });
+
+        handle
+    }
+}
+
+// Stops the disk thread when dropped.
+pub(crate) struct StreamHandle {
+    pool: Arc<StreamPool>,
+}
+
+impl StreamHandle {
+    pub(crate) fn stats(&self) -> StreamStats {
+        self.pool.stats()
+    }
+}
+
+impl Drop for StreamHandle {
+    fn drop(&mut self) {
+        self.pool.quit.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    pub(crate) struct MemoryReader {
+        data: Arc<Vec<f32>>,
+        channels: usize,
+        position: usize,
+    }
+
+    impl FrameReader for MemoryReader {
+        fn read_frames(&mut self, buf: &mut [f32]) -> usize {
+            let start = self.position * self.channels;
+            let len = buf.len().min(self.data.len() - start);
+            buf[..len].copy_from_slice(&self.data[start..start + len]);
+            self.position += len / self.channels;
+            len / self.channels
+        }
+    }
+
+    pub(crate) fn memory_opener(data: Vec<f32>) -> ReaderOpener {
+        let data = Arc::new(data);
+        Box::new(move |file: &StreamFile, start: usize| {
+            Some(Box::new(MemoryReader { data: data.clone(), channels: file.channels, position: start })
+                 as Box<dyn FrameReader>)
+        })
+    }
+
+    pub(crate) fn wait_for<F: Fn() -> bool>(condition: F) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < Duration::from_secs(5), "timeout waiting for disk thread");
+            thread::sleep(IDLE_SLEEP);
+        }
+    }
+
+    fn fetch_waiting(stream: &mut Stream, pos: usize) -> usize {
+        let started = Instant::now();
+        loop {
+            if let Some(index) = stream.fetch(pos) {
+                return index;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "timeout waiting for disk thread");
+            thread::sleep(IDLE_SLEEP);
+        }
+    }
+
+    fn config(max_streams: usize) -> StreamingConfig {
+        StreamingConfig { preload_frames: 16, buffer_frames: 512, max_streams }
+    }
+
+    fn stereo_ramp(frames: usize) -> Vec<f32> {
+        (0..frames * 2).map(|i| (i / 2) as f32 + if i % 2 == 0 { 0.0 } else { 0.5 }).collect()
+    }
+
+    #[test]
+    fn stream_delivers_frames_from_start() {
+        let mut streamer = DiskStreamer::new(config(1));
+        let source = streamer.register_file(StreamFile { path: PathBuf::from("ramp.wav"), channels: 2 });
+        let _handle = streamer.spawn(memory_opener(stereo_ramp(100)));
+
+        let mut stream = source.pool.start(source.file, 2, 10).unwrap();
+        wait_for(|| stream.buffered_frames() == 90 + sample::PADDING_FRAMES);
+
+        let index = stream.fetch(17).unwrap();
+        assert_eq!(&stream.window()[index - 14..index - 12], &[10.0, 10.5]);
+        assert_eq!(&stream.window()[index - 2..index + 6], &[16.0, 16.5, 17.0, 17.5, 18.0, 18.5, 19.0, 19.5]);
+
+        let index = stream.fetch(99).unwrap();
+        assert_eq!(&stream.window()[index - 2..index + 6], &[98.0, 98.5, 99.0, 99.5, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn stream_window_wraps_around() {
+        let mut streamer = DiskStreamer::new(config(1));
+        let source = streamer.register_file(StreamFile { path: PathBuf::from("ramp.wav"), channels: 1 });
+        let _handle = streamer.spawn(memory_opener((0..2000).map(|i| i as f32).collect()));
+
+        let mut stream = source.pool.start(source.file, 1, 0).unwrap();
+        for pos in 1..1900 {
+            let index = fetch_waiting(&mut stream, pos);
+            assert_eq!(stream.window()[index], pos as f32);
+        }
+    }
+
+    #[test]
+    fn stream_missing_data_is_underrun() {
+        let mut streamer = DiskStreamer::new(config(1));
+        let source = streamer.register_file(StreamFile { path: PathBuf::from("missing.wav"), channels: 2 });
+        let _handle = streamer.spawn(Box::new(|_: &StreamFile, _| None));
+
+        let mut stream = source.pool.start(source.file, 2, 10).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(stream.fetch(17), None);
+    }
+
+    #[test]
+    fn released_stream_is_reused() {
+        let mut streamer = DiskStreamer::new(config(1));
+        let source = streamer.register_file(StreamFile { path: PathBuf::from("ramp.wav"), channels: 2 });
+        let handle = streamer.spawn(memory_opener(stereo_ramp(100)));
+
+        let stream = source.pool.start(source.file, 2, 10).unwrap();
+        assert!(source.pool.start(source.file, 2, 10).is_none());
+        assert_eq!(handle.stats(), StreamStats { underruns: 0, missed_streams: 1 });
+
+        wait_for(|| stream.buffered_frames() > 0);
+        source.pool.release(stream);
+        wait_for(|| source.pool.free.len() == 1);
+
+        let mut stream = source.pool.start(source.file, 2, 50).unwrap();
+        wait_for(|| stream.buffered_frames() == 50 + sample::PADDING_FRAMES);
+        let index = stream.fetch(57).unwrap();
+        assert_eq!(&stream.window()[index - 14..index - 12], &[50.0, 50.5]);
+    }
+}
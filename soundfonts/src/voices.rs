@@ -0,0 +1,81 @@
+use std::cmp::Ordering;
+
+// The engine wide voice limit used if none is configured.
+pub const DEFAULT_MAX_VOICES: usize = 256;
+
+// Stolen voices are faded out over this time instead of being cut off.
+pub(crate) const DECLICK_SECONDS: f64 = 0.005;
+
+// Which voice is taken away when a voice limit is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum VoiceStealing {
+    #[default]
+    Oldest,
+    Quietest,
+    // A voice of the note being played if there is one, the oldest otherwise.
+    SameNote,
+}
+
+impl VoiceStealing {
+    pub fn from_name(name: &str) -> Option<VoiceStealing> {
+        match name {
+            "oldest" => Some(VoiceStealing::Oldest),
+            "quietest" => Some(VoiceStealing::Quietest),
+            "same_note" => Some(VoiceStealing::SameNote),
+            _ => None,
+        }
+    }
+
+    // Orders two voices by which one to steal first, `note` being the note that caused the
+    // stealing.
+    pub(crate) fn compare(&self, a: &VoiceInfo, b: &VoiceInfo, note: Option<wmidi::Note>) -> Ordering {
+        let rank = |v: &VoiceInfo| match self {
+            VoiceStealing::Oldest => (false, -(v.frames_played as f64)),
+            VoiceStealing::Quietest => (false, v.level as f64),
+            VoiceStealing::SameNote => (Some(v.note) != note, -(v.frames_played as f64)),
+        };
+        rank(a).partial_cmp(&rank(b)).unwrap_or(Ordering::Equal)
+    }
+}
+
+// What a sample tells about one of its voices to decide about stealing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct VoiceInfo {
+    pub(crate) note: wmidi::Note,
+    pub(crate) frames_played: usize,
+    pub(crate) level: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(note: wmidi::Note, frames_played: usize, level: f32) -> VoiceInfo {
+        VoiceInfo { note, frames_played, level }
+    }
+
+    fn victim(stealing: VoiceStealing, voices: &[VoiceInfo], note: Option<wmidi::Note>) -> usize {
+        (0..voices.len()).min_by(|a, b| stealing.compare(&voices[*a], &voices[*b], note)).unwrap()
+    }
+
+    #[test]
+    fn stealing_order() {
+        let voices = [info(wmidi::Note::C3, 100, 0.5),
+                      info(wmidi::Note::D3, 300, 0.8),
+                      info(wmidi::Note::E3, 200, 0.1)];
+        assert_eq!(victim(VoiceStealing::Oldest, &voices, Some(wmidi::Note::C3)), 1);
+        assert_eq!(victim(VoiceStealing::Quietest, &voices, Some(wmidi::Note::C3)), 2);
+        assert_eq!(victim(VoiceStealing::SameNote, &voices, Some(wmidi::Note::C3)), 0);
+        assert_eq!(victim(VoiceStealing::SameNote, &voices, Some(wmidi::Note::F3)), 1);
+        assert_eq!(victim(VoiceStealing::SameNote, &voices, None), 1);
+    }
+
+    #[test]
+    fn stealing_names() {
+        assert_eq!(VoiceStealing::from_name("oldest"), Some(VoiceStealing::Oldest));
+        assert_eq!(VoiceStealing::from_name("quietest"), Some(VoiceStealing::Quietest));
+        assert_eq!(VoiceStealing::from_name("same_note"), Some(VoiceStealing::SameNote));
+        assert_eq!(VoiceStealing::from_name("newest"), None);
+        assert_eq!(VoiceStealing::default(), VoiceStealing::Oldest);
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// How often the frontends poll the files of an instrument when hot reloading is enabled.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    fs::metadata(path).ok().map(|m| Stamp { modified: m.modified().ok(), len: m.len() })
+}
+
+// Polls files for changes of their modification time or size. A missing file is watched as
+// well, it changes when it appears.
+#[derive(Debug)]
+pub struct FileWatcher {
+    files: Vec<(PathBuf, Option<Stamp>)>,
+    settling: bool,
+}
+
+impl FileWatcher {
+    pub fn new(paths: Vec<PathBuf>) -> FileWatcher {
+        FileWatcher {
+            files: paths.into_iter()
+                .map(|path| {
+                    let stamp = stamp(&path);
+                    (path, stamp)
+                })
+                .collect(),
+            settling: false,
+        }
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files.iter().map(|(path, _)| path)
+    }
+
+    // Tells whether files have changed since the last time it returned true. A change is only
+    // reported once the files stayed the same for one more poll, so that files still being
+    // written are not picked up.
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last) in &mut self.files {
+            let current = stamp(path);
+            if current != *last {
+                *last = current;
+                changed = true;
+            }
+        }
+        match changed {
+            true => {
+                self.settling = true;
+                false
+            }
+            false => std::mem::replace(&mut self.settling, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let dir = std::env::temp_dir().join(format!("sonarigo-watch-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn unchanged_files() {
+        let dir = TestDir::new("unchanged");
+        fs::write(dir.0.join("a.sfz"), b"<region>").unwrap();
+        let mut watcher = FileWatcher::new(vec![dir.0.join("a.sfz"), dir.0.join("missing.wav")]);
+        assert_eq!(watcher.paths().count(), 2);
+        assert!(!watcher.poll());
+        assert!(!watcher.poll());
+    }
+
+    #[test]
+    fn change_is_reported_once_settled() {
+        let dir = TestDir::new("changed");
+        let sfz = dir.0.join("a.sfz");
+        fs::write(&sfz, b"<region>").unwrap();
+        let mut watcher = FileWatcher::new(vec![sfz.clone()]);
+
+        fs::write(&sfz, b"<region> sample=*sine").unwrap();
+        assert!(!watcher.poll());
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+    }
+
+    #[test]
+    fn change_while_settling_postpones_report() {
+        let dir = TestDir::new("settling");
+        let wav = dir.0.join("a.wav");
+        fs::write(&wav, b"RIFF").unwrap();
+        let mut watcher = FileWatcher::new(vec![wav.clone()]);
+
+        fs::write(&wav, b"RIFF....").unwrap();
+        assert!(!watcher.poll());
+        fs::write(&wav, b"RIFF........").unwrap();
+        assert!(!watcher.poll());
+        assert!(watcher.poll());
+    }
+
+    #[test]
+    fn appearing_and_vanishing_files() {
+        let dir = TestDir::new("appearing");
+        let wav = dir.0.join("a.wav");
+        let mut watcher = FileWatcher::new(vec![wav.clone()]);
+
+        fs::write(&wav, b"RIFF").unwrap();
+        assert!(!watcher.poll());
+        assert!(watcher.poll());
+
+        fs::remove_file(&wav).unwrap();
+        assert!(!watcher.poll());
+        assert!(watcher.poll());
+    }
+}